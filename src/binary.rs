@@ -0,0 +1,125 @@
+//! Compact binary format of transactions, for feeds where parsing CSV is the bottleneck.
+//!
+//! The input is a sequence of records, each a length byte followed by that many bytes of payload. The payload
+//! is, with every integer in little endian:
+//!
+//! * The type, one byte: 0 deposit, 1 withdrawal, 2 dispute, 3 resolve, 4 chargeback or 5 unlock.
+//! * The client id, a `u16`.
+//! * The transaction id, a `u32`.
+//! * The amount, an `i64` fixed point with `AMOUNT_SCALE` decimal places. Only present when the payload is 15
+//!   bytes long rather than 7.
+//!
+//! The length prefix lets a reader skip a record it can't decode and carry on with the next one.
+
+use crate::transaction::{Transaction, TransactionType};
+use anyhow::{bail, Context, Result};
+use rust_decimal::Decimal;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// Decimal places of the amounts.
+pub const AMOUNT_SCALE: u32 = 4;
+
+/// Payload length of a record without an amount.
+const SHORT_RECORD: usize = 7;
+
+/// Payload length of a record with an amount.
+const LONG_RECORD: usize = 15;
+
+/// Encode a transaction as a record, length prefix included. Fails for amounts with more decimal places than
+/// `AMOUNT_SCALE` or too large for the fixed point.
+pub fn encode(transaction: &Transaction) -> Result<Vec<u8>> {
+    let transaction_type = match transaction.get_type() {
+        TransactionType::Deposit => 0u8,
+        TransactionType::Withdrawal => 1,
+        TransactionType::Dispute => 2,
+        TransactionType::Resolve => 3,
+        TransactionType::Chargeback => 4,
+        TransactionType::Unlock => 5,
+    };
+
+    let mut record = vec![0];
+    record.push(transaction_type);
+    record.extend(transaction.get_client_id().to_le_bytes());
+    record.extend(transaction.get_tx_id().to_le_bytes());
+
+    if let Some(amount) = transaction.get_amount() {
+        let mut fixed = *amount;
+        fixed.rescale(AMOUNT_SCALE);
+        if fixed != *amount {
+            bail!("{amount} has more than {AMOUNT_SCALE} decimal places.");
+        }
+
+        let fixed = i64::try_from(fixed.mantissa()).context("The amount is too large.")?;
+        record.extend(fixed.to_le_bytes());
+    }
+
+    record[0] = (record.len() - 1) as u8;
+
+    Ok(record)
+}
+
+/// Decode the payload of a record, without its length prefix.
+pub fn decode(payload: &[u8]) -> Result<Transaction> {
+    if payload.len() != SHORT_RECORD && payload.len() != LONG_RECORD {
+        bail!("Invalid record length {}.", payload.len());
+    }
+
+    let transaction_type = match payload[0] {
+        0 => TransactionType::Deposit,
+        1 => TransactionType::Withdrawal,
+        2 => TransactionType::Dispute,
+        3 => TransactionType::Resolve,
+        4 => TransactionType::Chargeback,
+        5 => TransactionType::Unlock,
+        other => bail!("Unknown transaction type {other}."),
+    };
+
+    let client = u16::from_le_bytes(payload[1..3].try_into()?);
+    let tx = u32::from_le_bytes(payload[3..7].try_into()?);
+    let amount = (payload.len() == LONG_RECORD)
+        .then(|| payload[7..15].try_into())
+        .transpose()?
+        .map(|fixed| Decimal::new(i64::from_le_bytes(fixed), AMOUNT_SCALE));
+
+    Ok(Transaction::new(transaction_type, client, tx, amount))
+}
+
+/// Write transactions in the binary format, to generate fixtures.
+pub async fn write_transactions<'a, W>(
+    mut writer: W,
+    transactions: impl IntoIterator<Item = &'a Transaction>,
+) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    for transaction in transactions {
+        writer.write_all(&encode(transaction)?).await?;
+    }
+
+    writer.flush().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test that amounts the fixed point can't hold exactly aren't encoded.
+    #[test]
+    fn test_encode_invalid_amount() {
+        let transaction = |amount| Transaction::new(TransactionType::Deposit, 1, 1, Some(amount));
+
+        assert!(encode(&transaction(Decimal::new(12345, 5))).is_err());
+        assert!(encode(&transaction(Decimal::from(i64::MAX))).is_err());
+        assert_eq!(encode(&transaction(Decimal::new(15, 1))).unwrap().len(), 16);
+    }
+
+    /// Test that payloads of the wrong length or type are rejected.
+    #[test]
+    fn test_decode_invalid_record() {
+        assert!(decode(&[0; 6]).is_err());
+        assert!(decode(&[9, 1, 0, 1, 0, 0, 0]).is_err());
+        assert!(decode(&[2, 1, 0, 1, 0, 0, 0]).is_ok());
+    }
+}
@@ -1,14 +1,190 @@
-use anyhow::{bail, Context, Result};
-use rust_decimal::Decimal;
+use rust_decimal::{Decimal, RoundingStrategy};
 use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fmt,
+    hash::{Hash, Hasher},
+    str::{self, FromStr},
+};
+
+/// Most decimal places of an amount added to or subtracted from the available funds.
+pub const MAX_DECIMAL_PLACES: u32 = 4;
+
+/// Why an operation on a client's funds failed. The client is left unchanged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientError {
+    NegativeAmount,
+
+    /// The amount has more than `MAX_DECIMAL_PLACES` decimal places, not counting trailing zeros.
+    ExcessPrecision,
+
+    /// Not enough available or held funds, or not enough shortfall, to subtract the amount from.
+    InsufficientFunds,
+
+    /// The result doesn't fit in a `Decimal`.
+    Overflow,
+
+    /// The exact result has more significant digits than a `Decimal` can hold, so it would have to be rounded.
+    PrecisionLoss,
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NegativeAmount => write!(f, "Amount must be positive."),
+            Self::ExcessPrecision => write!(
+                f,
+                "The amount has more than {MAX_DECIMAL_PLACES} decimal places."
+            ),
+            Self::InsufficientFunds => write!(f, "Not enough funds."),
+            Self::Overflow => write!(f, "The result overflows."),
+            Self::PrecisionLoss => write!(
+                f,
+                "The result can't be represented without losing precision."
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+/// Decimal places of every amount written to the CSV outputs.
+pub const OUTPUT_DECIMAL_PLACES: u32 = 4;
+
+/// Format an amount with exactly `OUTPUT_DECIMAL_PLACES` decimal places, rounding half to even (banker's
+/// rounding), so `0.00005` is written `0.0000` and `0.00015` is written `0.0002`.
+pub fn format_amount(amount: Decimal) -> String {
+    let mut amount =
+        amount.round_dp_with_strategy(OUTPUT_DECIMAL_PLACES, RoundingStrategy::MidpointNearestEven);
+    amount.rescale(OUTPUT_DECIMAL_PLACES);
+
+    amount.to_string()
+}
+
+/// Add two amounts exactly. `Decimal` silently rounds a sum whose digits don't fit in its mantissa, by dropping
+/// decimal places, so a sum with fewer decimal places than its operands is checked to still add up.
+fn exact_add(a: Decimal, b: Decimal) -> Result<Decimal, ClientError> {
+    let sum = a.checked_add(b).ok_or(ClientError::Overflow)?;
+    if sum.scale() < a.scale().max(b.scale()) && sum.checked_sub(a) != Some(b) {
+        return Err(ClientError::PrecisionLoss);
+    }
+
+    Ok(sum)
+}
+
+/// Subtract two amounts exactly, see `exact_add`.
+fn exact_sub(a: Decimal, b: Decimal) -> Result<Decimal, ClientError> {
+    let difference = a.checked_sub(b).ok_or(ClientError::Overflow)?;
+    if difference.scale() < a.scale().max(b.scale()) && difference.checked_add(b) != Some(a) {
+        return Err(ClientError::PrecisionLoss);
+    }
+
+    Ok(difference)
+}
+
+/// Code of a currency, such as `USD` or `BTC`: one to eight ASCII letters or digits, kept in upper case. Stored
+/// inline, so a transaction carrying it stays cheap to copy around.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Currency([u8; 8]);
+
+impl Currency {
+    pub fn as_str(&self) -> &str {
+        let length = self
+            .0
+            .iter()
+            .position(|byte| *byte == 0)
+            .unwrap_or(self.0.len());
+
+        // Only ASCII is ever stored.
+        str::from_utf8(&self.0[..length]).unwrap_or_default()
+    }
+}
+
+impl FromStr for Currency {
+    type Err = anyhow::Error;
+
+    fn from_str(code: &str) -> Result<Self, Self::Err> {
+        anyhow::ensure!(
+            (1..=8).contains(&code.len()) && code.bytes().all(|byte| byte.is_ascii_alphanumeric()),
+            "Invalid currency {code}."
+        );
+
+        let mut bytes = [0; 8];
+        bytes[..code.len()].copy_from_slice(code.to_ascii_uppercase().as_bytes());
+
+        Ok(Self(bytes))
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Chargeback that locked a client account.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LockTrigger {
+    pub tx_id: u32,
+    pub amount: Decimal,
+}
 
 /// Hold the client state.
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct Client {
     id: u16,
     available: Decimal,
     held: Decimal,
     locked: bool,
+
+    #[serde(skip)]
+    shortfall: Decimal,
+
+    #[serde(skip)]
+    lock_trigger: Option<LockTrigger>,
+
+    #[serde(skip)]
+    flagged: bool,
+
+    /// Currency of this account, when it is one of the accounts of a client in a named currency.
+    #[serde(skip)]
+    currency: Option<Currency>,
+
+    /// Accounts of the client in named currencies, each with balances and a lock of its own. The client's own
+    /// balances are those of the transactions without a currency.
+    #[serde(skip)]
+    accounts: BTreeMap<Currency, Client>,
+}
+
+/// Clients are equal when they have the same id, balances, lock state and flag, in every currency, which is
+/// everything written to the output. The total is computed from the balances so it can't differ on its own.
+/// Amounts are compared by value, so `1.5` and `1.50` are equal. The shortfall and the lock trigger are
+/// diagnostics and don't take part.
+impl PartialEq for Client {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+            && self.available == other.available
+            && self.held == other.held
+            && self.locked == other.locked
+            && self.flagged == other.flagged
+            && self.currency == other.currency
+            && self.accounts == other.accounts
+    }
+}
+
+impl Eq for Client {}
+
+impl Hash for Client {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // Decimal hashes by value, consistently with its equality.
+        self.id.hash(state);
+        self.available.hash(state);
+        self.held.hash(state);
+        self.locked.hash(state);
+        self.flagged.hash(state);
+        self.currency.hash(state);
+        self.accounts.hash(state);
+    }
 }
 
 impl Client {
@@ -18,6 +194,28 @@ impl Client {
             available: Default::default(),
             held: Default::default(),
             locked: Default::default(),
+            shortfall: Default::default(),
+            lock_trigger: Default::default(),
+            flagged: Default::default(),
+            currency: Default::default(),
+            accounts: Default::default(),
+        }
+    }
+
+    /// Client with the given balances, such as restored from a previous run.
+    pub fn with_balances(id: u16, available: Decimal, held: Decimal) -> Self {
+        Self {
+            available,
+            held,
+            ..Self::new(id)
+        }
+    }
+
+    /// Client as read back from the output of a previous run, which doesn't have the lock trigger.
+    pub fn restored(id: u16, available: Decimal, held: Decimal, locked: bool) -> Self {
+        Self {
+            locked,
+            ..Self::with_balances(id, available, held)
         }
     }
 
@@ -25,6 +223,68 @@ impl Client {
         self.id
     }
 
+    /// The same client as an account in a currency, see `insert_account`.
+    pub fn in_currency(mut self, currency: Option<Currency>) -> Self {
+        self.currency = currency;
+        self
+    }
+
+    /// Put an account of the client in place, replacing the one in its currency, or the client's own balances
+    /// for an account without a currency. Its own accounts in currencies are dropped.
+    pub fn insert_account(&mut self, mut account: Client) {
+        account.accounts.clear();
+        match account.currency {
+            None => {
+                account.accounts = std::mem::take(&mut self.accounts);
+                *self = account;
+            }
+            Some(currency) => {
+                self.accounts.insert(currency, account);
+            }
+        }
+    }
+
+    /// Currency of the account, `None` for the client's own balances.
+    pub fn get_currency(&self) -> Option<Currency> {
+        self.currency
+    }
+
+    /// Account of the client in a currency, the client itself for `None`.
+    pub fn account(&self, currency: Option<Currency>) -> Option<&Client> {
+        match currency {
+            None => Some(self),
+            Some(currency) => self.accounts.get(&currency),
+        }
+    }
+
+    /// Account of the client in a currency, opened empty the first time, the client itself for `None`.
+    pub fn account_mut(&mut self, currency: Option<Currency>) -> &mut Client {
+        let Some(currency) = currency else {
+            return self;
+        };
+
+        let id = self.id;
+        self.accounts.entry(currency).or_insert_with(|| Self {
+            currency: Some(currency),
+            ..Self::new(id)
+        })
+    }
+
+    /// Every account of the client, one row each in the output: its own balances, then each currency in order.
+    /// Its own balances are left out when they are untouched and it has accounts in currencies.
+    pub fn accounts(&self) -> impl Iterator<Item = &Client> {
+        let untouched =
+            self.available.is_zero() && self.held.is_zero() && !self.locked && !self.flagged;
+        let own = (self.accounts.is_empty() || !untouched).then_some(self);
+
+        own.into_iter().chain(self.currency_accounts())
+    }
+
+    /// Accounts of the client in named currencies, in order, without its own balances.
+    pub fn currency_accounts(&self) -> impl Iterator<Item = &Client> {
+        self.accounts.values()
+    }
+
     pub fn get_available(&self) -> Decimal {
         self.available
     }
@@ -33,41 +293,107 @@ impl Client {
         self.held
     }
 
+    /// Sum of the available and held funds, clamped to the bounds of `Decimal`. Fine for comparisons, but not
+    /// to be written out, see `try_get_total`.
     pub fn get_total(&self) -> Decimal {
         self.available.saturating_add(self.held)
     }
 
+    /// Sum of the available and held funds, or an error when it doesn't fit in a `Decimal`, a corrupt state that
+    /// a clamped total would hide.
+    pub fn try_get_total(&self) -> Result<Decimal, ClientError> {
+        self.available
+            .checked_add(self.held)
+            .ok_or(ClientError::Overflow)
+    }
+
+    /// Available funds as written to the output, see `format_amount`.
+    pub fn format_available(&self) -> String {
+        format_amount(self.available)
+    }
+
+    /// Held funds as written to the output, see `format_amount`.
+    pub fn format_held(&self) -> String {
+        format_amount(self.held)
+    }
+
+    /// Total funds as written to the output, see `format_amount`. The total is rounded on its own, so it may
+    /// differ in the last place from the sum of the rounded balances. Fails when the total overflows.
+    pub fn format_total(&self) -> Result<String, ClientError> {
+        Ok(format_amount(self.try_get_total()?))
+    }
+
+    /// Share of the total that is available, or `None` when the total isn't positive and the ratio is
+    /// meaningless. If available is negative (held exceeds the total) the ratio is negative too.
+    pub fn available_ratio(&self) -> Option<Decimal> {
+        self.ratio_of_total(self.available)
+    }
+
+    /// Share of the total that is held, or `None` when the total isn't positive and the ratio is meaningless.
+    /// If available is negative the held ratio is above one.
+    pub fn held_ratio(&self) -> Option<Decimal> {
+        self.ratio_of_total(self.held)
+    }
+
+    fn ratio_of_total(&self, amount: Decimal) -> Option<Decimal> {
+        let total = self.available.checked_add(self.held)?;
+        if total <= Decimal::ZERO {
+            return None;
+        }
+
+        amount.checked_div(total)
+    }
+
     pub fn is_locked(&self) -> bool {
         self.locked
     }
 
-    pub fn add_available(&mut self, amount: Decimal) -> Result<()> {
+    /// Chargeback that locked the account, if it is locked.
+    pub fn get_lock_trigger(&self) -> Option<LockTrigger> {
+        self.lock_trigger
+    }
+
+    /// Disputed amount that couldn't be held because it was no longer available.
+    pub fn get_shortfall(&self) -> Decimal {
+        self.shortfall
+    }
+
+    /// Whether the client was flagged as suspicious, such as for opening many disputes in a short time.
+    pub fn is_flagged(&self) -> bool {
+        self.flagged
+    }
+
+    pub fn flag(&mut self) {
+        self.flagged = true;
+    }
+
+    pub fn add_available(&mut self, amount: Decimal) -> Result<(), ClientError> {
         if amount.is_sign_negative() {
-            bail!("Amount must be positive.");
+            return Err(ClientError::NegativeAmount);
+        }
+        if amount.normalize().scale() > MAX_DECIMAL_PLACES {
+            return Err(ClientError::ExcessPrecision);
         }
 
-        let new_amount = self
-            .available
-            .checked_add(amount)
-            .context("Fail to add to the available founds.")?;
+        let new_amount = exact_add(self.available, amount)?;
 
         self.available = new_amount;
 
         Ok(())
     }
 
-    pub fn subtract_available(&mut self, amount: Decimal) -> Result<()> {
+    pub fn subtract_available(&mut self, amount: Decimal) -> Result<(), ClientError> {
         if amount.is_sign_negative() {
-            bail!("Amount must be positive.");
+            return Err(ClientError::NegativeAmount);
+        }
+        if amount.normalize().scale() > MAX_DECIMAL_PLACES {
+            return Err(ClientError::ExcessPrecision);
         }
 
-        let new_amount = self
-            .available
-            .checked_sub(amount)
-            .context("Fail to subtract to the available funds.")?;
+        let new_amount = exact_sub(self.available, amount)?;
 
         if new_amount.is_sign_negative() {
-            bail!("Not enough funds available.");
+            return Err(ClientError::InsufficientFunds);
         }
 
         self.available = new_amount;
@@ -75,24 +401,18 @@ impl Client {
         Ok(())
     }
 
-    pub fn transfer_available_to_held(&mut self, amount: Decimal) -> Result<()> {
+    pub fn transfer_available_to_held(&mut self, amount: Decimal) -> Result<(), ClientError> {
         if amount.is_sign_negative() {
-            bail!("Amount must be positive.");
+            return Err(ClientError::NegativeAmount);
         }
 
-        let new_available = self
-            .available
-            .checked_sub(amount)
-            .context("Fail to acquire available funds to do the transaction.")?;
+        let new_available = exact_sub(self.available, amount)?;
 
         if new_available.is_sign_negative() {
-            bail!("Not enough available funds to do the transaction");
+            return Err(ClientError::InsufficientFunds);
         }
 
-        let new_held = self
-            .held
-            .checked_add(amount)
-            .context("Fail to held funds to do the transaction.")?;
+        let new_held = exact_add(self.held, amount)?;
 
         self.available = new_available;
         self.held = new_held;
@@ -100,23 +420,17 @@ impl Client {
         Ok(())
     }
 
-    pub fn transfer_held_to_available(&mut self, amount: Decimal) -> Result<()> {
+    pub fn transfer_held_to_available(&mut self, amount: Decimal) -> Result<(), ClientError> {
         if amount.is_sign_negative() {
-            bail!("Amount must be positive.");
+            return Err(ClientError::NegativeAmount);
         }
 
-        let new_available = self
-            .available
-            .checked_add(amount)
-            .context("Fail to add funds to available during transaction.")?;
+        let new_available = exact_add(self.available, amount)?;
 
-        let new_held = self
-            .held
-            .checked_sub(amount)
-            .context("Fail to subtract from held funds during transaction.")?;
+        let new_held = exact_sub(self.held, amount)?;
 
         if new_held.is_sign_negative() {
-            bail!("Not enough held funds to the transaction.");
+            return Err(ClientError::InsufficientFunds);
         }
 
         self.available = new_available;
@@ -125,18 +439,15 @@ impl Client {
         Ok(())
     }
 
-    pub fn subtract_held(&mut self, amount: Decimal) -> Result<()> {
+    pub fn subtract_held(&mut self, amount: Decimal) -> Result<(), ClientError> {
         if amount.is_sign_negative() {
-            bail!("Amount must be positive.");
+            return Err(ClientError::NegativeAmount);
         }
 
-        let new_held = self
-            .held
-            .checked_sub(amount)
-            .context("Fail to subtract from held funds.")?;
+        let new_held = exact_sub(self.held, amount)?;
 
         if new_held.is_sign_negative() {
-            bail!("Not enough held funds to subtract from.");
+            return Err(ClientError::InsufficientFunds);
         }
 
         self.held = new_held;
@@ -144,7 +455,204 @@ impl Client {
         Ok(())
     }
 
-    pub fn lock_account(&mut self) {
+    pub fn add_shortfall(&mut self, amount: Decimal) -> Result<(), ClientError> {
+        if amount.is_sign_negative() {
+            return Err(ClientError::NegativeAmount);
+        }
+
+        self.shortfall = self
+            .shortfall
+            .checked_add(amount)
+            .ok_or(ClientError::Overflow)?;
+
+        Ok(())
+    }
+
+    pub fn subtract_shortfall(&mut self, amount: Decimal) -> Result<(), ClientError> {
+        if amount.is_sign_negative() {
+            return Err(ClientError::NegativeAmount);
+        }
+
+        let new_shortfall = self
+            .shortfall
+            .checked_sub(amount)
+            .ok_or(ClientError::Overflow)?;
+
+        if new_shortfall.is_sign_negative() {
+            return Err(ClientError::InsufficientFunds);
+        }
+
+        self.shortfall = new_shortfall;
+
+        Ok(())
+    }
+
+    /// Zero the balances and the shortfall, unlock the account and clear its flag, as a new client. Only for
+    /// administrative recovery, see `Ledger::reset_client`.
+    pub fn reset(&mut self) {
+        *self = Self::new(self.id);
+    }
+
+    /// Lock the account, keeping the first chargeback that locked it when it is already locked.
+    pub fn lock_account(&mut self, trigger: LockTrigger) {
         self.locked = true;
+        self.lock_trigger.get_or_insert(trigger);
+    }
+
+    /// Unlock the account after a manual review, forgetting the chargeback that locked it.
+    pub fn unlock_account(&mut self) {
+        self.locked = false;
+        self.lock_trigger = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test the ratios of a client with both available and held funds.
+    #[test]
+    fn test_mixed_ratios() {
+        let mut client = Client::new(1);
+        client.add_available(Decimal::new(100, 0)).unwrap();
+        client
+            .transfer_available_to_held(Decimal::new(25, 0))
+            .unwrap();
+
+        assert_eq!(client.available_ratio(), Some(Decimal::new(75, 2)));
+        assert_eq!(client.held_ratio(), Some(Decimal::new(25, 2)));
+    }
+
+    /// Test that a client without funds has no ratios.
+    #[test]
+    fn test_zero_balance_ratios() {
+        let client = Client::new(1);
+
+        assert_eq!(client.available_ratio(), None);
+        assert_eq!(client.held_ratio(), None);
+    }
+
+    /// Test the ratios when more is held than the total.
+    #[test]
+    fn test_negative_available_ratios() {
+        let mut client = Client::new(1);
+        client.available = Decimal::new(-5, 0);
+        client.held = Decimal::new(10, 0);
+
+        assert_eq!(client.available_ratio(), Some(Decimal::new(-1, 0)));
+        assert_eq!(client.held_ratio(), Some(Decimal::new(2, 0)));
+
+        // A negative total has no meaningful ratios.
+        client.held = Decimal::new(4, 0);
+        assert_eq!(client.available_ratio(), None);
+    }
+
+    /// Test that the balances are written with four decimal places, rounding half to even.
+    #[test]
+    fn test_format_amounts() {
+        let mut client = Client::new(1);
+        client.add_available(Decimal::new(1, 1)).unwrap();
+        client.add_available(Decimal::new(2, 1)).unwrap();
+
+        assert_eq!(client.format_available(), "0.3000");
+        assert_eq!(client.format_held(), "0.0000");
+        assert_eq!(client.format_total().unwrap(), "0.3000");
+
+        for (amount, formatted) in [
+            ("0.00005", "0.0000"),
+            ("0.00015", "0.0002"),
+            ("0.00025", "0.0002"),
+            ("-0.00005", "0.0000"),
+            ("1.23456", "1.2346"),
+            ("12", "12.0000"),
+        ] {
+            assert_eq!(format_amount(amount.parse().unwrap()), formatted);
+        }
+    }
+
+    /// Test that clients compare amounts by value, ignore the diagnostics and tell a flagged client apart.
+    #[test]
+    fn test_equality() {
+        let mut client = Client::new(1);
+        client.add_available(Decimal::new(15, 1)).unwrap();
+
+        let mut other = Client::new(1);
+        other.add_available(Decimal::new(150, 2)).unwrap();
+        other.add_shortfall(Decimal::ONE).unwrap();
+
+        assert_eq!(client, other);
+        assert_ne!(client, Client::new(1));
+        assert_ne!(Client::new(1), Client::new(2));
+
+        let hash = |client: &Client| {
+            let mut hasher = std::collections::hash_map::DefaultHasher::new();
+            client.hash(&mut hasher);
+            hasher.finish()
+        };
+        assert_eq!(hash(&client), hash(&other));
+
+        other.flag();
+        assert_ne!(client, other);
+    }
+
+    /// Test that each failure of a funds operation is told apart by its error, and leaves the client unchanged.
+    #[test]
+    fn test_client_errors() {
+        let mut client = Client::new(1);
+        client.add_available(Decimal::TEN).unwrap();
+        client.transfer_available_to_held(Decimal::ONE).unwrap();
+
+        assert_eq!(
+            client.add_available(Decimal::NEGATIVE_ONE),
+            Err(ClientError::NegativeAmount)
+        );
+        assert_eq!(
+            client.subtract_held(Decimal::NEGATIVE_ONE),
+            Err(ClientError::NegativeAmount)
+        );
+        assert_eq!(
+            client.add_available(Decimal::new(1, 5)),
+            Err(ClientError::ExcessPrecision)
+        );
+        assert_eq!(
+            client.subtract_available(Decimal::new(10, 0)),
+            Err(ClientError::InsufficientFunds)
+        );
+        assert_eq!(
+            client.transfer_held_to_available(Decimal::TWO),
+            Err(ClientError::InsufficientFunds)
+        );
+        assert_eq!(
+            client.subtract_shortfall(Decimal::ONE),
+            Err(ClientError::InsufficientFunds)
+        );
+        assert_eq!(
+            client.add_available(Decimal::MAX),
+            Err(ClientError::Overflow)
+        );
+        assert_eq!(client.get_available(), Decimal::new(9, 0));
+        assert_eq!(client.get_held(), Decimal::ONE);
+
+        // Adding a decimal place to an amount that already uses every digit would round it.
+        let mut large = Client::with_balances(2, Decimal::MAX - Decimal::ONE, Decimal::ZERO);
+        assert_eq!(
+            large.add_available(Decimal::new(1, 4)),
+            Err(ClientError::PrecisionLoss)
+        );
+        assert_eq!(large.get_available(), Decimal::MAX - Decimal::ONE);
+        assert_eq!(ClientError::Overflow.to_string(), "The result overflows.");
+    }
+
+    /// Test that a total beyond `Decimal::MAX` is reported instead of clamped.
+    #[test]
+    fn test_total_overflow() {
+        let client = Client::with_balances(1, Decimal::MAX, Decimal::ONE);
+
+        assert_eq!(client.try_get_total(), Err(ClientError::Overflow));
+        assert_eq!(client.format_total(), Err(ClientError::Overflow));
+        assert_eq!(client.get_total(), Decimal::MAX);
+
+        let client = Client::with_balances(1, Decimal::MAX - Decimal::ONE, Decimal::ONE);
+        assert_eq!(client.try_get_total(), Ok(Decimal::MAX));
     }
 }
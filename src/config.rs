@@ -0,0 +1,420 @@
+use crate::{
+    client::{Client, ClientError, MAX_DECIMAL_PLACES},
+    clock::{Clock, SystemClock},
+    ledger::RejectionReason,
+    transaction::{Transaction, TransactionType},
+};
+use rust_decimal::Decimal;
+use std::{
+    collections::HashMap,
+    fmt,
+    num::NonZeroUsize,
+    sync::Arc,
+    thread,
+    time::{Duration, SystemTime},
+};
+
+/// How a dispute is funded when the available funds don't cover the whole disputed amount.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DisputePolicy {
+    /// The whole disputed amount must be available, otherwise the dispute is ignored.
+    #[default]
+    RequireAvailable,
+
+    /// Hold as much of the disputed amount as is available and flag the rest as the client's shortfall. The
+    /// shortfall is never held: a resolve releases only what was held, and a chargeback removes only what was
+    /// held, leaving the shortfall flagged on the client.
+    AvailableFirst,
+}
+
+/// What to do when a transaction leaves the total of its client negative.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NegativeTotalPolicy {
+    /// Keep the transaction.
+    #[default]
+    Allow,
+
+    /// Undo the transaction, as if it was never in the input.
+    RejectTransaction,
+
+    /// Keep the transaction, and count it in the stats, see `LedgerStats::negative_totals`.
+    Warn,
+}
+
+/// What to do with a dispute that would hold more than the held cap allows.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HeldCapPolicy {
+    /// Hold as much as the cap allows, the rest is counted in the stats. A resolve or chargeback releases only
+    /// what was held.
+    #[default]
+    PartialHold,
+
+    /// Ignore the dispute.
+    Reject,
+}
+
+/// What to do with a client created by a transaction that didn't change it, such as a dispute of a transaction
+/// that doesn't exist, and that no later transaction changed either. Such phantom clients are all zero in the
+/// output and usually point at a data-quality issue in the input.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PhantomClientPolicy {
+    /// Keep them in the output like any other client.
+    #[default]
+    Keep,
+
+    /// Keep them in the output, and count them in the stats.
+    Flag,
+
+    /// Leave them out of the output.
+    Suppress,
+}
+
+/// What to do with a deposit or withdrawal without an amount.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MissingAmountPolicy {
+    /// Reject it as an invalid amount.
+    #[default]
+    Reject,
+
+    /// Apply it with an amount of zero, which changes no balance but stores the transaction to be referenced.
+    TreatAsZero,
+
+    /// Ignore it, without rejecting it or creating its client.
+    Skip,
+}
+
+/// What to do with a chargeback of a client whose account is already locked, by an earlier chargeback.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PostLockChargebackPolicy {
+    /// Reject it like any other transaction of a locked account.
+    #[default]
+    Ignore,
+
+    /// Charge it back, removing its held funds too. The account stays locked by the first chargeback.
+    Apply,
+}
+
+/// Rule flagging a client that opens `count` disputes within `window`, without rejecting them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RapidDisputeRule {
+    pub count: usize,
+    pub window: Duration,
+}
+
+/// Limit of the total a client may withdraw within any `window`, over a sliding window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VelocityLimit {
+    pub amount: Decimal,
+    pub window: Duration,
+}
+
+/// Callback called with every rejected transaction and the reason, see `Config::on_reject`.
+#[derive(Clone)]
+pub struct RejectHook(Arc<RejectFn>);
+
+type RejectFn = dyn Fn(&Transaction, RejectionReason) + Send + Sync;
+
+impl RejectHook {
+    pub fn call(&self, transaction: &Transaction, reason: RejectionReason) {
+        (self.0)(transaction, reason)
+    }
+}
+
+impl fmt::Debug for RejectHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RejectHook")
+    }
+}
+
+/// Most workers spawned per CPU, however many are requested. Beyond this the workers only compete for the same
+/// CPUs, and each one still costs a task and a channel.
+pub const MAX_WORKERS_PER_CPU: usize = 4;
+
+/// Number of CPUs the process may run on, one when it can't be told.
+pub fn available_cpus() -> usize {
+    thread::available_parallelism()
+        .map(NonZeroUsize::get)
+        .unwrap_or(1)
+}
+
+/// Transactions and messages queued in each channel of the processor when not configured, see
+/// `Config::channel_capacity`.
+pub const DEFAULT_CHANNEL_CAPACITY: usize = 10_000;
+
+/// Options that change how transactions are processed.
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    pub dispute_policy: DisputePolicy,
+    pub negative_total_policy: NegativeTotalPolicy,
+
+    /// Largest fraction of a client's total that may be held at once, between 0 and 1, no limit when not set.
+    pub held_cap: Option<Decimal>,
+    pub held_cap_policy: HeldCapPolicy,
+
+    pub phantom_client_policy: PhantomClientPolicy,
+    pub missing_amount_policy: MissingAmountPolicy,
+    pub post_lock_chargeback_policy: PostLockChargebackPolicy,
+
+    /// Number of workers to spawn, one per CPU when not set.
+    pub workers: Option<usize>,
+
+    /// Transactions queued for the processor, and messages for each of its workers, before submitting waits for
+    /// them to catch up. `DEFAULT_CHANNEL_CAPACITY` when not set.
+    pub channel_capacity: Option<usize>,
+
+    /// Largest amount accepted for a deposit or withdrawal, no limit when not set. An amount close to
+    /// `Decimal::MAX` is valid on its own, but leaves no headroom for the balances it is added to, so any later
+    /// arithmetic on them overflows. Setting the ceiling well below `MAX` rejects such amounts up front.
+    pub max_amount: Option<Decimal>,
+
+    /// Most decimal places accepted in the amount of each transaction type, such as 4 for deposits and 2 for
+    /// withdrawals. Trailing zeros don't count, so `1.50` has one. Types not listed take up to
+    /// `client::MAX_DECIMAL_PLACES`.
+    pub decimal_places: HashMap<TransactionType, u32>,
+
+    /// Most digits accepted before the decimal point of a deposit or withdrawal amount, no limit when not set. A
+    /// valid amount of absurd magnitude is usually corruption, such as an id in the amount column.
+    pub max_integer_digits: Option<u32>,
+
+    /// Treat a deposit with a negative amount as a withdrawal, and the other way around, instead of rejecting
+    /// it. Off by default: a feed that gets the sign wrong by mistake has its balances moved the wrong way.
+    pub normalize_signs: bool,
+
+    /// Let a resolve or chargeback take at most what is held, recording any difference as residual in the stats.
+    /// Rounding may leave held slightly short of the disputed amount, and otherwise the resolve or chargeback is
+    /// ignored altogether.
+    pub clamp_to_held: bool,
+
+    /// Priority of each transaction type, higher first, for the transactions queued in a worker. Types not listed
+    /// have priority zero. When empty, every worker applies its transactions strictly in arrival order.
+    pub priorities: HashMap<TransactionType, u8>,
+
+    /// Days a dispute must have been held before it can be resolved, a resolve before then is ignored. A
+    /// chargeback isn't delayed. Disputes seeded from a previous run have no start and can always be resolved.
+    pub hold_days: Option<u32>,
+
+    /// Keep the balances of each currency of a client apart, in an account of their own, see `Client::accounts`.
+    /// A dispute, resolve or chargeback only refers to a deposit in its own currency, and a chargeback only
+    /// locks the account of its currency. When not set, the currency of the transactions is ignored.
+    pub currencies: bool,
+
+    /// Flag the clients that open disputes too fast, see `RapidDisputeRule`. Flagged clients have a `flagged`
+    /// column in the CSV output.
+    pub rapid_disputes: Option<RapidDisputeRule>,
+
+    /// Reject the withdrawals that would take a client's total withdrawn within the window above the limit, see
+    /// `VelocityLimit`. They are also counted in the stats, see `LedgerStats::velocity_exceeded`.
+    pub withdrawal_velocity: Option<VelocityLimit>,
+
+    /// Clock used for the hold days, the rapid disputes and the withdrawal velocity, the system clock when not
+    /// set.
+    pub clock: Option<Arc<dyn Clock>>,
+
+    /// Recompute the total of every client from the amounts its transactions moved, independently of its
+    /// balances, and record the clients where they differ in the stats, see `LedgerStats::divergences`.
+    pub verify_ledger: bool,
+
+    /// Catch a panic while applying a transaction in a worker, and skip the transaction, rejected as `Panicked`,
+    /// instead of losing the worker. Costs saving the state of every transaction's client before applying it.
+    pub catch_panics: bool,
+
+    /// Allow clients to be reset by an administrator, see `Ledger::reset_client`. Off by default, so a reset
+    /// can't happen by mistake.
+    pub allow_admin_reset: bool,
+
+    /// Collect the stored transactions of every worker when they finish, see
+    /// `TransactionProcessor::get_results_with_store`.
+    pub collect_tx_store: bool,
+
+    /// Keep every rejected transaction to return it from `TransactionProcessor::finalize`. Off by default, so the
+    /// rejections of a large input don't pile up in memory when nobody asks for them.
+    pub collect_rejections: bool,
+
+    /// Called by the transaction processor with every rejected transaction, see `on_reject`.
+    pub on_reject: Option<RejectHook>,
+}
+
+impl Config {
+    /// Whether the amount of a deposit or withdrawal is within the configured ceiling, has no more integer digits
+    /// than allowed, and no more decimal places than its type allows.
+    pub fn allows_amount(&self, transaction_type: TransactionType, amount: Decimal) -> bool {
+        let integer_digits = amount
+            .trunc()
+            .mantissa()
+            .unsigned_abs()
+            .checked_ilog10()
+            .map_or(0, |log| log + 1);
+
+        self.max_amount
+            .is_none_or(|max_amount| amount <= max_amount)
+            && self
+                .max_integer_digits
+                .is_none_or(|max_integer_digits| integer_digits <= max_integer_digits)
+            && self
+                .decimal_places
+                .get(&transaction_type)
+                .is_none_or(|decimal_places| amount.normalize().scale() <= *decimal_places)
+    }
+
+    /// Whether the ledger rejects the amount of a deposit or withdrawal whatever the balances: missing when it
+    /// must be given, negative, with more than `MAX_DECIMAL_PLACES`, or not allowed by `allows_amount`. Other
+    /// transactions are never rejected for their amount here.
+    pub fn rejects_amount(&self, transaction: &Transaction) -> bool {
+        let transaction = if self.normalize_signs {
+            transaction.clone().with_normalized_sign()
+        } else {
+            transaction.clone()
+        };
+        let transaction_type = transaction.get_type();
+        if !matches!(
+            transaction_type,
+            TransactionType::Deposit | TransactionType::Withdrawal
+        ) {
+            return false;
+        }
+
+        match *transaction.get_amount() {
+            Some(amount) => {
+                amount.is_sign_negative()
+                    || amount.normalize().scale() > MAX_DECIMAL_PLACES
+                    || !self.allows_amount(transaction_type, amount)
+            }
+            None => self.missing_amount_policy == MissingAmountPolicy::Reject,
+        }
+    }
+
+    /// How much more a client can have held under the held cap, or `None` without a cap. Fails when the cap of
+    /// the client's total overflows, which only a cap above 1 can do.
+    pub fn held_room(&self, client: &Client) -> Result<Option<Decimal>, ClientError> {
+        let Some(held_cap) = self.held_cap else {
+            return Ok(None);
+        };
+
+        let room = held_cap
+            .checked_mul(client.get_total())
+            .and_then(|capped| capped.checked_sub(client.get_held()))
+            .ok_or(ClientError::Overflow)?;
+
+        Ok(Some(room.max(Decimal::ZERO)))
+    }
+
+    /// Call `on_reject` with every transaction the transaction processor rejects, and the reason. The workers
+    /// only queue the rejections on a channel, the callback runs on a task of its own, so a slow callback never
+    /// holds up processing. Every call is done by the time the processor returns its results. The rejections
+    /// aren't kept for it, see `collect_rejections`.
+    pub fn on_reject(
+        mut self,
+        on_reject: impl Fn(&Transaction, RejectionReason) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_reject = Some(RejectHook(Arc::new(on_reject)));
+        self
+    }
+
+    pub fn now(&self) -> SystemTime {
+        match &self.clock {
+            Some(clock) => clock.now(),
+            None => SystemClock.now(),
+        }
+    }
+
+    /// Whether a dispute held since `disputed_at` has been held for the hold days, and may be resolved.
+    pub fn hold_matured(&self, disputed_at: SystemTime) -> bool {
+        self.hold_days.is_none_or(|hold_days| {
+            let hold = Duration::from_secs(u64::from(hold_days) * 24 * 60 * 60);
+
+            self.now()
+                .duration_since(disputed_at)
+                .is_ok_and(|held_for| held_for >= hold)
+        })
+    }
+
+    /// Number of workers that will actually be spawned: the requested count clamped between one and
+    /// `MAX_WORKERS_PER_CPU` per CPU.
+    pub fn worker_count(&self) -> usize {
+        let cpus = available_cpus();
+
+        self.workers
+            .unwrap_or(cpus)
+            .clamp(1, cpus * MAX_WORKERS_PER_CPU)
+    }
+
+    /// Capacity of every channel of the processor, at least one.
+    pub fn channel_capacity(&self) -> usize {
+        self.channel_capacity
+            .unwrap_or(DEFAULT_CHANNEL_CAPACITY)
+            .max(1)
+    }
+
+    /// Warning to report when the requested number of workers had to be clamped.
+    pub fn worker_count_warning(&self) -> Option<String> {
+        let requested = self.workers?;
+        let effective = self.worker_count();
+
+        (requested != effective)
+            .then(|| format!("Requested {requested} workers, using {effective} instead."))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test that a huge worker count, or none at all, is clamped and reported.
+    #[test]
+    fn test_clamped_worker_count() {
+        let config = Config {
+            workers: Some(100000),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            config.worker_count(),
+            available_cpus() * MAX_WORKERS_PER_CPU
+        );
+        assert!(config.worker_count_warning().is_some());
+
+        let config = Config {
+            workers: Some(0),
+            ..Default::default()
+        };
+
+        assert_eq!(config.worker_count(), 1);
+        assert!(config.worker_count_warning().is_some());
+    }
+
+    /// Test that sensible worker counts are used as requested.
+    #[test]
+    fn test_worker_count() {
+        let config = Config {
+            workers: Some(2),
+            ..Default::default()
+        };
+
+        assert_eq!(config.worker_count(), 2);
+        assert!(config.worker_count_warning().is_none());
+        assert_eq!(Config::default().worker_count(), available_cpus());
+    }
+
+    /// Test that at least one CPU, and so at least one worker, is always counted.
+    #[test]
+    fn test_available_cpus() {
+        assert!(available_cpus() >= 1);
+        assert!(Config::default().worker_count() >= 1);
+    }
+
+    /// Test the amount ceiling right at its edge.
+    #[test]
+    fn test_max_amount() {
+        let max_amount = Decimal::MAX / Decimal::from(1000);
+        let config = Config {
+            max_amount: Some(max_amount),
+            ..Default::default()
+        };
+
+        assert!(config.allows_amount(TransactionType::Deposit, max_amount));
+        assert!(config.allows_amount(TransactionType::Deposit, max_amount - Decimal::ONE));
+        assert!(!config.allows_amount(TransactionType::Deposit, max_amount + Decimal::ONE));
+        assert!(!config.allows_amount(TransactionType::Deposit, Decimal::MAX));
+        assert!(Config::default().allows_amount(TransactionType::Deposit, Decimal::MAX));
+    }
+}
@@ -0,0 +1,207 @@
+use crate::transaction::{Transaction, TransactionType};
+use anyhow::Result;
+use rust_decimal::Decimal;
+use std::collections::HashMap;
+use tokio::io::AsyncWrite;
+
+/// Number of clients the generated transactions are spread over, fewer for a small count.
+const GENERATED_CLIENTS: u64 = 1000;
+
+/// SplitMix64, a small and fast generator. It isn't cryptographic, it only has to give the same transactions for
+/// the same seed on every platform.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Number in `0..bound`.
+    fn below(&mut self, bound: u64) -> u64 {
+        self.next() % bound
+    }
+
+    /// Amount in `0.0001..=max`, with four decimal places like the input.
+    fn amount(&mut self, max: u64) -> Decimal {
+        Decimal::new((self.below(max * 10_000) + 1) as i64, 4)
+    }
+}
+
+/// Generate `count` transactions that look like a real input, the same ones for the same seed: mostly deposits
+/// and withdrawals, and occasionally the dispute of an earlier deposit of the same client, or the resolve of an
+/// open dispute. Disputes and resolves only ever reference a deposit that was generated before them, each
+/// deposit is disputed at most once and only while its amount is available, so none is rejected for its
+/// reference. Withdrawals may exceed the funds.
+pub fn generate(count: usize, seed: u64) -> Vec<Transaction> {
+    let mut rng = Rng(seed);
+    let clients = GENERATED_CLIENTS.min(count as u64 / 10 + 1);
+
+    // Deposits of each client that were never disputed, disputes still open, and the available funds of each
+    // client, as the ledger will have them.
+    let mut undisputed: HashMap<u16, Vec<(u32, Decimal)>> = HashMap::new();
+    let mut open_disputes: Vec<(u16, u32, Decimal)> = Vec::new();
+    let mut available: HashMap<u16, Decimal> = HashMap::new();
+
+    let mut transactions = Vec::with_capacity(count);
+    let mut next_tx = 1;
+    while transactions.len() < count {
+        let client = rng.below(clients) as u16 + 1;
+
+        let transaction = match rng.below(100) {
+            0..=59 => {
+                let amount = rng.amount(1000);
+                undisputed
+                    .entry(client)
+                    .or_default()
+                    .push((next_tx, amount));
+                *available.entry(client).or_default() += amount;
+
+                Transaction::new(TransactionType::Deposit, client, next_tx, Some(amount))
+            }
+            60..=89 => {
+                let amount = rng.amount(100);
+                let funds = available.entry(client).or_default();
+                if amount <= *funds {
+                    *funds -= amount;
+                }
+
+                Transaction::new(TransactionType::Withdrawal, client, next_tx, Some(amount))
+            }
+            90..=95 => {
+                let funds = available.entry(client).or_default();
+                let Some(deposits) = undisputed
+                    .get_mut(&client)
+                    .filter(|deposits| !deposits.is_empty())
+                else {
+                    continue;
+                };
+                let (tx, amount) = deposits[rng.below(deposits.len() as u64) as usize];
+                if amount > *funds {
+                    continue;
+                }
+                deposits.retain(|(deposit_tx, _)| *deposit_tx != tx);
+                *funds -= amount;
+                open_disputes.push((client, tx, amount));
+
+                Transaction::new(TransactionType::Dispute, client, tx, None)
+            }
+            _ => {
+                if open_disputes.is_empty() {
+                    continue;
+                }
+                let (client, tx, amount) =
+                    open_disputes.swap_remove(rng.below(open_disputes.len() as u64) as usize);
+                *available.entry(client).or_default() += amount;
+
+                Transaction::new(TransactionType::Resolve, client, tx, None)
+            }
+        };
+
+        // Only deposits and withdrawals take a new tx id, disputes and resolves reuse the referenced one.
+        if transaction.get_tx_id() == next_tx {
+            next_tx += 1;
+        }
+        transactions.push(transaction);
+    }
+
+    transactions
+}
+
+/// Write transactions as CSV rows in the input format.
+pub async fn write_transactions<W>(writer: W, transactions: &[Transaction]) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut writer = csv_async::AsyncWriter::from_writer(writer);
+    writer
+        .write_record(["type", "client", "tx", "amount"])
+        .await?;
+
+    for transaction in transactions {
+        let transaction_type = transaction.get_type();
+        writer
+            .write_record([
+                transaction_type.name().to_string(),
+                transaction.get_client_id().to_string(),
+                transaction.get_tx_id().to_string(),
+                transaction
+                    .get_amount()
+                    .map(|amount| amount.to_string())
+                    .unwrap_or_default(),
+            ])
+            .await?;
+    }
+
+    writer.flush().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        input::TransactionReader,
+        ledger::{Ledger, RejectionReason},
+    };
+    use tokio::sync::mpsc;
+
+    /// Test that the same seed generates the same transactions, and a different one others.
+    #[test]
+    fn test_reproducible() {
+        let rows = |seed| {
+            generate(200, seed)
+                .iter()
+                .map(|transaction| {
+                    (
+                        transaction.get_type(),
+                        transaction.get_client_id(),
+                        transaction.get_tx_id(),
+                        *transaction.get_amount(),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(rows(7).len(), 200);
+        assert_eq!(rows(7), rows(7));
+        assert_ne!(rows(7), rows(8));
+    }
+
+    /// Test that a generated file is read back whole, and none of its disputes or resolves has a bad reference.
+    #[tokio::test]
+    async fn test_generated_references() {
+        let transactions = generate(2000, 42);
+        let mut file = Vec::new();
+        write_transactions(&mut file, &transactions).await.unwrap();
+
+        let mut reader = TransactionReader::new(&file[..]);
+        let (rejections, mut rejections_rx) = mpsc::unbounded_channel();
+        let mut ledger = Ledger::new(Default::default()).with_rejections(rejections);
+        while let Some(transaction) = reader.next().await.unwrap() {
+            ledger.apply(transaction);
+        }
+
+        assert_eq!(reader.stats().transactions, 2000);
+        assert_eq!(reader.stats().malformed, 0);
+        assert!(transactions
+            .iter()
+            .any(|transaction| transaction.get_type() == TransactionType::Dispute));
+
+        while let Ok(rejection) = rejections_rx.try_recv() {
+            assert!(
+                !matches!(
+                    rejection.reason,
+                    RejectionReason::UnknownTransaction
+                        | RejectionReason::AlreadyDisputed
+                        | RejectionReason::NotDisputed
+                ),
+                "{rejection:?}"
+            );
+        }
+    }
+}
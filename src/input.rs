@@ -0,0 +1,1124 @@
+use crate::{
+    binary,
+    transaction::{Transaction, TransactionType},
+};
+use anyhow::{bail, Result};
+use async_compression::tokio::bufread::GzipDecoder;
+use csv_async::{StringRecord, Trim};
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{self, ErrorKind},
+    mem,
+    path::Path,
+    pin::Pin,
+    task::{Context, Poll},
+    time::SystemTime,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader, ReadBuf},
+    sync::mpsc,
+    time::Instant,
+};
+
+/// Number of columns of a transaction: type, client, tx and amount.
+const TRANSACTION_FIELDS: usize = 4;
+
+/// Input path of the standard input.
+pub const STDIN_PATH: &str = "-";
+
+/// Bytes every gzip stream starts with.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Name of the optional column after the ones of a transaction with the transaction a dispute refers to.
+const REF_TX_FIELD: &str = "ref_tx";
+
+/// Name of the optional column after the ones of a transaction with the currency it is in.
+const CURRENCY_FIELD: &str = "currency";
+
+/// Number of columns of a transaction with a split amount: type, client, tx, dollars and cents.
+const SPLIT_AMOUNT_FIELDS: usize = 5;
+
+/// Counts of the rows read from the input, not counting the header.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ReadStats {
+    /// Rows read as transactions.
+    pub transactions: usize,
+
+    /// Rows whose fields are all empty or whitespace, which are skipped without being an error. Completely empty
+    /// lines never make it to a row and aren't counted.
+    pub blank: usize,
+
+    /// Rows that couldn't be parsed, had fewer fields than the header, or couldn't be read as a transaction.
+    pub malformed: usize,
+
+    /// Whether reading stopped at the deadline, before the end of the input.
+    pub truncated: bool,
+}
+
+/// Format of the transactions input.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum InputFormat {
+    #[default]
+    Csv,
+
+    /// Length-prefixed binary records, see the `binary` module.
+    Binary,
+
+    /// CSV with the amount split in whole `dollars` and `cents` columns, see `SplitAmountRow`.
+    SplitAmountCsv,
+}
+
+/// Row of a CSV input with the amount split in whole dollars and cents, which are composed exactly into the
+/// amount, so `10` dollars and `5` cents is `10.05`. The cents must be in `0..100` and take the sign of the
+/// dollars. Without dollars or cents there is no amount, and cents without dollars are invalid.
+#[derive(Debug, Deserialize)]
+struct SplitAmountRow {
+    #[serde(rename = "type")]
+    ttype: TransactionType,
+    client: u16,
+    tx: u32,
+    dollars: Option<i64>,
+    cents: Option<u8>,
+}
+
+impl SplitAmountRow {
+    fn into_transaction(self) -> Option<Transaction> {
+        let amount = match (self.dollars, self.cents) {
+            (None, None) => None,
+            (None, Some(_)) => return None,
+            (Some(_), Some(cents)) if cents >= 100 => return None,
+            (Some(dollars), cents) => {
+                let cents = Decimal::new(i64::from(cents.unwrap_or_default()), 2);
+                let amount = Decimal::from(dollars.abs()) + cents;
+
+                Some(if dollars.is_negative() {
+                    -amount
+                } else {
+                    amount
+                })
+            }
+        };
+
+        Some(Transaction::new(self.ttype, self.client, self.tx, amount))
+    }
+}
+
+/// What to do with a CSV row that isn't valid UTF-8.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum InvalidUtf8Policy {
+    /// Stop reading with an error.
+    Abort,
+
+    /// Skip the row as malformed.
+    #[default]
+    Skip,
+
+    /// Replace the invalid bytes with `U+FFFD` and read the row as usual, which is still malformed if they were
+    /// in a number.
+    Replace,
+}
+
+/// Mapping from the values of a `direction` column to the transaction types they stand for, by default `credit`
+/// for a deposit and `debit` for a withdrawal. Values are matched in any case.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirectionMap(HashMap<String, TransactionType>);
+
+impl Default for DirectionMap {
+    fn default() -> Self {
+        let mut directions = Self::empty();
+        directions.insert("credit", TransactionType::Deposit);
+        directions.insert("debit", TransactionType::Withdrawal);
+
+        directions
+    }
+}
+
+impl DirectionMap {
+    /// Mapping without any direction, to be filled with `insert`.
+    pub fn empty() -> Self {
+        Self(HashMap::new())
+    }
+
+    pub fn insert(&mut self, direction: &str, transaction_type: TransactionType) {
+        self.0
+            .insert(direction.to_ascii_lowercase(), transaction_type);
+    }
+
+    pub fn get(&self, direction: &str) -> Option<TransactionType> {
+        self.0.get(&direction.to_ascii_lowercase()).copied()
+    }
+}
+
+/// Row of a CSV input whose first column is a `direction` instead of the type, mapped by a `DirectionMap`. A
+/// direction that isn't mapped is read as a type name, so the disputes of such a feed keep their usual type.
+#[derive(Debug, Deserialize)]
+struct DirectionRow {
+    direction: String,
+    client: u16,
+    tx: u32,
+    amount: Option<Decimal>,
+
+    #[serde(default)]
+    ref_tx: Option<u32>,
+}
+
+impl DirectionRow {
+    fn into_transaction(self, directions: &DirectionMap) -> Option<Transaction> {
+        let transaction_type = match directions.get(&self.direction) {
+            Some(transaction_type) => transaction_type,
+            None => self.direction.parse().ok()?,
+        };
+
+        let transaction = Transaction::new(transaction_type, self.client, self.tx, self.amount);
+
+        Some(match self.ref_tx {
+            Some(ref_tx) => transaction.with_ref_tx(ref_tx),
+            None => transaction,
+        })
+    }
+}
+
+/// Order in which the files of an input directory are processed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum InputOrder {
+    /// Lexicographic order of the file names.
+    #[default]
+    Name,
+
+    /// Oldest modification time first, ties in name order.
+    Modified,
+}
+
+/// An input decompressed on the fly if it is gzipped, as told by its first bytes rather than its name, so a
+/// compressed standard input or S3 object is read as well. Anything else, such as a plain CSV or binary input,
+/// is read as it is.
+pub async fn decompressed(
+    input: Box<dyn AsyncRead + Unpin + Send>,
+) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+    let mut input = BufReader::new(input);
+    if !input.fill_buf().await?.starts_with(&GZIP_MAGIC) {
+        return Ok(Box::new(input));
+    }
+
+    // Concatenated gzip files are a valid gzip file too.
+    let mut decoder = GzipDecoder::new(input);
+    decoder.multiple_members(true);
+
+    Ok(Box::new(decoder))
+}
+
+/// The `*.csv` and `*.csv.gz` files of a directory, in the given order. Other files and subdirectories are
+/// skipped, so an empty directory, or one without CSV files, has no inputs.
+pub async fn list_input_dir(directory: impl AsRef<Path>, order: InputOrder) -> Result<Vec<String>> {
+    let mut files = Vec::new();
+    let mut entries = tokio::fs::read_dir(directory).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        let metadata = entry.metadata().await?;
+        let is_csv = path
+            .to_str()
+            .is_some_and(|path| path.ends_with(".csv") || path.ends_with(".csv.gz"));
+        if !metadata.is_file() || !is_csv {
+            continue;
+        }
+
+        let modified = match order {
+            InputOrder::Name => SystemTime::UNIX_EPOCH,
+            InputOrder::Modified => metadata.modified()?,
+        };
+        files.push((modified, path));
+    }
+
+    files.sort();
+
+    Ok(files
+        .into_iter()
+        .map(|(_, path)| path.to_string_lossy().into_owned())
+        .collect())
+}
+
+/// Reader that keeps up to a number of raw records of another reader read ahead, so a stall of the input (such
+/// as a slow network) is absorbed by the records already buffered instead of holding up the workers.
+///
+/// The records are read on a task of their own, split at line ends, which are the CSV records. A binary input is
+/// split at arbitrary bytes instead, which is just as correct. The memory cost is up to `records` records, plus
+/// one being read: with 100 byte rows and 10000 records, about one megabyte.
+pub struct ReadAhead {
+    records: mpsc::Receiver<io::Result<Vec<u8>>>,
+    current: Vec<u8>,
+    position: usize,
+}
+
+impl ReadAhead {
+    pub fn new<R>(reader: R, records: usize) -> Self
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+    {
+        let (records_tx, records_rx) = mpsc::channel(records.max(1));
+        tokio::spawn(async move {
+            let mut reader = BufReader::new(reader);
+            loop {
+                let mut record = Vec::new();
+                let result = reader.read_until(b'\n', &mut record).await;
+                let end = matches!(result, Ok(0) | Err(_));
+
+                // Stop early if the reader was dropped.
+                if !matches!(result, Ok(0))
+                    && records_tx.send(result.map(|_| record)).await.is_err()
+                {
+                    break;
+                }
+                if end {
+                    break;
+                }
+            }
+        });
+
+        Self {
+            records: records_rx,
+            current: Vec::new(),
+            position: 0,
+        }
+    }
+}
+
+impl AsyncRead for ReadAhead {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        while self.position == self.current.len() {
+            match self.records.poll_recv(cx) {
+                Poll::Ready(Some(Ok(record))) => {
+                    self.current = record;
+                    self.position = 0;
+                }
+                Poll::Ready(Some(Err(error))) => return Poll::Ready(Err(error)),
+                // The end of the input.
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        let length = buf.remaining().min(self.current.len() - self.position);
+        buf.put_slice(&self.current[self.position..self.position + length]);
+        self.position += length;
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Decoder of the rows of one of the input formats.
+enum Decoder<R> {
+    Csv {
+        reader: csv_async::AsyncReader<R>,
+        record: StringRecord,
+        split_amount: bool,
+        directions: DirectionMap,
+        positional: bool,
+        invalid_utf8: InvalidUtf8Policy,
+    },
+    Binary(R),
+}
+
+/// Error of a malformed row read by a strict reader, naming where it is and what it has.
+#[derive(Debug)]
+pub struct MalformedRecord(String);
+
+impl fmt::Display for MalformedRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Malformed record {}.", self.0)
+    }
+}
+
+impl std::error::Error for MalformedRecord {}
+
+/// Outcome of reading one row.
+enum Row {
+    Transaction(Transaction),
+    Blank,
+
+    /// Where the malformed row is, and what it has, to name it in an error.
+    Malformed(String),
+    End,
+}
+
+/// Name a malformed CSV row by its line and fields.
+fn malformed(line: u64, record: &StringRecord) -> Row {
+    Row::Malformed(format!(
+        "at line {line}: {}",
+        record.iter().collect::<Vec<_>>().join(", ")
+    ))
+}
+
+/// Read transactions from CSV, or the binary format, skipping blank and malformed rows. Extra trailing CSV columns
+/// are ignored, except a `ref_tx` right after the amount.
+pub struct TransactionReader<R> {
+    decoder: Decoder<R>,
+    stats: ReadStats,
+    deadline: Option<Instant>,
+    strict: bool,
+
+    /// Line of the last row read from a CSV input.
+    line: u64,
+}
+
+impl<R> TransactionReader<R>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    pub fn new(reader: R) -> Self {
+        Self::with_format(reader, InputFormat::Csv)
+    }
+
+    pub fn with_format(reader: R, format: InputFormat) -> Self {
+        Self::with_layout(reader, format, false, true)
+    }
+
+    /// Read the CSV columns by position only, in the order of the format, whatever the header names them. The
+    /// columns named by the header, a `direction` or a `ref_tx`, aren't recognized. Without headers, the first
+    /// row is a transaction too.
+    pub fn positional(reader: R, format: InputFormat, has_headers: bool) -> Self {
+        Self::with_layout(reader, format, true, has_headers)
+    }
+
+    fn with_layout(reader: R, format: InputFormat, positional: bool, has_headers: bool) -> Self {
+        let decoder = match format {
+            InputFormat::Csv | InputFormat::SplitAmountCsv => {
+                let reader = csv_async::AsyncReaderBuilder::new()
+                    .trim(Trim::All) // Make sure we trim everything to avoid parsing errors.
+                    .flexible(true) // The number of fields is checked later, to tell blank rows from malformed.
+                    .has_headers(has_headers)
+                    .create_reader(reader);
+
+                Decoder::Csv {
+                    reader,
+                    record: StringRecord::new(),
+                    split_amount: format == InputFormat::SplitAmountCsv,
+                    directions: Default::default(),
+                    positional,
+                    invalid_utf8: Default::default(),
+                }
+            }
+            InputFormat::Binary => Decoder::Binary(reader),
+        };
+
+        Self {
+            decoder,
+            stats: Default::default(),
+            deadline: None,
+            strict: false,
+            line: 0,
+        }
+    }
+
+    /// Map the values of a `direction` column, read when the header has one in place of the type, with the given
+    /// directions instead of the default ones. Only the CSV format has a header.
+    pub fn with_directions(mut self, directions: DirectionMap) -> Self {
+        if let Decoder::Csv {
+            directions: decoder_directions,
+            ..
+        } = &mut self.decoder
+        {
+            *decoder_directions = directions;
+        }
+
+        self
+    }
+
+    /// Handle the CSV rows that aren't valid UTF-8 with the given policy, instead of skipping them.
+    pub fn with_invalid_utf8(mut self, policy: InvalidUtf8Policy) -> Self {
+        if let Decoder::Csv { invalid_utf8, .. } = &mut self.decoder {
+            *invalid_utf8 = policy;
+        }
+
+        self
+    }
+
+    /// Stop reading at the deadline, as if the input had ended there. The deadline is checked before every row,
+    /// and also interrupts a read that is waiting on the input.
+    pub fn with_deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Fail on the first malformed row with a `MalformedRecord` naming it, instead of skipping it. Blank rows are
+    /// still skipped, and reading may go on with the rows after it.
+    pub fn strict(mut self) -> Self {
+        self.strict = true;
+        self
+    }
+
+    /// Read the next transaction, or `None` at the end of the input.
+    pub async fn next(&mut self) -> Result<Option<Transaction>> {
+        loop {
+            let row = match self.deadline {
+                Some(deadline) if Instant::now() >= deadline => None,
+                Some(deadline) => {
+                    tokio::time::timeout_at(deadline, self.decoder.read_row(&mut self.line))
+                        .await
+                        .ok()
+                }
+                None => Some(self.decoder.read_row(&mut self.line).await),
+            };
+
+            let Some(row) = row else {
+                self.stats.truncated = true;
+                return Ok(None);
+            };
+
+            match row? {
+                Row::Transaction(transaction) => {
+                    self.stats.transactions += 1;
+                    return Ok(Some(transaction));
+                }
+                Row::Blank => self.stats.blank += 1,
+                Row::Malformed(row) if self.strict => return Err(MalformedRecord(row).into()),
+                Row::Malformed(_) => self.stats.malformed += 1,
+                Row::End => return Ok(None),
+            }
+        }
+    }
+
+    pub fn stats(&self) -> ReadStats {
+        self.stats
+    }
+
+    /// Line of the last row read from a CSV input, or its number among the rows of a binary input.
+    pub fn line(&self) -> u64 {
+        match &self.decoder {
+            Decoder::Csv { .. } => self.line,
+            Decoder::Binary(_) => {
+                (self.stats.transactions + self.stats.blank + self.stats.malformed) as u64
+            }
+        }
+    }
+}
+
+impl<R> Decoder<R>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    /// Read the next row, keeping the line it is on for a CSV input.
+    async fn read_row(&mut self, line: &mut u64) -> Result<Row> {
+        match self {
+            Self::Csv {
+                reader,
+                record,
+                split_amount,
+                directions,
+                positional,
+                invalid_utf8,
+            } => {
+                // Without headers to go by, a row needs every column of the format.
+                let (fields, by_direction, with_ref_tx, currency_field) = if *positional {
+                    let fields = if *split_amount {
+                        SPLIT_AMOUNT_FIELDS
+                    } else {
+                        TRANSACTION_FIELDS
+                    };
+
+                    (fields, false, false, None)
+                } else {
+                    let headers = reader.headers().await?;
+
+                    (
+                        headers.len(),
+                        headers.get(0) == Some("direction"),
+                        headers.get(TRANSACTION_FIELDS) == Some(REF_TX_FIELD),
+                        headers.iter().position(|header| header == CURRENCY_FIELD),
+                    )
+                };
+
+                // Read the raw bytes to decide about invalid UTF-8 here, reusing the record's buffer.
+                let mut bytes = mem::take(record).into_byte_record();
+                match reader.read_byte_record(&mut bytes).await {
+                    Ok(false) => return Ok(Row::End),
+                    Ok(true) => {}
+                    Err(error) => return Ok(Row::Malformed(format!("({error})"))),
+                }
+                *line = bytes.position().map_or(0, |position| position.line());
+                let line = *line;
+
+                *record = match StringRecord::from_byte_record(bytes) {
+                    Ok(record) => record,
+                    Err(error) => match invalid_utf8 {
+                        InvalidUtf8Policy::Abort => bail!("The input isn't valid UTF-8: {error}."),
+                        InvalidUtf8Policy::Skip => {
+                            return Ok(Row::Malformed(format!("at line {line}, invalid UTF-8")))
+                        }
+                        InvalidUtf8Policy::Replace => error
+                            .into_byte_record()
+                            .iter()
+                            .map(String::from_utf8_lossy)
+                            .collect(),
+                    },
+                };
+                // The reader doesn't trim the first byte record of a headerless input.
+                record.trim();
+
+                if record.iter().all(str::is_empty) {
+                    return Ok(Row::Blank);
+                }
+
+                if record.len() < fields {
+                    return Ok(malformed(line, record));
+                }
+
+                // Read before the extra columns are dropped. An empty currency is none.
+                let currency = match currency_field
+                    .and_then(|field| record.get(field))
+                    .filter(|code| !code.is_empty())
+                    .map(str::parse)
+                {
+                    Some(Ok(currency)) => Some(currency),
+                    Some(Err(_)) => return Ok(malformed(line, record)),
+                    None => None,
+                };
+                let in_currency = |transaction: Transaction| {
+                    Row::Transaction(transaction.with_currency(currency))
+                };
+
+                // Columns after the ones of a transaction are of no interest and are dropped, whether the header
+                // names them or not, except a `ref_tx` the header names. The currency was read above.
+                if *split_amount {
+                    record.truncate(SPLIT_AMOUNT_FIELDS);
+
+                    return Ok(record
+                        .deserialize::<SplitAmountRow>(None)
+                        .ok()
+                        .and_then(SplitAmountRow::into_transaction)
+                        .map_or_else(|| malformed(line, record), in_currency));
+                }
+
+                record.truncate(TRANSACTION_FIELDS + usize::from(with_ref_tx));
+
+                if by_direction {
+                    return Ok(record
+                        .deserialize::<DirectionRow>(None)
+                        .ok()
+                        .and_then(|row| row.into_transaction(directions))
+                        .map_or_else(|| malformed(line, record), in_currency));
+                }
+
+                Ok(record
+                    .deserialize::<Transaction>(None)
+                    .map_or_else(|_| malformed(line, record), in_currency))
+            }
+
+            Self::Binary(reader) => {
+                let length = match reader.read_u8().await {
+                    Ok(length) => length,
+                    Err(error) if error.kind() == ErrorKind::UnexpectedEof => return Ok(Row::End),
+                    Err(error) => return Err(error.into()),
+                };
+
+                // A record cut short by the end of the input is malformed, and the next read finds the end.
+                let mut payload = vec![0; length as usize];
+                match reader.read_exact(&mut payload).await {
+                    Ok(_) => {}
+                    Err(error) if error.kind() == ErrorKind::UnexpectedEof => {
+                        return Ok(Row::Malformed(format!("cut short, {payload:02x?}")))
+                    }
+                    Err(error) => return Err(error.into()),
+                }
+
+                Ok(binary::decode(&payload).map_or_else(
+                    |error| Row::Malformed(format!("{payload:02x?} ({error})")),
+                    Row::Transaction,
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ledger::Ledger;
+
+    async fn read_all(input: &str) -> (Vec<Transaction>, ReadStats) {
+        read_all_with_format(input, InputFormat::Csv).await
+    }
+
+    async fn read_all_with_format(
+        input: &str,
+        format: InputFormat,
+    ) -> (Vec<Transaction>, ReadStats) {
+        let mut reader = TransactionReader::with_format(input.as_bytes(), format);
+
+        let mut transactions = Vec::new();
+        while let Some(transaction) = reader.next().await.unwrap() {
+            transactions.push(transaction);
+        }
+
+        (transactions, reader.stats())
+    }
+
+    /// Test that blank rows between valid rows are skipped without being malformed.
+    #[tokio::test]
+    async fn test_blank_rows() {
+        let (transactions, stats) = read_all(
+            "type, client, tx, amount\n\
+             deposit, 1, 1, 1.0\n\
+             \n\
+             deposit, 1, 2, 2.0\n\
+             \x20\x20\x20\n\
+             ,,,\n\
+             withdrawal, 1, 3, 1.5\n",
+        )
+        .await;
+
+        assert_eq!(transactions.len(), 3);
+        assert_eq!(
+            stats,
+            ReadStats {
+                transactions: 3,
+                blank: 2,
+                malformed: 0,
+                truncated: false,
+            }
+        );
+    }
+
+    /// Test that rows with missing fields, or fields that aren't a transaction, are malformed.
+    #[tokio::test]
+    async fn test_malformed_rows() {
+        let (transactions, stats) = read_all(
+            "type, client, tx, amount\n\
+             deposit, 1, 1, 1.0\n\
+             deposit, 1, 2\n\
+             deposit\n\
+             deposit, one, 4, 1.0\n",
+        )
+        .await;
+
+        assert_eq!(transactions.len(), 1);
+        assert_eq!(stats.malformed, 3);
+        assert_eq!(stats.blank, 0);
+    }
+
+    /// Test that the type is read in any case, and a row of an unknown type is malformed.
+    #[tokio::test]
+    async fn test_type_case() {
+        let (transactions, stats) = read_all(
+            "type, client, tx, amount\n\
+             DEPOSIT, 1, 1, 1.0\n\
+             WithDrawal, 1, 2, 0.5\n\
+             transfer, 1, 3, 1.0\n",
+        )
+        .await;
+
+        let types = transactions
+            .iter()
+            .map(Transaction::get_type)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            types,
+            [TransactionType::Deposit, TransactionType::Withdrawal]
+        );
+        assert_eq!(stats.malformed, 1);
+    }
+
+    /// Test that extra trailing columns are ignored, with or without a header naming them.
+    #[tokio::test]
+    async fn test_extra_trailing_columns() {
+        let (transactions, stats) = read_all(
+            "type, client, tx, amount\n\
+             deposit, 1, 1, 1.0, branch-7, 2022-05-01\n\
+             dispute, 1, 1,, branch-7, 2022-05-02\n",
+        )
+        .await;
+
+        assert_eq!(transactions.len(), 2);
+        assert_eq!(transactions[0].get_amount(), &Some(Decimal::new(10, 1)));
+        assert_eq!(transactions[1].get_type(), TransactionType::Dispute);
+        assert_eq!(stats.malformed, 0);
+
+        let (transactions, _) = read_all(
+            "type, client, tx, amount, branch, date\n\
+             withdrawal, 2, 3, 0.5, branch-7, 2022-05-03\n",
+        )
+        .await;
+
+        assert_eq!(transactions.len(), 1);
+        assert_eq!(transactions[0].get_amount(), &Some(Decimal::new(5, 1)));
+    }
+
+    /// Test that transactions written in the binary format are read back the same.
+    #[tokio::test]
+    async fn test_binary_roundtrip() {
+        let transactions = [
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(Decimal::new(15, 1))),
+            Transaction::new(
+                TransactionType::Withdrawal,
+                65535,
+                2,
+                Some(Decimal::new(-1, 4)),
+            ),
+            Transaction::new(TransactionType::Dispute, 1, 1, None),
+            Transaction::new(TransactionType::Resolve, 1, 1, None),
+            Transaction::new(TransactionType::Chargeback, 2, u32::MAX, None),
+        ];
+
+        let mut input = Vec::new();
+        binary::write_transactions(&mut input, &transactions)
+            .await
+            .unwrap();
+
+        let mut reader = TransactionReader::with_format(&input[..], InputFormat::Binary);
+        let mut read = Vec::new();
+        while let Some(transaction) = reader.next().await.unwrap() {
+            read.push(transaction);
+        }
+
+        let fields = |transaction: &Transaction| {
+            (
+                transaction.get_type(),
+                transaction.get_client_id(),
+                transaction.get_tx_id(),
+                *transaction.get_amount(),
+            )
+        };
+
+        assert_eq!(
+            read.iter().map(fields).collect::<Vec<_>>(),
+            transactions.iter().map(fields).collect::<Vec<_>>()
+        );
+        assert_eq!(reader.stats().malformed, 0);
+    }
+
+    /// Test that an undecodable binary record is skipped, and a truncated one ends the input.
+    #[tokio::test]
+    async fn test_binary_malformed_records() {
+        let deposit = Transaction::new(TransactionType::Deposit, 1, 1, Some(Decimal::ONE));
+
+        let mut input = vec![3, 0, 0, 0];
+        input.extend(binary::encode(&deposit).unwrap());
+        input.extend([15, 0, 1]);
+
+        let mut reader = TransactionReader::with_format(&input[..], InputFormat::Binary);
+        let mut transactions = 0;
+        while reader.next().await.unwrap().is_some() {
+            transactions += 1;
+        }
+
+        assert_eq!(transactions, 1);
+        assert_eq!(reader.stats().malformed, 2);
+    }
+
+    /// Test that reading stops at the deadline and reports the truncation.
+    #[tokio::test]
+    async fn test_deadline() {
+        let rows = 1000000;
+        let input = std::iter::once("type, client, tx, amount\n".to_string())
+            .chain((0..rows).map(|tx| format!("deposit, {}, {tx}, 1.0\n", tx % 100)))
+            .collect::<String>();
+
+        let mut reader = TransactionReader::new(input.as_bytes())
+            .with_deadline(Instant::now() + std::time::Duration::from_millis(1));
+
+        let mut transactions = 0;
+        while reader.next().await.unwrap().is_some() {
+            transactions += 1;
+        }
+
+        assert!(transactions < rows);
+        assert_eq!(reader.stats().transactions, transactions);
+        assert!(reader.stats().truncated);
+    }
+
+    /// Test that split dollars and cents compose into the amount, and cents out of range are malformed.
+    #[tokio::test]
+    async fn test_split_amount() {
+        let (transactions, stats) = read_all_with_format(
+            "type, client, tx, dollars, cents\n\
+             deposit, 1, 1, 10, 5\n\
+             deposit, 1, 2, 10, 150\n\
+             dispute, 1, 1, ,\n",
+            InputFormat::SplitAmountCsv,
+        )
+        .await;
+
+        assert_eq!(transactions.len(), 2);
+        assert_eq!(*transactions[0].get_amount(), Some(Decimal::new(1005, 2)));
+        assert_eq!(transactions[1].get_type(), TransactionType::Dispute);
+        assert_eq!(*transactions[1].get_amount(), None);
+        assert_eq!(stats.malformed, 1);
+    }
+
+    /// Test that credits and debits of a direction column give the same balances as deposits and withdrawals.
+    #[tokio::test]
+    async fn test_direction_column() {
+        let (by_type, _) = read_all(
+            "type, client, tx, amount\n\
+             deposit, 1, 1, 10.0\n\
+             withdrawal, 1, 2, 2.5\n\
+             deposit, 2, 3, 4.0\n\
+             dispute, 2, 3,\n",
+        )
+        .await;
+        let (by_direction, stats) = read_all(
+            "direction, client, tx, amount\n\
+             credit, 1, 1, 10.0\n\
+             DEBIT, 1, 2, 2.5\n\
+             credit, 2, 3, 4.0\n\
+             dispute, 2, 3,\n\
+             refund, 2, 4, 1.0\n",
+        )
+        .await;
+
+        assert_eq!(stats.transactions, 4);
+        assert_eq!(stats.malformed, 1);
+
+        let balances = |transactions: Vec<Transaction>| {
+            let mut ledger = Ledger::new(Default::default());
+            for transaction in transactions {
+                ledger.apply(transaction);
+            }
+
+            ledger.into_clients()
+        };
+        let clients = balances(by_direction);
+        assert_eq!(clients, balances(by_type));
+        assert_eq!(clients[&1].get_available(), Decimal::new(75, 1));
+        assert_eq!(clients[&2].get_held(), Decimal::new(4, 0));
+
+        // A custom mapping replaces the default one.
+        let mut directions = DirectionMap::empty();
+        directions.insert("in", TransactionType::Deposit);
+        let mut reader = TransactionReader::new(
+            "direction, client, tx, amount\n\
+             in, 1, 1, 1.0\n\
+             credit, 1, 2, 1.0\n"
+                .as_bytes(),
+        )
+        .with_directions(directions);
+
+        let transaction = reader.next().await.unwrap().unwrap();
+        assert_eq!(transaction.get_type(), TransactionType::Deposit);
+        assert!(reader.next().await.unwrap().is_none());
+        assert_eq!(reader.stats().malformed, 1);
+    }
+
+    /// Test that a dispute with a `ref_tx` column disputes the transaction it references, not its own tx id.
+    #[tokio::test]
+    async fn test_ref_tx_column() {
+        let (transactions, stats) = read_all(
+            "type, client, tx, amount, ref_tx\n\
+             deposit, 1, 1, 10.0,\n\
+             deposit, 1, 2, 4.0,\n\
+             dispute, 1, 100,, 1\n\
+             resolve, 1, 101,, 1\n\
+             dispute, 1, 2,,\n",
+        )
+        .await;
+
+        assert_eq!(stats.malformed, 0);
+        assert_eq!(transactions[2].get_tx_id(), 100);
+        assert_eq!(transactions[2].get_ref_tx_id(), 1);
+        assert_eq!(transactions[4].get_ref_tx_id(), 2);
+
+        let mut ledger = Ledger::new(Default::default());
+        for transaction in transactions {
+            ledger.apply(transaction);
+        }
+
+        // The first deposit was disputed and resolved, and the last dispute falls back to its own tx.
+        let client = &ledger.clients()[&1];
+        assert_eq!(client.get_available(), Decimal::TEN);
+        assert_eq!(client.get_held(), Decimal::new(4, 0));
+        assert_eq!(ledger.query_transactions(1).len(), 2);
+    }
+
+    /// Test that a headerless CSV is read by position, the first row included.
+    #[tokio::test]
+    async fn test_headerless_positional() {
+        let input = "deposit, 1, 1, 10.0\n\
+                     deposit, 2, 2, 5.0\n\
+                     withdrawal, 1, 3, 2.5\n\
+                     dispute, 2, 2,\n\
+                     deposit, 1\n";
+        let mut reader = TransactionReader::positional(input.as_bytes(), InputFormat::Csv, false);
+
+        let mut ledger = Ledger::new(Default::default());
+        while let Some(transaction) = reader.next().await.unwrap() {
+            ledger.apply(transaction);
+        }
+
+        assert_eq!(reader.stats().transactions, 4);
+        assert_eq!(reader.stats().malformed, 1);
+        assert_eq!(ledger.clients()[&1].get_available(), Decimal::new(75, 1));
+        assert_eq!(ledger.clients()[&2].get_held(), Decimal::new(5, 0));
+
+        // A header is skipped, whatever it names the columns.
+        let mut reader = TransactionReader::positional(
+            "kind, customer, id, value\ndeposit, 1, 1, 1.0\n".as_bytes(),
+            InputFormat::Csv,
+            true,
+        );
+        assert_eq!(reader.next().await.unwrap().unwrap().get_tx_id(), 1);
+        assert!(reader.next().await.unwrap().is_none());
+    }
+
+    /// Test that a row with an invalid UTF-8 byte is skipped alone, or aborts or is read lossily by policy.
+    #[tokio::test]
+    async fn test_invalid_utf8() {
+        let input = b"type, client, tx, amount\n\
+                      deposit, 1, 1, 10.0\n\
+                      deposit, 1, 2, 1\xff.0\n\
+                      deposit\xfe, 1, 3, 2.0\n\
+                      withdrawal, 1, 4, 2.5\n";
+        let read = |policy| async move {
+            let mut reader = TransactionReader::new(&input[..]).with_invalid_utf8(policy);
+            let mut transactions = Vec::new();
+            while let Some(transaction) = reader.next().await? {
+                transactions.push(transaction.get_tx_id());
+            }
+
+            anyhow::Ok((transactions, reader.stats().malformed))
+        };
+
+        assert_eq!(
+            read(InvalidUtf8Policy::Skip).await.unwrap(),
+            (vec![1, 4], 2)
+        );
+        assert!(read(InvalidUtf8Policy::Abort).await.is_err());
+
+        // The replaced bytes still spoil the amount and the type.
+        assert_eq!(
+            read(InvalidUtf8Policy::Replace).await.unwrap(),
+            (vec![1, 4], 2)
+        );
+    }
+
+    /// Reader that hands out one line per read, and stalls for a while every ten lines.
+    struct JitteryReader {
+        lines: std::collections::VecDeque<String>,
+        reads: usize,
+        stall: Option<Pin<Box<tokio::time::Sleep>>>,
+    }
+
+    impl AsyncRead for JitteryReader {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            if let Some(stall) = &mut self.stall {
+                if std::future::Future::poll(stall.as_mut(), cx).is_pending() {
+                    return Poll::Pending;
+                }
+                self.stall = None;
+            }
+
+            self.reads += 1;
+            if self.reads.is_multiple_of(10) {
+                self.stall = Some(Box::pin(tokio::time::sleep(
+                    std::time::Duration::from_millis(5),
+                )));
+            }
+
+            if let Some(line) = self.lines.pop_front() {
+                buf.put_slice(line.as_bytes());
+            }
+
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// Read 100 deposits from a jittery input, spending a millisecond on each, and return them with the time it
+    /// took.
+    async fn read_jittery(read_ahead: Option<usize>) -> (Vec<Transaction>, std::time::Duration) {
+        let reader = JitteryReader {
+            lines: std::iter::once("type, client, tx, amount\n".to_string())
+                .chain((1..=100).map(|tx| format!("deposit, 1, {tx}, 1.0\n")))
+                .collect(),
+            reads: 0,
+            stall: None,
+        };
+        let reader: Box<dyn AsyncRead + Unpin + Send> = match read_ahead {
+            Some(records) => Box::new(ReadAhead::new(reader, records)),
+            None => Box::new(reader),
+        };
+
+        let start = Instant::now();
+        let mut reader = TransactionReader::new(reader);
+        let mut transactions = Vec::new();
+        while let Some(transaction) = reader.next().await.unwrap() {
+            transactions.push(transaction);
+            tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+        }
+
+        (transactions, start.elapsed())
+    }
+
+    /// Test that reading ahead reads the same transactions, while hiding the stalls of the input behind the time
+    /// spent on the transactions.
+    #[tokio::test(start_paused = true)]
+    async fn test_read_ahead() {
+        let (direct, direct_elapsed) = read_jittery(None).await;
+        let (read_ahead, read_ahead_elapsed) = read_jittery(Some(20)).await;
+
+        assert_eq!(
+            direct
+                .iter()
+                .map(Transaction::get_tx_id)
+                .collect::<Vec<_>>(),
+            read_ahead
+                .iter()
+                .map(Transaction::get_tx_id)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(read_ahead.len(), 100);
+
+        // 100 ms are spent on the transactions, and the stalls add to it unless read ahead overlaps them.
+        assert!(direct_elapsed >= std::time::Duration::from_millis(130));
+        assert!(read_ahead_elapsed < std::time::Duration::from_millis(110));
+    }
+
+    /// Test that a strict reader fails on a malformed row, naming its line and fields, but still skips blank rows.
+    #[tokio::test]
+    async fn test_strict() {
+        let file = "type, client, tx, amount\n\
+                    deposit, 1, 1, 1.0\n\
+                    , , , \n\
+                    deposit, 1\n";
+        let mut reader = TransactionReader::new(file.as_bytes()).strict();
+
+        assert!(reader.next().await.unwrap().is_some());
+        assert_eq!(
+            reader.next().await.unwrap_err().to_string(),
+            "Malformed record at line 4: deposit, 1."
+        );
+        assert_eq!(reader.stats().blank, 1);
+        assert_eq!(reader.stats().malformed, 0);
+    }
+    /// Test that a `currency` column after the ones of a transaction is read, in any case, and that an invalid
+    /// currency makes the row malformed.
+    #[tokio::test]
+    async fn test_currency_column() {
+        let (transactions, stats) = read_all(
+            "type, client, tx, amount, currency\n\
+             deposit, 1, 1, 1.0, usd\n\
+             deposit, 1, 2, 1.0,\n\
+             deposit, 1, 3, 1.0, US-D\n",
+        )
+        .await;
+
+        let currencies = transactions
+            .iter()
+            .map(|transaction| {
+                transaction
+                    .get_currency()
+                    .map(|currency| currency.to_string())
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(currencies, [Some("USD".to_string()), None]);
+        assert_eq!(stats.malformed, 1);
+    }
+}
@@ -0,0 +1,2613 @@
+use crate::{
+    client::{Client, ClientError, Currency, LockTrigger},
+    config::{
+        Config, DisputePolicy, HeldCapPolicy, MissingAmountPolicy, NegativeTotalPolicy,
+        PhantomClientPolicy, PostLockChargebackPolicy,
+    },
+    transaction::{Transaction, TransactionType},
+};
+use anyhow::{bail, Result};
+use rust_decimal::Decimal;
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet, VecDeque},
+    iter,
+    panic::{self, AssertUnwindSafe},
+    time::SystemTime,
+};
+use tokio::sync::mpsc;
+
+/// Most disputes waiting for the deposit they refer to, a dispute of an unknown deposit beyond it is rejected
+/// right away.
+pub const PENDING_DISPUTES_CAPACITY: usize = 10_000;
+
+/// Tx ids in a page of `TxIdSet`, one bit each, so a page takes 8 KiB.
+const TX_ID_PAGE_BITS: usize = 1 << 16;
+
+/// Set of tx ids, as a bitmap over the u32 space whose pages are only allocated once one of their ids is inserted.
+///
+/// Ids that are mostly consecutive, as usual, take one bit each, where a `HashSet<u32>` takes at least five bytes.
+/// Scattered ids take up to a whole page each, but the set never grows past 512 MiB, however many ids it holds.
+#[derive(Debug, Clone, Default)]
+pub struct TxIdSet {
+    pages: Vec<Option<Box<[u64]>>>,
+}
+
+impl TxIdSet {
+    /// Insert a tx id, returning whether it wasn't in the set yet.
+    pub fn insert(&mut self, tx_id: u32) -> bool {
+        let (page, word, bit) = Self::position(tx_id);
+        if self.pages.len() <= page {
+            self.pages.resize(page + 1, None);
+        }
+
+        let word = &mut self.pages[page]
+            .get_or_insert_with(|| vec![0; TX_ID_PAGE_BITS / 64].into_boxed_slice())[word];
+        let inserted = *word & bit == 0;
+        *word |= bit;
+
+        inserted
+    }
+
+    pub fn contains(&self, tx_id: u32) -> bool {
+        let (page, word, bit) = Self::position(tx_id);
+
+        self.pages
+            .get(page)
+            .and_then(Option::as_ref)
+            .is_some_and(|page| page[word] & bit != 0)
+    }
+
+    /// Page, word in the page and bit in the word of a tx id.
+    fn position(tx_id: u32) -> (usize, usize, u64) {
+        let tx_id = tx_id as usize;
+
+        (
+            tx_id / TX_ID_PAGE_BITS,
+            tx_id % TX_ID_PAGE_BITS / 64,
+            1 << (tx_id % 64),
+        )
+    }
+}
+
+impl Extend<u32> for TxIdSet {
+    fn extend<T: IntoIterator<Item = u32>>(&mut self, tx_ids: T) {
+        for tx_id in tx_ids {
+            self.insert(tx_id);
+        }
+    }
+}
+
+/// Counters of what a ledger has processed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LedgerStats {
+    /// Transactions processed from each tagged source, whether or not they were applied.
+    pub processed_by_source: HashMap<String, usize>,
+
+    /// Part of the resolves and chargebacks that couldn't be taken from held, when clamped to it.
+    pub held_residual: Decimal,
+
+    /// Deposits and withdrawals rejected because the resulting balance couldn't be represented exactly.
+    pub precision_losses: usize,
+
+    /// Transactions that left the total of their client negative, when that is rejected or warned about.
+    pub negative_totals: usize,
+
+    /// Disputed amounts left unheld because holding them would have exceeded the held cap.
+    pub capped_shortfall: Decimal,
+
+    /// Clients that no transaction has changed since they were created, when they are flagged.
+    pub phantom_clients: usize,
+
+    /// Transactions skipped because applying them panicked, when panics are caught.
+    pub panics: usize,
+
+    /// Deposits and withdrawals rejected because an earlier one, of any client, had the same tx id.
+    pub duplicates: usize,
+
+    /// Withdrawals rejected because they would have exceeded the withdrawal velocity limit.
+    pub velocity_exceeded: usize,
+
+    /// Disputes that came before the deposit they refer to, still waiting for it.
+    pub pending_disputes: usize,
+
+    /// Disputes rejected because the deposit they refer to never came by the end of the input.
+    pub unmatched_disputes: usize,
+
+    /// Transactions rejected for any reason, such as an invalid amount or insufficient funds.
+    pub rejected: usize,
+
+    /// Accounts whose total differs from the one recomputed from their transactions, by client, when verifying
+    /// the ledger. Recorded at the end of the input, and whenever the stats of a processor are asked for.
+    pub divergences: Vec<TotalDivergence>,
+}
+
+impl LedgerStats {
+    /// Add the counters of another ledger, such as another worker's.
+    pub fn merge(&mut self, other: LedgerStats) {
+        for (source, count) in other.processed_by_source {
+            *self.processed_by_source.entry(source).or_default() += count;
+        }
+
+        self.held_residual += other.held_residual;
+        self.precision_losses += other.precision_losses;
+        self.negative_totals += other.negative_totals;
+        self.capped_shortfall += other.capped_shortfall;
+        self.phantom_clients += other.phantom_clients;
+        self.panics += other.panics;
+        self.duplicates += other.duplicates;
+        self.velocity_exceeded += other.velocity_exceeded;
+        self.pending_disputes += other.pending_disputes;
+        self.unmatched_disputes += other.unmatched_disputes;
+        self.rejected += other.rejected;
+        self.divergences.extend(other.divergences);
+        self.divergences
+            .sort_by_key(|divergence| (divergence.client, divergence.currency));
+    }
+}
+
+/// Why a transaction was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectionReason {
+    ClientLocked,
+
+    /// Missing, negative, above the configured ceiling, or beyond the disputed transaction's amount.
+    InvalidAmount,
+
+    /// Not enough available funds for a withdrawal or dispute, or not enough held funds for a resolve or
+    /// chargeback. The dispute then stays open.
+    InsufficientFunds,
+    PrecisionLoss,
+
+    /// The referenced transaction isn't a deposit, doesn't exist, or is of another client. Only deposits are
+    /// kept, so a withdrawal is as unknown as a transaction that never was.
+    UnknownTransaction,
+    AlreadyDisputed,
+    NotDisputed,
+
+    /// Holding the disputed amount would exceed the held cap, or the cap of the client's total overflows.
+    HeldCapExceeded,
+    HoldNotMatured,
+
+    /// The withdrawal would exceed the client's withdrawal velocity limit.
+    VelocityExceeded,
+
+    /// A deposit or withdrawal reusing the tx id of an earlier one, of any client. The first one keeps it, even
+    /// when it was rejected itself.
+    DuplicateTransaction,
+
+    /// Undone because it left the total of its client negative.
+    NegativeTotal,
+
+    /// Skipped because applying it panicked, when panics are caught, see `Ledger::try_apply`.
+    Panicked,
+}
+
+/// Where a stored transaction is in the dispute lifecycle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisputeState {
+    /// Never disputed, or disputed again after being resolved.
+    Open,
+    Disputed,
+    Resolved,
+
+    /// Final, the account was locked.
+    ChargedBack,
+}
+
+/// Account of a client whose total differs from the total recomputed from the transactions applied to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TotalDivergence {
+    pub client: u16,
+    pub currency: Option<Currency>,
+    pub recomputed: Decimal,
+    pub total: Decimal,
+}
+
+/// A rejected transaction and why it was rejected.
+#[derive(Debug, Clone)]
+pub struct RejectedTransaction {
+    pub transaction: Transaction,
+    pub reason: RejectionReason,
+}
+
+/// A stored transaction that a dispute may refer to, and whether it is currently disputed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DisputableRecord {
+    pub tx_id: u32,
+    pub transaction_type: TransactionType,
+    pub amount: Decimal,
+    pub disputed: bool,
+}
+
+/// A stored transaction of a client, as exported for offline analysis.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredTransaction {
+    pub client: u16,
+    pub record: DisputableRecord,
+}
+
+/// Administrative operation on a client, outside of the transactions, kept in the audit log.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditEntry {
+    pub client: u16,
+    pub operation: AuditOperation,
+
+    /// Who asked for the operation, and why.
+    pub reason: String,
+    pub at: SystemTime,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AuditOperation {
+    /// The client was reset from the given state, with the given open disputes closed.
+    Reset {
+        previous: Client,
+        closed_disputes: Vec<u32>,
+    },
+}
+
+/// Dispute left open by a previous run, with the amount it holds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpenDispute {
+    pub tx_id: u32,
+    pub amount: Decimal,
+}
+
+/// Client carried over from a previous run, to resume processing where it stopped.
+#[derive(Debug, Clone)]
+pub struct ClientSeed {
+    /// The client with its balances, the held balance already including the open disputes.
+    pub client: Client,
+    pub open_disputes: Vec<OpenDispute>,
+}
+
+/// Account of a client in a currency, see `Client::account`.
+type AccountId = (u16, Option<Currency>);
+
+/// State saved before applying a transaction, to reject it afterwards.
+struct Undo {
+    client_id: u16,
+    account_id: AccountId,
+    tx_id: u32,
+    client: Option<Client>,
+    transaction: Option<Transaction>,
+    dispute_amount: Option<Decimal>,
+    partial_hold: Option<(Decimal, Decimal)>,
+    disputed: bool,
+    settled: Option<DisputeState>,
+    dispute_time: Option<SystemTime>,
+    recomputed_total: Option<Decimal>,
+    recent_disputes: Option<VecDeque<SystemTime>>,
+    recent_withdrawals: Option<VecDeque<(SystemTime, Decimal)>>,
+}
+
+/// Synchronous core of the engine. Applies transactions, in order, to the clients it owns.
+///
+/// Each worker owns one ledger, but it can also be used on its own to process a batch of transactions in a
+/// single thread.
+///
+/// Only deposits are stored, by their tx id, since only they may be disputed. Deposits and withdrawals must have
+/// unique tx ids, a later one reusing the tx id of any earlier one is rejected. A dispute, resolve or chargeback
+/// row reuses the tx id of the transaction it refers to, as expected of it, so it is never a duplicate.
+#[derive(Default)]
+pub struct Ledger {
+    config: Config,
+    clients: HashMap<u16, Client>,
+
+    /// Deposits, by tx id, the only transactions that may be disputed. Withdrawals aren't kept, so the memory
+    /// grows with the deposits only.
+    transactions: HashMap<u32, Transaction>,
+
+    /// Tx ids of every deposit and withdrawal so far, applied or not, to reject the ones reused. See `TxIdSet` for
+    /// its memory cost. In a worker, only the tx ids the load balancer told it were taken, see `take_tx_id`.
+    tx_ids: TxIdSet,
+
+    /// Duplicate tx ids were already rejected before the transactions reached this ledger, so it doesn't keep
+    /// them, see `without_tx_id_check`.
+    tx_ids_checked: bool,
+
+    /// Disputes of a deposit that hasn't come yet, by the tx id they refer to, applied as soon as it does.
+    pending_disputes: HashMap<u32, Transaction>,
+
+    /// Amount of each open dispute that doesn't cover the whole referenced transaction.
+    dispute_amounts: HashMap<u32, Decimal>,
+
+    /// Held amount and shortfall of each open dispute, when disputes may be partially held.
+    partial_holds: HashMap<u32, (Decimal, Decimal)>,
+
+    /// Transactions with a dispute that was held and not yet resolved or charged back.
+    disputed: HashSet<u32>,
+
+    /// Last settlement of the transactions whose dispute was resolved or charged back, until disputed again.
+    settled: HashMap<u32, DisputeState>,
+
+    /// When each open dispute was held, when resolves wait for the hold days.
+    dispute_times: HashMap<u32, SystemTime>,
+
+    /// Flagged clients that no transaction has changed since they were created.
+    phantoms: HashSet<u16>,
+
+    /// When each account opened its disputes still within the window of the rapid disputes rule, oldest first.
+    recent_disputes: HashMap<AccountId, VecDeque<SystemTime>>,
+
+    /// When each account made its withdrawals still within the window of the velocity limit, and how much,
+    /// oldest first.
+    recent_withdrawals: HashMap<AccountId, VecDeque<(SystemTime, Decimal)>>,
+
+    /// Total of each account recomputed from the amounts its transactions moved in or out, when verifying.
+    recomputed_totals: HashMap<AccountId, Decimal>,
+
+    /// Where every rejected transaction is sent, when someone listens.
+    rejections: Option<mpsc::UnboundedSender<RejectedTransaction>>,
+
+    /// Every administrative operation, in order.
+    audit_log: Vec<AuditEntry>,
+
+    stats: LedgerStats,
+}
+
+impl Ledger {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            ..Default::default()
+        }
+    }
+
+    /// Send every rejected transaction, with the reason, to a channel. Sending never blocks the ledger.
+    pub fn with_rejections(
+        mut self,
+        rejections: mpsc::UnboundedSender<RejectedTransaction>,
+    ) -> Self {
+        self.rejections = Some(rejections);
+        self
+    }
+
+    /// Don't keep the tx ids to reject the reused ones, for a ledger only given transactions already checked for
+    /// them, such as a worker's, whose load balancer sees the transactions of every client.
+    pub(crate) fn without_tx_id_check(mut self) -> Self {
+        self.tx_ids_checked = true;
+        self
+    }
+
+    /// Record that a deposit or withdrawal, of any client, already took a tx id, so a dispute of it is rejected
+    /// right away instead of waiting for a deposit that can't come. Told by the load balancer to a worker's
+    /// ledger, which doesn't keep the tx ids itself, see `without_tx_id_check`.
+    pub(crate) fn take_tx_id(&mut self, tx_id: u32) {
+        self.tx_ids.insert(tx_id);
+    }
+
+    /// Process a batch of transactions in a single thread and return the resulting clients.
+    pub fn process(
+        config: Config,
+        transactions: impl IntoIterator<Item = Transaction>,
+    ) -> HashMap<u16, Client> {
+        let mut ledger = Self::new(config);
+        for transaction in transactions {
+            ledger.apply(transaction);
+        }
+        ledger.finish();
+
+        ledger.into_clients()
+    }
+
+    pub fn apply(&mut self, transaction: Transaction) {
+        let transaction = if self.config.normalize_signs {
+            transaction.with_normalized_sign()
+        } else {
+            transaction
+        };
+        let transaction = if self.config.currencies {
+            transaction
+        } else {
+            transaction.with_currency(None)
+        };
+
+        if let Some(source) = transaction.get_source() {
+            *self
+                .stats
+                .processed_by_source
+                .entry(source.into())
+                .or_default() += 1;
+        }
+
+        self.apply_counted(transaction);
+    }
+
+    /// Apply a transaction already counted by its source, such as a dispute that waited for its deposit.
+    fn apply_counted(&mut self, transaction: Transaction) {
+        let client_id = transaction.get_client_id();
+        let currency = transaction.get_currency();
+        let tx_id = transaction.get_tx_id();
+        let is_deposit = transaction.get_type() == TransactionType::Deposit;
+        let undo = (self.config.negative_total_policy == NegativeTotalPolicy::RejectTransaction)
+            .then(|| self.undo(&transaction));
+        let is_new_client = !self.clients.contains_key(&client_id);
+        let rejected = self.rejections.is_some().then(|| transaction.clone());
+
+        let mut result = self.apply_to_client(transaction);
+        self.track_phantom(client_id, is_new_client);
+
+        if self
+            .clients
+            .get(&client_id)
+            .and_then(|client| client.account(currency))
+            .is_some_and(|account| account.get_total() < Decimal::ZERO)
+        {
+            match self.config.negative_total_policy {
+                NegativeTotalPolicy::Allow => {}
+                NegativeTotalPolicy::RejectTransaction => {
+                    self.stats.negative_totals += 1;
+                    if let Some(undo) = undo {
+                        self.restore(undo);
+                    }
+                    result = Err(RejectionReason::NegativeTotal);
+                }
+                NegativeTotalPolicy::Warn => self.stats.negative_totals += 1,
+            }
+        }
+
+        if let Err(reason) = result {
+            self.reject(rejected, reason);
+        }
+
+        // A dispute that came before the deposit it refers to is applied right after it.
+        if is_deposit && result.is_ok() {
+            if let Some(dispute) = self.pending_disputes.remove(&tx_id) {
+                self.apply_counted(dispute);
+            }
+        }
+        self.stats.pending_disputes = self.pending_disputes.len();
+    }
+
+    /// Count a rejected transaction, and send it with the reason when someone listens.
+    fn reject(&mut self, transaction: Option<Transaction>, reason: RejectionReason) {
+        self.stats.rejected += 1;
+
+        if let (Some(rejections), Some(transaction)) = (&self.rejections, transaction) {
+            // Nobody listening anymore is fine.
+            rejections
+                .send(RejectedTransaction {
+                    transaction,
+                    reason,
+                })
+                .unwrap_or_default();
+        }
+    }
+
+    /// End of the input: reject the disputes still waiting for the deposit they refer to, by tx id, as disputes
+    /// of an unknown transaction, and record the divergences of the totals when verifying.
+    pub fn finish(&mut self) {
+        let mut unmatched = self.pending_disputes.drain().collect::<Vec<_>>();
+        unmatched.sort_by_key(|(tx_id, _)| *tx_id);
+
+        self.stats.pending_disputes = 0;
+        self.stats.unmatched_disputes += unmatched.len();
+        for (_, dispute) in unmatched {
+            let rejected = self.rejections.is_some().then_some(dispute);
+            self.reject(rejected, RejectionReason::UnknownTransaction);
+        }
+
+        self.record_divergences();
+    }
+
+    /// Record the accounts whose total diverges in the stats, when verifying, see `verify_totals`.
+    pub(crate) fn record_divergences(&mut self) {
+        if self.config.verify_ledger {
+            self.stats.divergences = self.verify_totals();
+        }
+    }
+
+    /// Apply a transaction like `apply`, but turn a panic while applying it into an error. Everything the
+    /// transaction may have changed is put back as it was, so the ledger can go on with the next transactions.
+    /// The transaction is also rejected as `Panicked`.
+    pub fn try_apply(&mut self, transaction: Transaction) -> Result<()> {
+        let client_id = transaction.get_client_id();
+        let tx_id = transaction.get_tx_id();
+        let undo = self.undo(&transaction);
+        let rejected = self.rejections.is_some().then(|| transaction.clone());
+
+        let Err(panic) = panic::catch_unwind(AssertUnwindSafe(|| self.apply(transaction))) else {
+            return Ok(());
+        };
+
+        self.restore(undo);
+        self.stats.panics += 1;
+        self.reject(rejected, RejectionReason::Panicked);
+
+        let message = panic
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        bail!("Applying transaction {tx_id} of client {client_id} panicked, it was skipped: {message}");
+    }
+
+    /// Flag or suppress the client of a transaction that didn't change it, if the transaction created it. A
+    /// flagged client stops being one as soon as a transaction changes it.
+    fn track_phantom(&mut self, client_id: u16, is_new_client: bool) {
+        if self.config.phantom_client_policy == PhantomClientPolicy::Keep {
+            return;
+        }
+
+        let unchanged = self
+            .clients
+            .get(&client_id)
+            .is_some_and(|client| *client == Client::new(client_id));
+
+        if !unchanged {
+            self.phantoms.remove(&client_id);
+        } else if is_new_client {
+            match self.config.phantom_client_policy {
+                PhantomClientPolicy::Keep => {}
+                PhantomClientPolicy::Flag => {
+                    self.phantoms.insert(client_id);
+                }
+                PhantomClientPolicy::Suppress => {
+                    self.clients.remove(&client_id);
+                }
+            }
+        }
+
+        self.stats.phantom_clients = self.phantoms.len();
+    }
+
+    /// Account a transaction is applied to: its client's, in its currency when currencies are kept apart.
+    fn account_id(&self, transaction: &Transaction) -> AccountId {
+        let currency = transaction
+            .get_currency()
+            .filter(|_| self.config.currencies);
+
+        (transaction.get_client_id(), currency)
+    }
+
+    /// Everything that applying a transaction may change: the state of its client and account, and of the
+    /// transaction id it refers to.
+    fn undo(&self, transaction: &Transaction) -> Undo {
+        let client_id = transaction.get_client_id();
+        let account_id = self.account_id(transaction);
+        let tx_id = transaction.get_ref_tx_id();
+
+        Undo {
+            client_id,
+            account_id,
+            tx_id,
+            client: self.clients.get(&client_id).cloned(),
+            transaction: self.transactions.get(&tx_id).cloned(),
+            dispute_amount: self.dispute_amounts.get(&tx_id).copied(),
+            partial_hold: self.partial_holds.get(&tx_id).copied(),
+            disputed: self.disputed.contains(&tx_id),
+            settled: self.settled.get(&tx_id).copied(),
+            dispute_time: self.dispute_times.get(&tx_id).copied(),
+            recomputed_total: self.recomputed_totals.get(&account_id).copied(),
+            recent_disputes: self.recent_disputes.get(&account_id).cloned(),
+            recent_withdrawals: self.recent_withdrawals.get(&account_id).cloned(),
+        }
+    }
+
+    /// Put back the state saved before a transaction, as if it was never applied.
+    fn restore(&mut self, undo: Undo) {
+        fn restore_entry<K: Eq + std::hash::Hash, V>(
+            map: &mut HashMap<K, V>,
+            key: K,
+            value: Option<V>,
+        ) {
+            match value {
+                Some(value) => map.insert(key, value),
+                None => map.remove(&key),
+            };
+        }
+
+        restore_entry(&mut self.clients, undo.client_id, undo.client);
+        restore_entry(&mut self.transactions, undo.tx_id, undo.transaction);
+        restore_entry(&mut self.dispute_amounts, undo.tx_id, undo.dispute_amount);
+        restore_entry(&mut self.partial_holds, undo.tx_id, undo.partial_hold);
+        restore_entry(&mut self.dispute_times, undo.tx_id, undo.dispute_time);
+        restore_entry(&mut self.settled, undo.tx_id, undo.settled);
+        restore_entry(
+            &mut self.recomputed_totals,
+            undo.account_id,
+            undo.recomputed_total,
+        );
+        restore_entry(
+            &mut self.recent_disputes,
+            undo.account_id,
+            undo.recent_disputes,
+        );
+        restore_entry(
+            &mut self.recent_withdrawals,
+            undo.account_id,
+            undo.recent_withdrawals,
+        );
+        if undo.disputed {
+            self.disputed.insert(undo.tx_id);
+        } else {
+            self.disputed.remove(&undo.tx_id);
+        }
+    }
+
+    fn apply_to_client(&mut self, mut transaction: Transaction) -> Result<(), RejectionReason> {
+        let is_deposit_or_withdrawal = matches!(
+            transaction.get_type(),
+            TransactionType::Deposit | TransactionType::Withdrawal
+        );
+
+        if is_deposit_or_withdrawal
+            && !self.tx_ids_checked
+            && !self.tx_ids.insert(transaction.get_tx_id())
+        {
+            self.stats.duplicates += 1;
+            return Err(RejectionReason::DuplicateTransaction);
+        }
+
+        if is_deposit_or_withdrawal && transaction.get_amount().is_none() {
+            match self.config.missing_amount_policy {
+                // Rejected as an invalid amount below.
+                MissingAmountPolicy::Reject => {}
+                MissingAmountPolicy::TreatAsZero => {
+                    transaction = transaction.with_amount(Decimal::ZERO)
+                }
+                MissingAmountPolicy::Skip => return Ok(()),
+            }
+        }
+
+        let account_id = self.account_id(&transaction);
+        let client = self
+            .clients
+            .entry(transaction.get_client_id())
+            .or_insert_with(|| Client::new(transaction.get_client_id()))
+            .account_mut(account_id.1);
+
+        let is_chargeback_applied_after_lock = self.config.post_lock_chargeback_policy
+            == PostLockChargebackPolicy::Apply
+            && transaction.get_type() == TransactionType::Chargeback;
+        let transaction_type = transaction.get_type();
+
+        // Unlocking is the one transaction that goes through while locked.
+        if client.is_locked()
+            && !is_chargeback_applied_after_lock
+            && transaction_type != TransactionType::Unlock
+        {
+            return Err(RejectionReason::ClientLocked);
+        }
+
+        match transaction_type {
+            TransactionType::Deposit => {
+                let amount = transaction
+                    .get_amount()
+                    .filter(|amount| self.config.allows_amount(transaction_type, *amount))
+                    .ok_or(RejectionReason::InvalidAmount)?;
+
+                match client.add_available(amount) {
+                    Ok(()) => {
+                        recompute_total(
+                            &self.config,
+                            &mut self.recomputed_totals,
+                            account_id,
+                            amount,
+                        );
+                        self.transactions
+                            .insert(transaction.get_tx_id(), transaction);
+                    }
+                    Err(ClientError::PrecisionLoss) => {
+                        self.stats.precision_losses += 1;
+                        return Err(RejectionReason::PrecisionLoss);
+                    }
+                    Err(
+                        ClientError::NegativeAmount
+                        | ClientError::ExcessPrecision
+                        | ClientError::InsufficientFunds
+                        | ClientError::Overflow,
+                    ) => return Err(RejectionReason::InvalidAmount),
+                }
+            }
+
+            TransactionType::Withdrawal => {
+                let amount = transaction
+                    .get_amount()
+                    .filter(|amount| self.config.allows_amount(transaction_type, *amount))
+                    .filter(|amount| !amount.is_sign_negative())
+                    .ok_or(RejectionReason::InvalidAmount)?;
+
+                if let Some(limit) = self.config.withdrawal_velocity {
+                    let now = self.config.now();
+                    let recent = self.recent_withdrawals.entry(account_id).or_default();
+                    while recent.front().is_some_and(|(withdrawn_at, _)| {
+                        now.duration_since(*withdrawn_at).unwrap_or_default() > limit.window
+                    }) {
+                        recent.pop_front();
+                    }
+
+                    let withdrawn = recent
+                        .iter()
+                        .try_fold(amount, |total, (_, amount)| total.checked_add(*amount));
+                    if withdrawn.is_none_or(|withdrawn| withdrawn > limit.amount) {
+                        self.stats.velocity_exceeded += 1;
+                        return Err(RejectionReason::VelocityExceeded);
+                    }
+                }
+
+                match client.subtract_available(amount) {
+                    Ok(()) => {
+                        recompute_total(
+                            &self.config,
+                            &mut self.recomputed_totals,
+                            account_id,
+                            -amount,
+                        );
+                        if self.config.withdrawal_velocity.is_some() {
+                            self.recent_withdrawals
+                                .entry(account_id)
+                                .or_default()
+                                .push_back((self.config.now(), amount));
+                        }
+                    }
+                    Err(ClientError::PrecisionLoss) => {
+                        self.stats.precision_losses += 1;
+                        return Err(RejectionReason::PrecisionLoss);
+                    }
+                    Err(
+                        ClientError::NegativeAmount
+                        | ClientError::ExcessPrecision
+                        | ClientError::Overflow,
+                    ) => return Err(RejectionReason::InvalidAmount),
+                    Err(ClientError::InsufficientFunds) => {
+                        return Err(RejectionReason::InsufficientFunds)
+                    }
+                }
+            }
+
+            TransactionType::Dispute => {
+                // The deposit may still come later in the input, unless its tx id was already taken.
+                let ref_tx_id = transaction.get_ref_tx_id();
+                if !self.transactions.contains_key(&ref_tx_id)
+                    && !self.tx_ids.contains(ref_tx_id)
+                    && !self.pending_disputes.contains_key(&ref_tx_id)
+                    && self.pending_disputes.len() < PENDING_DISPUTES_CAPACITY
+                {
+                    self.pending_disputes.insert(ref_tx_id, transaction);
+                    return Ok(());
+                }
+
+                let ref_transaction = self
+                    .transactions
+                    .get(&transaction.get_ref_tx_id())
+                    .filter(|ref_transaction| {
+                        (
+                            ref_transaction.get_client_id(),
+                            ref_transaction.get_currency(),
+                        ) == account_id
+                    })
+                    .ok_or(RejectionReason::UnknownTransaction)?;
+                let original = ref_transaction
+                    .get_amount()
+                    .ok_or(RejectionReason::UnknownTransaction)?;
+
+                // Holding the funds of a transaction that is already disputed would hold them twice.
+                if self.disputed.contains(&transaction.get_ref_tx_id()) {
+                    return Err(RejectionReason::AlreadyDisputed);
+                }
+
+                // A dispute may name the part of the original amount that is disputed, otherwise the whole of it
+                // is.
+                let amount = transaction.get_amount().unwrap_or(original);
+                if amount.is_sign_negative() || amount > original {
+                    return Err(RejectionReason::InvalidAmount);
+                }
+
+                // Only as much as the cap leaves room for can be held. A cap that can't be computed can't be met.
+                let room = self
+                    .config
+                    .held_room(client)
+                    .map_err(|_| RejectionReason::HeldCapExceeded)?
+                    .unwrap_or(amount);
+                let capped_shortfall = (amount - room).max(Decimal::ZERO);
+                if !capped_shortfall.is_zero()
+                    && self.config.held_cap_policy == HeldCapPolicy::Reject
+                {
+                    return Err(RejectionReason::HeldCapExceeded);
+                }
+                let amount = amount - capped_shortfall;
+
+                match self.config.dispute_policy {
+                    DisputePolicy::RequireAvailable => {
+                        client
+                            .transfer_available_to_held(amount)
+                            .map_err(|_| RejectionReason::InsufficientFunds)?;
+
+                        if amount != original {
+                            self.dispute_amounts
+                                .insert(transaction.get_ref_tx_id(), amount);
+                        }
+                    }
+
+                    DisputePolicy::AvailableFirst => {
+                        let Entry::Vacant(entry) =
+                            self.partial_holds.entry(transaction.get_ref_tx_id())
+                        else {
+                            return Err(RejectionReason::AlreadyDisputed);
+                        };
+
+                        let held = amount.min(client.get_available().max(Decimal::ZERO));
+                        let shortfall = amount - held;
+
+                        client
+                            .transfer_available_to_held(held)
+                            .map_err(|_| RejectionReason::InsufficientFunds)?;
+                        client.add_shortfall(shortfall).unwrap_or_default();
+                        entry.insert((held, shortfall));
+                    }
+                }
+
+                self.stats.capped_shortfall += capped_shortfall;
+                self.disputed.insert(transaction.get_ref_tx_id());
+                self.settled.remove(&transaction.get_ref_tx_id());
+                if self.config.hold_days.is_some() {
+                    self.dispute_times
+                        .insert(transaction.get_ref_tx_id(), self.config.now());
+                }
+
+                if let Some(rule) = self.config.rapid_disputes {
+                    let now = self.config.now();
+                    let recent = self.recent_disputes.entry(account_id).or_default();
+                    recent.push_back(now);
+                    while recent.front().is_some_and(|opened| {
+                        now.duration_since(*opened).unwrap_or_default() > rule.window
+                    }) {
+                        recent.pop_front();
+                    }
+
+                    if recent.len() >= rule.count {
+                        client.flag();
+                    }
+                }
+            }
+
+            TransactionType::Resolve => {
+                let original = self
+                    .transactions
+                    .get(&transaction.get_ref_tx_id())
+                    .filter(|ref_transaction| {
+                        (
+                            ref_transaction.get_client_id(),
+                            ref_transaction.get_currency(),
+                        ) == account_id
+                    })
+                    .and_then(|ref_transaction| *ref_transaction.get_amount())
+                    .ok_or(RejectionReason::UnknownTransaction)?;
+
+                if !self.disputed.contains(&transaction.get_ref_tx_id()) {
+                    return Err(RejectionReason::NotDisputed);
+                }
+
+                // The held funds must mature before they are released.
+                if let Some(disputed_at) = self.dispute_times.get(&transaction.get_ref_tx_id()) {
+                    if !self.config.hold_matured(*disputed_at) {
+                        return Err(RejectionReason::HoldNotMatured);
+                    }
+                }
+
+                // The dispute is only closed once its funds are released, otherwise it stays open to be settled
+                // later, instead of leaving them held for good.
+                match self.config.dispute_policy {
+                    DisputePolicy::RequireAvailable => {
+                        let amount = self
+                            .dispute_amounts
+                            .get(&transaction.get_ref_tx_id())
+                            .copied()
+                            .unwrap_or(original);
+                        let amount = clamp_to_held(&self.config, &mut self.stats, client, amount);
+
+                        client
+                            .transfer_held_to_available(amount)
+                            .map_err(|_| RejectionReason::InsufficientFunds)?;
+                        self.dispute_amounts.remove(&transaction.get_ref_tx_id());
+                    }
+
+                    DisputePolicy::AvailableFirst => {
+                        let (held, shortfall) = self
+                            .partial_holds
+                            .get(&transaction.get_ref_tx_id())
+                            .copied()
+                            .ok_or(RejectionReason::NotDisputed)?;
+                        let held = clamp_to_held(&self.config, &mut self.stats, client, held);
+
+                        client
+                            .transfer_held_to_available(held)
+                            .map_err(|_| RejectionReason::InsufficientFunds)?;
+                        client.subtract_shortfall(shortfall).unwrap_or_default();
+                        self.partial_holds.remove(&transaction.get_ref_tx_id());
+                    }
+                }
+
+                self.disputed.remove(&transaction.get_ref_tx_id());
+                self.dispute_times.remove(&transaction.get_ref_tx_id());
+                self.settled
+                    .insert(transaction.get_ref_tx_id(), DisputeState::Resolved);
+            }
+
+            TransactionType::Chargeback => {
+                let original = self
+                    .transactions
+                    .get(&transaction.get_ref_tx_id())
+                    .filter(|ref_transaction| {
+                        (
+                            ref_transaction.get_client_id(),
+                            ref_transaction.get_currency(),
+                        ) == account_id
+                    })
+                    .and_then(|ref_transaction| *ref_transaction.get_amount())
+                    .ok_or(RejectionReason::UnknownTransaction)?;
+
+                if !self.disputed.contains(&transaction.get_ref_tx_id()) {
+                    return Err(RejectionReason::NotDisputed);
+                }
+
+                // Like a resolve, the dispute is only closed once its funds are taken.
+                match self.config.dispute_policy {
+                    DisputePolicy::RequireAvailable => {
+                        let amount = self
+                            .dispute_amounts
+                            .get(&transaction.get_ref_tx_id())
+                            .copied()
+                            .unwrap_or(original);
+                        let amount = clamp_to_held(&self.config, &mut self.stats, client, amount);
+
+                        client
+                            .subtract_held(amount)
+                            .map_err(|_| RejectionReason::InsufficientFunds)?;
+                        recompute_total(
+                            &self.config,
+                            &mut self.recomputed_totals,
+                            account_id,
+                            -amount,
+                        );
+                        self.dispute_amounts.remove(&transaction.get_ref_tx_id());
+                        client.lock_account(LockTrigger {
+                            tx_id: transaction.get_ref_tx_id(),
+                            amount,
+                        });
+                    }
+
+                    DisputePolicy::AvailableFirst => {
+                        let (held, shortfall) = self
+                            .partial_holds
+                            .get(&transaction.get_ref_tx_id())
+                            .copied()
+                            .ok_or(RejectionReason::NotDisputed)?;
+                        let held = clamp_to_held(&self.config, &mut self.stats, client, held);
+
+                        client
+                            .subtract_held(held)
+                            .map_err(|_| RejectionReason::InsufficientFunds)?;
+                        recompute_total(
+                            &self.config,
+                            &mut self.recomputed_totals,
+                            account_id,
+                            -held,
+                        );
+                        self.partial_holds.remove(&transaction.get_ref_tx_id());
+                        client.lock_account(LockTrigger {
+                            tx_id: transaction.get_ref_tx_id(),
+                            amount: held + shortfall,
+                        });
+                    }
+                }
+
+                self.disputed.remove(&transaction.get_ref_tx_id());
+                self.dispute_times.remove(&transaction.get_ref_tx_id());
+                self.settled
+                    .insert(transaction.get_ref_tx_id(), DisputeState::ChargedBack);
+            }
+
+            TransactionType::Unlock => client.unlock_account(),
+        }
+
+        Ok(())
+    }
+
+    pub fn clients(&self) -> &HashMap<u16, Client> {
+        &self.clients
+    }
+
+    /// Resume a client from a previous run. Each open dispute is recorded as a disputed deposit of the amount it
+    /// holds, so a resolve or chargeback that follows settles it as if the dispute had been applied here. The
+    /// balances aren't changed, the held amounts are already in them.
+    pub fn seed(&mut self, seed: ClientSeed) {
+        let client_id = seed.client.get_id();
+
+        for dispute in seed.open_disputes {
+            if !self.tx_ids_checked {
+                self.tx_ids.insert(dispute.tx_id);
+            }
+            self.transactions.insert(
+                dispute.tx_id,
+                Transaction::new(
+                    TransactionType::Deposit,
+                    client_id,
+                    dispute.tx_id,
+                    Some(dispute.amount),
+                ),
+            );
+            self.disputed.insert(dispute.tx_id);
+
+            if self.config.dispute_policy == DisputePolicy::AvailableFirst {
+                self.partial_holds
+                    .insert(dispute.tx_id, (dispute.amount, Decimal::ZERO));
+            }
+        }
+
+        if self.config.verify_ledger {
+            for account in iter::once(&seed.client).chain(seed.client.currency_accounts()) {
+                self.recomputed_totals
+                    .insert((client_id, account.get_currency()), account.get_total());
+            }
+        }
+        self.clients.insert(client_id, seed.client);
+    }
+
+    /// The stored deposits of a client, by transaction id. Empty for an unknown client.
+    pub fn query_transactions(&self, client_id: u16) -> Vec<DisputableRecord> {
+        let mut records = self
+            .transactions
+            .values()
+            .filter(|transaction| transaction.get_client_id() == client_id)
+            .filter_map(|transaction| self.disputable_record(transaction))
+            .collect::<Vec<_>>();
+
+        records.sort_by_key(|record| record.tx_id);
+
+        records
+    }
+
+    /// Where a stored transaction of a client is in the dispute lifecycle, or `None` if the client has no such
+    /// transaction.
+    pub fn dispute_state(&self, client_id: u16, tx_id: u32) -> Option<DisputeState> {
+        self.transactions
+            .get(&tx_id)
+            .filter(|transaction| transaction.get_client_id() == client_id)?;
+
+        Some(if self.disputed.contains(&tx_id) {
+            DisputeState::Disputed
+        } else {
+            self.settled
+                .get(&tx_id)
+                .copied()
+                .unwrap_or(DisputeState::Open)
+        })
+    }
+
+    /// Every stored deposit, by client id and then transaction id.
+    pub fn stored_transactions(&self) -> Vec<StoredTransaction> {
+        let mut stored = self
+            .transactions
+            .values()
+            .filter_map(|transaction| {
+                Some(StoredTransaction {
+                    client: transaction.get_client_id(),
+                    record: self.disputable_record(transaction)?,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        stored.sort_by_key(|stored| (stored.client, stored.record.tx_id));
+
+        stored
+    }
+
+    fn disputable_record(&self, transaction: &Transaction) -> Option<DisputableRecord> {
+        Some(DisputableRecord {
+            tx_id: transaction.get_tx_id(),
+            transaction_type: transaction.get_type(),
+            amount: (*transaction.get_amount())?,
+            disputed: self.disputed.contains(&transaction.get_tx_id()),
+        })
+    }
+
+    /// Reset a client as new, zeroing its balances and unlocking it, for administrative recovery. Its open
+    /// disputes are closed, so they can't release or charge back funds it no longer holds, but its transactions
+    /// are kept and may be disputed again. The reset is recorded in the audit log with the state before it.
+    ///
+    /// Fails unless the config allows administrative resets, or if the client doesn't exist.
+    pub fn reset_client(&mut self, client_id: u16, reason: impl Into<String>) -> Result<()> {
+        if !self.config.allow_admin_reset {
+            bail!("Resetting clients isn't allowed.");
+        }
+
+        let Some(client) = self.clients.get_mut(&client_id) else {
+            bail!("Client {client_id} doesn't exist.");
+        };
+        let previous = client.clone();
+        client.reset();
+
+        let mut closed_disputes = self
+            .transactions
+            .values()
+            .filter(|transaction| transaction.get_client_id() == client_id)
+            .map(Transaction::get_tx_id)
+            .filter(|tx_id| self.disputed.contains(tx_id) || self.partial_holds.contains_key(tx_id))
+            .collect::<Vec<_>>();
+        closed_disputes.sort();
+        for tx_id in &closed_disputes {
+            self.disputed.remove(tx_id);
+            self.dispute_amounts.remove(tx_id);
+            self.partial_holds.remove(tx_id);
+            self.dispute_times.remove(tx_id);
+        }
+
+        if self.config.verify_ledger {
+            self.recomputed_totals.retain(|(id, _), _| *id != client_id);
+        }
+
+        self.audit_log.push(AuditEntry {
+            client: client_id,
+            operation: AuditOperation::Reset {
+                previous,
+                closed_disputes,
+            },
+            reason: reason.into(),
+            at: self.config.now(),
+        });
+
+        Ok(())
+    }
+
+    /// Every administrative operation so far, in order.
+    pub fn audit_log(&self) -> &[AuditEntry] {
+        &self.audit_log
+    }
+
+    /// The clients whose total differs from the one recomputed from their transactions, by client id. Only
+    /// meaningful when verifying the ledger.
+    pub fn verify_totals(&self) -> Vec<TotalDivergence> {
+        let mut divergences = self
+            .clients
+            .values()
+            .flat_map(|client| iter::once(client).chain(client.currency_accounts()))
+            .map(|account| TotalDivergence {
+                client: account.get_id(),
+                currency: account.get_currency(),
+                recomputed: self
+                    .recomputed_totals
+                    .get(&(account.get_id(), account.get_currency()))
+                    .copied()
+                    .unwrap_or_default(),
+                total: account.get_total(),
+            })
+            .filter(|divergence| divergence.recomputed != divergence.total)
+            .collect::<Vec<_>>();
+
+        divergences.sort_by_key(|divergence| (divergence.client, divergence.currency));
+
+        divergences
+    }
+
+    pub fn stats(&self) -> &LedgerStats {
+        &self.stats
+    }
+
+    pub fn into_clients(self) -> HashMap<u16, Client> {
+        self.clients
+    }
+}
+
+/// Amount a resolve or chargeback can take from held. When clamping is enabled and held is short of the amount,
+/// all of held is taken and the difference is recorded as residual, otherwise the amount is taken as is.
+fn clamp_to_held(
+    config: &Config,
+    stats: &mut LedgerStats,
+    client: &Client,
+    amount: Decimal,
+) -> Decimal {
+    if !config.clamp_to_held || amount <= client.get_held() {
+        return amount;
+    }
+
+    let held = client.get_held().max(Decimal::ZERO);
+    stats.held_residual += amount - held;
+
+    held
+}
+
+/// Add what a transaction moved in (or out, when negative) of a client to its recomputed total, when verifying.
+fn recompute_total(
+    config: &Config,
+    recomputed_totals: &mut HashMap<AccountId, Decimal>,
+    account_id: AccountId,
+    delta: Decimal,
+) {
+    if config.verify_ledger {
+        *recomputed_totals.entry(account_id).or_default() += delta;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clock::Clock,
+        config::{RapidDisputeRule, VelocityLimit},
+    };
+    use std::{sync::Arc, time::Duration};
+
+    /// Overlapping disputes on a thin available balance: 15 deposited, 8 withdrawn, then both deposits disputed.
+    fn overlapping_disputes(dispute_policy: DisputePolicy) -> Client {
+        let mut ledger = Ledger::new(Config {
+            dispute_policy,
+            ..Default::default()
+        });
+
+        for transaction in [
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(Decimal::new(10, 0))),
+            Transaction::new(TransactionType::Deposit, 1, 2, Some(Decimal::new(5, 0))),
+            Transaction::new(TransactionType::Withdrawal, 1, 3, Some(Decimal::new(8, 0))),
+            Transaction::new(TransactionType::Dispute, 1, 1, None),
+            Transaction::new(TransactionType::Dispute, 1, 2, None),
+        ] {
+            ledger.apply(transaction);
+        }
+
+        ledger.into_clients().remove(&1).unwrap()
+    }
+
+    /// Test that, by default, a dispute that isn't fully available is ignored.
+    #[test]
+    fn test_require_available_dispute() {
+        let client = overlapping_disputes(DisputePolicy::RequireAvailable);
+
+        assert_eq!(client.get_available(), Decimal::new(2, 0)); // Only the second dispute was held.
+        assert_eq!(client.get_held(), Decimal::new(5, 0));
+        assert_eq!(client.get_shortfall(), Decimal::ZERO);
+    }
+
+    /// Test that disputes are held from available first and the rest is flagged as shortfall.
+    #[test]
+    fn test_available_first_dispute() {
+        let client = overlapping_disputes(DisputePolicy::AvailableFirst);
+
+        assert_eq!(client.get_available(), Decimal::ZERO);
+        assert_eq!(client.get_held(), Decimal::new(7, 0)); // All the available funds went to the first dispute.
+        assert_eq!(client.get_shortfall(), Decimal::new(8, 0)); // 3 from the first and 5 from the second.
+    }
+
+    /// Test that a resolve releases only what was held and clears the dispute's shortfall.
+    #[test]
+    fn test_available_first_resolve() {
+        let mut ledger = Ledger::new(Config {
+            dispute_policy: DisputePolicy::AvailableFirst,
+            ..Default::default()
+        });
+
+        for transaction in [
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(Decimal::new(10, 0))),
+            Transaction::new(TransactionType::Withdrawal, 1, 2, Some(Decimal::new(4, 0))),
+            Transaction::new(TransactionType::Dispute, 1, 1, None),
+            Transaction::new(TransactionType::Resolve, 1, 1, None),
+        ] {
+            ledger.apply(transaction);
+        }
+
+        let client = ledger.into_clients().remove(&1).unwrap();
+
+        assert_eq!(client.get_available(), Decimal::new(6, 0));
+        assert_eq!(client.get_held(), Decimal::ZERO);
+        assert_eq!(client.get_shortfall(), Decimal::ZERO);
+    }
+
+    /// Test that a dispute row with an amount holds only that part of the deposit.
+    #[test]
+    fn test_partial_dispute() {
+        let mut ledger = Ledger::default();
+        ledger.apply(Transaction::new(
+            TransactionType::Deposit,
+            1,
+            1,
+            Some(Decimal::new(10, 0)),
+        ));
+        ledger.apply(Transaction::new(
+            TransactionType::Dispute,
+            1,
+            1,
+            Some(Decimal::new(5, 0)),
+        ));
+
+        let client = ledger.clients().get(&1).unwrap();
+        assert_eq!(client.get_available(), Decimal::new(5, 0));
+        assert_eq!(client.get_held(), Decimal::new(5, 0));
+
+        // Resolving releases only the disputed half.
+        ledger.apply(Transaction::new(TransactionType::Resolve, 1, 1, None));
+
+        let client = ledger.clients().get(&1).unwrap();
+        assert_eq!(client.get_available(), Decimal::new(10, 0));
+        assert_eq!(client.get_held(), Decimal::ZERO);
+    }
+
+    /// Test that a dispute can't hold more than the original amount.
+    #[test]
+    fn test_excessive_dispute_amount() {
+        let client = Ledger::process(
+            Default::default(),
+            [
+                Transaction::new(TransactionType::Deposit, 1, 1, Some(Decimal::new(10, 0))),
+                Transaction::new(TransactionType::Deposit, 1, 2, Some(Decimal::new(10, 0))),
+                Transaction::new(TransactionType::Dispute, 1, 1, Some(Decimal::new(11, 0))),
+            ],
+        )
+        .remove(&1)
+        .unwrap();
+
+        assert_eq!(client.get_available(), Decimal::new(20, 0));
+        assert_eq!(client.get_held(), Decimal::ZERO);
+    }
+
+    /// Test that deposits above the ceiling are rejected, so the balance can't be pushed to `Decimal::MAX`.
+    #[test]
+    fn test_max_amount() {
+        let max_amount = Decimal::MAX / Decimal::from(1000);
+        let config = Config {
+            max_amount: Some(max_amount),
+            ..Default::default()
+        };
+
+        let clients = Ledger::process(
+            config,
+            [
+                Transaction::new(
+                    TransactionType::Deposit,
+                    1,
+                    1,
+                    Some(max_amount + Decimal::ONE),
+                ),
+                Transaction::new(TransactionType::Deposit, 1, 2, Some(Decimal::MAX)),
+                Transaction::new(
+                    TransactionType::Deposit,
+                    2,
+                    3,
+                    Some(max_amount - Decimal::ONE),
+                ),
+            ],
+        );
+
+        assert_eq!(clients.get(&1).unwrap().get_total(), Decimal::ZERO);
+        assert_eq!(
+            clients.get(&2).unwrap().get_total(),
+            max_amount - Decimal::ONE
+        );
+    }
+
+    /// Test that a resolve of slightly more than is held, after the held amount was rounded, releases all of held
+    /// and records the difference.
+    #[test]
+    fn test_clamp_to_held() {
+        for (clamp_to_held, held, held_residual) in [
+            (false, Decimal::new(100000, 4), Decimal::ZERO),
+            (true, Decimal::ZERO, Decimal::new(4, 4)),
+        ] {
+            let mut ledger = Ledger::new(Config {
+                clamp_to_held,
+                ..Default::default()
+            });
+
+            ledger.apply(Transaction::new(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Decimal::new(100004, 4)),
+            ));
+            ledger.apply(Transaction::new(TransactionType::Dispute, 1, 1, None));
+
+            // Held rounded down to three decimal places, 0.0004 short of the deposit.
+            ledger
+                .clients
+                .get_mut(&1)
+                .unwrap()
+                .subtract_held(Decimal::new(4, 4))
+                .unwrap();
+
+            ledger.apply(Transaction::new(TransactionType::Resolve, 1, 1, None));
+
+            assert_eq!(ledger.clients().get(&1).unwrap().get_held(), held);
+            assert_eq!(ledger.stats().held_residual, held_residual);
+        }
+    }
+
+    /// Test that deposits whose sum no longer fits in a `Decimal` are rejected and counted, instead of rounding
+    /// the balance.
+    #[test]
+    fn test_precision_loss() {
+        // 25 integer digits and 4 decimal places, the sum of eight of them needs 30 significant digits.
+        let amount = "1000000000000000000000000.0001".parse::<Decimal>().unwrap();
+
+        let mut ledger = Ledger::default();
+        for tx in 0..10 {
+            ledger.apply(Transaction::new(
+                TransactionType::Deposit,
+                1,
+                tx,
+                Some(amount),
+            ));
+        }
+
+        assert_eq!(
+            ledger.clients().get(&1).unwrap().get_available(),
+            amount * Decimal::from(7)
+        );
+        assert_eq!(ledger.stats().precision_losses, 3);
+    }
+
+    /// Ledger with a cap of half the total held, after deposits of 2 and 8 and a dispute of the 8.
+    fn capped_dispute(held_cap_policy: HeldCapPolicy) -> Ledger {
+        let mut ledger = Ledger::new(Config {
+            held_cap: Some(Decimal::new(5, 1)),
+            held_cap_policy,
+            ..Default::default()
+        });
+
+        for transaction in [
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(Decimal::new(2, 0))),
+            Transaction::new(TransactionType::Deposit, 1, 2, Some(Decimal::new(8, 0))),
+            Transaction::new(TransactionType::Dispute, 1, 2, None),
+        ] {
+            ledger.apply(transaction);
+        }
+
+        ledger
+    }
+
+    /// Test that a dispute above the held cap is held up to the cap, the rest logged, and released as held.
+    #[test]
+    fn test_held_cap_partial_hold() {
+        let mut ledger = capped_dispute(HeldCapPolicy::PartialHold);
+
+        let client = ledger.clients().get(&1).unwrap();
+        assert_eq!(client.get_held(), Decimal::new(5, 0));
+        assert_eq!(client.get_available(), Decimal::new(5, 0));
+        assert_eq!(ledger.stats().capped_shortfall, Decimal::new(3, 0));
+
+        ledger.apply(Transaction::new(TransactionType::Resolve, 1, 2, None));
+
+        let client = ledger.clients().get(&1).unwrap();
+        assert_eq!(client.get_held(), Decimal::ZERO);
+        assert_eq!(client.get_available(), Decimal::new(10, 0));
+    }
+
+    /// Test that a held cap whose product with an oversized total overflows rejects the dispute instead of
+    /// panicking.
+    #[test]
+    fn test_held_cap_overflow() {
+        let mut ledger = Ledger::new(Config {
+            held_cap: Some(Decimal::TWO),
+            ..Default::default()
+        });
+
+        ledger.apply(Transaction::new(
+            TransactionType::Deposit,
+            1,
+            1,
+            Some(Decimal::MAX),
+        ));
+        ledger.apply(Transaction::new(TransactionType::Dispute, 1, 1, None));
+
+        let client = ledger.clients().get(&1).unwrap();
+        assert_eq!(client.get_held(), Decimal::ZERO);
+        assert_eq!(client.get_available(), Decimal::MAX);
+        assert_eq!(ledger.stats().rejected, 1);
+    }
+
+    /// Test that a dispute above the held cap is ignored when rejected.
+    #[test]
+    fn test_held_cap_reject() {
+        let ledger = capped_dispute(HeldCapPolicy::Reject);
+
+        let client = ledger.clients().get(&1).unwrap();
+        assert_eq!(client.get_held(), Decimal::ZERO);
+        assert_eq!(client.get_available(), Decimal::new(10, 0));
+        assert_eq!(ledger.stats().capped_shortfall, Decimal::ZERO);
+    }
+
+    /// Clock that panics when read, for a transaction that panics while applied.
+    #[derive(Debug)]
+    struct PanickingClock;
+
+    impl Clock for PanickingClock {
+        fn now(&self) -> SystemTime {
+            panic!("The clock is broken.")
+        }
+    }
+
+    /// Clock that only moves when told to.
+    #[derive(Debug)]
+    struct MockClock(std::sync::Mutex<SystemTime>);
+
+    impl Clock for MockClock {
+        fn now(&self) -> SystemTime {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    /// Test that a resolve is ignored until the dispute has been held for the hold days.
+    #[test]
+    fn test_hold_days() {
+        let clock = Arc::new(MockClock(std::sync::Mutex::new(SystemTime::UNIX_EPOCH)));
+        let mut ledger = Ledger::new(Config {
+            hold_days: Some(2),
+            clock: Some(clock.clone()),
+            ..Default::default()
+        });
+
+        ledger.apply(Transaction::new(
+            TransactionType::Deposit,
+            1,
+            1,
+            Some(Decimal::TEN),
+        ));
+        ledger.apply(Transaction::new(TransactionType::Dispute, 1, 1, None));
+
+        let advance =
+            |days: u64| *clock.0.lock().unwrap() += Duration::from_secs(days * 24 * 60 * 60);
+
+        // One day in, the resolve is too early.
+        advance(1);
+        ledger.apply(Transaction::new(TransactionType::Resolve, 1, 1, None));
+        assert_eq!(ledger.clients().get(&1).unwrap().get_held(), Decimal::TEN);
+
+        // Two days in, the funds have matured.
+        advance(1);
+        ledger.apply(Transaction::new(TransactionType::Resolve, 1, 1, None));
+        let client = ledger.clients().get(&1).unwrap();
+        assert_eq!(client.get_held(), Decimal::ZERO);
+        assert_eq!(client.get_available(), Decimal::TEN);
+    }
+
+    /// Test that the recomputed totals match the balances through a dispute lifecycle, and catch a balance changed
+    /// behind the ledger's back.
+    #[test]
+    fn test_verify_totals() {
+        let mut ledger = Ledger::new(Config {
+            verify_ledger: true,
+            ..Default::default()
+        });
+
+        for transaction in [
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(Decimal::new(10, 0))),
+            Transaction::new(TransactionType::Deposit, 1, 2, Some(Decimal::new(5, 0))),
+            Transaction::new(TransactionType::Withdrawal, 1, 3, Some(Decimal::new(3, 0))),
+            Transaction::new(TransactionType::Dispute, 1, 1, None),
+            Transaction::new(TransactionType::Resolve, 1, 1, None),
+            Transaction::new(TransactionType::Dispute, 1, 2, None),
+            Transaction::new(TransactionType::Chargeback, 1, 2, None),
+        ] {
+            ledger.apply(transaction);
+        }
+
+        assert_eq!(
+            ledger.clients().get(&1).unwrap().get_total(),
+            Decimal::new(7, 0)
+        );
+        assert_eq!(ledger.verify_totals(), []);
+
+        // Funds appearing without a transaction are caught.
+        ledger
+            .clients
+            .get_mut(&1)
+            .unwrap()
+            .add_available(Decimal::ONE)
+            .unwrap();
+
+        assert_eq!(
+            ledger.verify_totals(),
+            [TotalDivergence {
+                client: 1,
+                currency: None,
+                recomputed: Decimal::new(7, 0),
+                total: Decimal::new(8, 0),
+            }]
+        );
+
+        // And recorded in the stats at the end, for the caller to report.
+        ledger.finish();
+        assert_eq!(ledger.stats().divergences, ledger.verify_totals());
+    }
+
+    /// Test that a transaction that panics is skipped, and the ones after it are still applied.
+    #[test]
+    fn test_try_apply_panic() {
+        // Counting the dispute towards the rapid disputes reads the clock, which panics.
+        let (rejections, mut rejections_rx) = mpsc::unbounded_channel();
+        let mut ledger = Ledger::new(Config {
+            rapid_disputes: Some(RapidDisputeRule {
+                count: 2,
+                window: Duration::from_secs(60),
+            }),
+            clock: Some(Arc::new(PanickingClock)),
+            ..Default::default()
+        })
+        .with_rejections(rejections);
+
+        ledger
+            .try_apply(Transaction::new(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Decimal::TEN),
+            ))
+            .unwrap();
+        assert!(ledger
+            .try_apply(Transaction::new(TransactionType::Dispute, 1, 1, None))
+            .is_err());
+        ledger
+            .try_apply(Transaction::new(
+                TransactionType::Deposit,
+                1,
+                2,
+                Some(Decimal::ONE),
+            ))
+            .unwrap();
+
+        let client = ledger.clients().get(&1).unwrap();
+        assert_eq!(client.get_available(), Decimal::new(11, 0));
+        assert_eq!(client.get_held(), Decimal::ZERO);
+        assert_eq!(ledger.stats().panics, 1);
+        assert!(!ledger.disputed.contains(&1));
+
+        let rejection = rejections_rx.try_recv().unwrap();
+        assert_eq!(rejection.transaction.get_type(), TransactionType::Dispute);
+        assert_eq!(rejection.reason, RejectionReason::Panicked);
+    }
+
+    /// Three disputes of client 1, `interval` apart, under a rule of three disputes within an hour.
+    fn rapid_disputes(interval: Duration) -> Client {
+        let clock = Arc::new(MockClock(std::sync::Mutex::new(SystemTime::UNIX_EPOCH)));
+        let mut ledger = Ledger::new(Config {
+            rapid_disputes: Some(RapidDisputeRule {
+                count: 3,
+                window: Duration::from_secs(60 * 60),
+            }),
+            clock: Some(clock.clone()),
+            ..Default::default()
+        });
+
+        for tx in 1..=3 {
+            ledger.apply(Transaction::new(
+                TransactionType::Deposit,
+                1,
+                tx,
+                Some(Decimal::ONE),
+            ));
+        }
+        for tx in 1..=3 {
+            ledger.apply(Transaction::new(TransactionType::Dispute, 1, tx, None));
+            *clock.0.lock().unwrap() += interval;
+        }
+
+        ledger.clients().get(&1).unwrap().clone()
+    }
+
+    /// Test that three disputes within the window flag the client, and three slower ones don't.
+    #[test]
+    fn test_rapid_disputes() {
+        assert!(rapid_disputes(Duration::from_secs(10 * 60)).is_flagged());
+        assert!(!rapid_disputes(Duration::from_secs(40 * 60)).is_flagged());
+    }
+
+    /// Ledger where client 2 only appears in a dispute of a transaction of client 1.
+    fn phantom_client(phantom_client_policy: PhantomClientPolicy) -> Ledger {
+        let mut ledger = Ledger::new(Config {
+            phantom_client_policy,
+            ..Default::default()
+        });
+
+        for transaction in [
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(Decimal::new(5, 0))),
+            Transaction::new(TransactionType::Dispute, 2, 1, None),
+        ] {
+            ledger.apply(transaction);
+        }
+
+        ledger
+    }
+
+    /// Test that a client only seen in a dispute is flagged, until a transaction changes it.
+    #[test]
+    fn test_flag_phantom_client() {
+        let mut ledger = phantom_client(PhantomClientPolicy::Flag);
+
+        assert!(ledger.clients().contains_key(&2));
+        assert_eq!(ledger.stats().phantom_clients, 1);
+
+        ledger.apply(Transaction::new(
+            TransactionType::Deposit,
+            2,
+            2,
+            Some(Decimal::ONE),
+        ));
+        assert_eq!(ledger.stats().phantom_clients, 0);
+    }
+
+    /// Test that a client only seen in a dispute is left out of the clients.
+    #[test]
+    fn test_suppress_phantom_client() {
+        let ledger = phantom_client(PhantomClientPolicy::Suppress);
+
+        assert_eq!(ledger.clients().keys().copied().collect::<Vec<_>>(), [1]);
+        assert_eq!(ledger.stats().phantom_clients, 0);
+
+        // By default it is kept, without being flagged.
+        let ledger = phantom_client(PhantomClientPolicy::Keep);
+        assert!(ledger.clients().contains_key(&2));
+        assert_eq!(ledger.stats().phantom_clients, 0);
+    }
+
+    /// Ledger with a client restored with an overdrawn available balance and a deposit of 4 that is still held,
+    /// so charging the deposit back leaves a total of -3.
+    fn overdrawn_ledger(negative_total_policy: NegativeTotalPolicy) -> Ledger {
+        let mut ledger = Ledger::new(Config {
+            negative_total_policy,
+            ..Default::default()
+        });
+
+        ledger.clients.insert(
+            1,
+            Client::with_balances(1, Decimal::new(-3, 0), Decimal::new(4, 0)),
+        );
+        ledger.transactions.insert(
+            1,
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(Decimal::new(4, 0))),
+        );
+        ledger.disputed.insert(1);
+
+        ledger.apply(Transaction::new(TransactionType::Chargeback, 1, 1, None));
+
+        ledger
+    }
+
+    /// Test that a negative total is kept silently by default.
+    #[test]
+    fn test_negative_total_allowed() {
+        let ledger = overdrawn_ledger(NegativeTotalPolicy::Allow);
+        let client = ledger.clients().get(&1).unwrap();
+
+        assert_eq!(client.get_total(), Decimal::new(-3, 0));
+        assert!(client.is_locked());
+        assert_eq!(ledger.stats().negative_totals, 0);
+    }
+
+    /// Test that a transaction leaving a negative total is undone when rejected.
+    #[test]
+    fn test_negative_total_rejected() {
+        let ledger = overdrawn_ledger(NegativeTotalPolicy::RejectTransaction);
+        let client = ledger.clients().get(&1).unwrap();
+
+        assert_eq!(client.get_available(), Decimal::new(-3, 0));
+        assert_eq!(client.get_held(), Decimal::new(4, 0));
+        assert!(!client.is_locked());
+        assert_eq!(ledger.stats().negative_totals, 1);
+    }
+
+    /// Test that a negative total is kept, but counted, when warned about.
+    #[test]
+    fn test_negative_total_warned() {
+        let ledger = overdrawn_ledger(NegativeTotalPolicy::Warn);
+        let client = ledger.clients().get(&1).unwrap();
+
+        assert_eq!(client.get_total(), Decimal::new(-3, 0));
+        assert!(client.is_locked());
+        assert_eq!(ledger.stats().negative_totals, 1);
+    }
+
+    /// Test that a negative deposit is processed as a withdrawal only when sign normalization is on.
+    #[test]
+    fn test_normalize_signs() {
+        let transactions = || {
+            [
+                Transaction::new(TransactionType::Deposit, 1, 1, Some(Decimal::new(25, 0))),
+                Transaction::new(TransactionType::Deposit, 1, 2, Some(Decimal::new(-10, 0))),
+            ]
+        };
+
+        let client = Ledger::process(Default::default(), transactions())
+            .remove(&1)
+            .unwrap();
+        assert_eq!(client.get_available(), Decimal::new(25, 0));
+
+        let config = Config {
+            normalize_signs: true,
+            ..Default::default()
+        };
+        let mut ledger = Ledger::new(config);
+        for transaction in transactions() {
+            ledger.apply(transaction);
+        }
+
+        assert_eq!(
+            ledger.clients().get(&1).unwrap().get_available(),
+            Decimal::new(15, 0)
+        );
+
+        // And the other way around.
+        ledger.apply(Transaction::new(
+            TransactionType::Withdrawal,
+            1,
+            3,
+            Some(Decimal::new(-5, 0)),
+        ));
+        assert_eq!(
+            ledger.clients().get(&1).unwrap().get_available(),
+            Decimal::new(20, 0)
+        );
+    }
+
+    /// Test that the dispute lifecycle reusing the tx id of a deposit isn't a duplicate, and isn't stored.
+    #[test]
+    fn test_dispute_lifecycle_reuses_tx_id() {
+        let (rejections, mut rejections_rx) = mpsc::unbounded_channel();
+        let mut ledger = Ledger::new(Default::default()).with_rejections(rejections);
+
+        for transaction in [
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(Decimal::TEN)),
+            Transaction::new(TransactionType::Dispute, 1, 1, None),
+            Transaction::new(TransactionType::Resolve, 1, 1, None),
+            Transaction::new(TransactionType::Dispute, 1, 1, None),
+            Transaction::new(TransactionType::Chargeback, 1, 1, None),
+        ] {
+            ledger.apply(transaction);
+        }
+
+        assert!(rejections_rx.try_recv().is_err());
+        assert_eq!(
+            ledger.query_transactions(1),
+            [DisputableRecord {
+                tx_id: 1,
+                transaction_type: TransactionType::Deposit,
+                amount: Decimal::TEN,
+                disputed: false,
+            }]
+        );
+
+        let client = &ledger.clients()[&1];
+        assert_eq!(client.get_total(), Decimal::ZERO);
+        assert!(client.is_locked());
+    }
+
+    /// Apply a deposit of 10, and a deposit and withdrawal without an amount, with the missing amount policy.
+    fn missing_amounts(
+        missing_amount_policy: MissingAmountPolicy,
+    ) -> (Ledger, Vec<RejectionReason>) {
+        let (rejections, mut rejections_rx) = mpsc::unbounded_channel();
+        let mut ledger = Ledger::new(Config {
+            missing_amount_policy,
+            ..Default::default()
+        })
+        .with_rejections(rejections);
+
+        for transaction in [
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(Decimal::TEN)),
+            Transaction::new(TransactionType::Deposit, 1, 2, None),
+            Transaction::new(TransactionType::Withdrawal, 2, 3, None),
+        ] {
+            ledger.apply(transaction);
+        }
+
+        let mut reasons = Vec::new();
+        while let Ok(rejection) = rejections_rx.try_recv() {
+            reasons.push(rejection.reason);
+        }
+
+        (ledger, reasons)
+    }
+
+    /// Test that a deposit without an amount is rejected by default.
+    #[test]
+    fn test_missing_amount_rejected() {
+        let (ledger, reasons) = missing_amounts(MissingAmountPolicy::Reject);
+
+        assert_eq!(
+            reasons,
+            [
+                RejectionReason::InvalidAmount,
+                RejectionReason::InvalidAmount
+            ]
+        );
+        assert_eq!(ledger.query_transactions(1).len(), 1);
+        assert_eq!(ledger.clients()[&1].get_available(), Decimal::TEN);
+    }
+
+    /// Test that a deposit without an amount is stored as a deposit of zero, which can then be disputed.
+    #[test]
+    fn test_missing_amount_as_zero() {
+        let (mut ledger, reasons) = missing_amounts(MissingAmountPolicy::TreatAsZero);
+
+        assert!(reasons.is_empty());
+        assert_eq!(ledger.query_transactions(1)[1].amount, Decimal::ZERO);
+        assert_eq!(ledger.clients()[&1].get_available(), Decimal::TEN);
+        assert_eq!(ledger.clients()[&2].get_total(), Decimal::ZERO);
+
+        ledger.apply(Transaction::new(TransactionType::Dispute, 1, 2, None));
+        assert!(ledger.query_transactions(1)[1].disputed);
+    }
+
+    /// Test that a deposit without an amount is ignored, without a rejection or a client.
+    #[test]
+    fn test_missing_amount_skipped() {
+        let (ledger, reasons) = missing_amounts(MissingAmountPolicy::Skip);
+
+        assert!(reasons.is_empty());
+        assert_eq!(ledger.query_transactions(1).len(), 1);
+        assert_eq!(ledger.clients()[&1].get_available(), Decimal::TEN);
+        assert!(!ledger.clients().contains_key(&2));
+    }
+
+    /// Test that a second chargeback of a locked account is rejected by default, and removes its held funds when
+    /// applied.
+    #[test]
+    fn test_post_lock_chargeback() {
+        let charged_back = |post_lock_chargeback_policy| {
+            let mut ledger = Ledger::new(Config {
+                post_lock_chargeback_policy,
+                ..Default::default()
+            });
+            for transaction in [
+                Transaction::new(TransactionType::Deposit, 1, 1, Some(Decimal::TEN)),
+                Transaction::new(TransactionType::Deposit, 1, 2, Some(Decimal::new(4, 0))),
+                Transaction::new(TransactionType::Dispute, 1, 1, None),
+                Transaction::new(TransactionType::Dispute, 1, 2, None),
+                Transaction::new(TransactionType::Chargeback, 1, 1, None),
+            ] {
+                ledger.apply(transaction);
+            }
+            assert_eq!(ledger.clients()[&1].get_held(), Decimal::new(4, 0));
+
+            ledger.apply(Transaction::new(TransactionType::Chargeback, 1, 2, None));
+            ledger.into_clients().remove(&1).unwrap()
+        };
+
+        let client = charged_back(PostLockChargebackPolicy::Ignore);
+        assert_eq!(client.get_held(), Decimal::new(4, 0));
+
+        let client = charged_back(PostLockChargebackPolicy::Apply);
+        assert_eq!(client.get_held(), Decimal::ZERO);
+        assert_eq!(client.get_total(), Decimal::ZERO);
+        assert!(client.is_locked());
+        assert_eq!(client.get_lock_trigger().unwrap().tx_id, 1);
+    }
+
+    /// Test that the decimal places are limited per transaction type, trailing zeros aside.
+    #[test]
+    fn test_decimal_places() {
+        let (rejections, mut rejections_rx) = mpsc::unbounded_channel();
+        let mut ledger = Ledger::new(Config {
+            decimal_places: [
+                (TransactionType::Deposit, 4),
+                (TransactionType::Withdrawal, 2),
+            ]
+            .into(),
+            ..Default::default()
+        })
+        .with_rejections(rejections);
+
+        for transaction in [
+            Transaction::new(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Decimal::new(101_234, 4)),
+            ),
+            Transaction::new(
+                TransactionType::Withdrawal,
+                1,
+                2,
+                Some(Decimal::new(1_234, 4)),
+            ),
+            Transaction::new(
+                TransactionType::Withdrawal,
+                1,
+                3,
+                Some(Decimal::new(1_200, 4)),
+            ),
+            Transaction::new(
+                TransactionType::Deposit,
+                1,
+                4,
+                Some(Decimal::new(12_345, 5)),
+            ),
+        ] {
+            ledger.apply(transaction);
+        }
+
+        let mut rejected = Vec::new();
+        while let Ok(rejection) = rejections_rx.try_recv() {
+            rejected.push((rejection.transaction.get_tx_id(), rejection.reason));
+        }
+        assert_eq!(
+            rejected,
+            [
+                (2, RejectionReason::InvalidAmount),
+                (4, RejectionReason::InvalidAmount)
+            ]
+        );
+        assert_eq!(
+            ledger.clients()[&1].get_available(),
+            Decimal::new(100_034, 4)
+        );
+    }
+
+    /// Test that an amount with more integer digits than allowed is rejected, and one within the limit isn't.
+    #[test]
+    fn test_max_integer_digits() {
+        let (rejections, mut rejections_rx) = mpsc::unbounded_channel();
+        let mut ledger = Ledger::new(Config {
+            max_integer_digits: Some(12),
+            ..Default::default()
+        })
+        .with_rejections(rejections);
+
+        ledger.apply(Transaction::new(
+            TransactionType::Deposit,
+            1,
+            1,
+            Some(Decimal::from_i128_with_scale(
+                1_234_567_890_123_456_789_012,
+                2,
+            )),
+        ));
+        ledger.apply(Transaction::new(
+            TransactionType::Deposit,
+            1,
+            2,
+            Some(Decimal::new(9_999_999_999_995, 1)),
+        ));
+
+        let rejection = rejections_rx.try_recv().unwrap();
+        assert_eq!(rejection.transaction.get_tx_id(), 1);
+        assert_eq!(rejection.reason, RejectionReason::InvalidAmount);
+        assert!(rejections_rx.try_recv().is_err());
+        assert_eq!(
+            ledger.clients()[&1].get_available(),
+            Decimal::new(9_999_999_999_995, 1)
+        );
+    }
+
+    /// Test that a reset must be allowed, clears the balances, the lock and the open disputes, and is audited.
+    #[test]
+    fn test_reset_client() {
+        let transactions = [
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(Decimal::TEN)),
+            Transaction::new(TransactionType::Deposit, 1, 2, Some(Decimal::new(4, 0))),
+            Transaction::new(TransactionType::Dispute, 1, 1, None),
+            Transaction::new(TransactionType::Dispute, 1, 2, None),
+            Transaction::new(TransactionType::Chargeback, 1, 1, None),
+        ];
+
+        let mut ledger = Ledger::new(Default::default());
+        ledger.apply(transactions[0].clone());
+        assert!(ledger.reset_client(1, "admin: recovery").is_err());
+        assert_eq!(ledger.clients()[&1].get_available(), Decimal::TEN);
+
+        let clock = Arc::new(MockClock(std::sync::Mutex::new(SystemTime::UNIX_EPOCH)));
+        let mut ledger = Ledger::new(Config {
+            allow_admin_reset: true,
+            clock: Some(clock),
+            ..Default::default()
+        });
+        for transaction in transactions {
+            ledger.apply(transaction);
+        }
+
+        let previous = ledger.clients()[&1].clone();
+        assert!(previous.is_locked());
+        assert_eq!(previous.get_held(), Decimal::new(4, 0));
+
+        ledger.reset_client(1, "admin: recovery").unwrap();
+        assert!(ledger.reset_client(3, "admin: recovery").is_err());
+
+        let client = &ledger.clients()[&1];
+        assert_eq!(client.get_available(), Decimal::ZERO);
+        assert_eq!(client.get_held(), Decimal::ZERO);
+        assert!(!client.is_locked());
+        assert!(ledger
+            .query_transactions(1)
+            .iter()
+            .all(|record| !record.disputed));
+
+        assert_eq!(
+            ledger.audit_log(),
+            [AuditEntry {
+                client: 1,
+                operation: AuditOperation::Reset {
+                    previous,
+                    closed_disputes: vec![2],
+                },
+                reason: "admin: recovery".into(),
+                at: SystemTime::UNIX_EPOCH,
+            }]
+        );
+
+        // The client takes transactions again.
+        ledger.apply(Transaction::new(
+            TransactionType::Deposit,
+            1,
+            3,
+            Some(Decimal::ONE),
+        ));
+        assert_eq!(ledger.clients()[&1].get_available(), Decimal::ONE);
+    }
+
+    /// Withdrawals of 60 and 50 by client 1, `interval` apart, under a limit of 100 within an hour.
+    fn withdrawal_velocity(interval: Duration) -> (Client, Vec<RejectedTransaction>) {
+        let clock = Arc::new(MockClock(std::sync::Mutex::new(SystemTime::UNIX_EPOCH)));
+        let (rejections, mut rejections_rx) = mpsc::unbounded_channel();
+        let mut ledger = Ledger::new(Config {
+            withdrawal_velocity: Some(VelocityLimit {
+                amount: Decimal::new(100, 0),
+                window: Duration::from_secs(60 * 60),
+            }),
+            clock: Some(clock.clone()),
+            ..Default::default()
+        })
+        .with_rejections(rejections);
+
+        ledger.apply(Transaction::new(
+            TransactionType::Deposit,
+            1,
+            1,
+            Some(Decimal::new(200, 0)),
+        ));
+        ledger.apply(Transaction::new(
+            TransactionType::Withdrawal,
+            1,
+            2,
+            Some(Decimal::new(60, 0)),
+        ));
+        *clock.0.lock().unwrap() += interval;
+        ledger.apply(Transaction::new(
+            TransactionType::Withdrawal,
+            1,
+            3,
+            Some(Decimal::new(50, 0)),
+        ));
+
+        let mut rejected = Vec::new();
+        while let Ok(rejection) = rejections_rx.try_recv() {
+            rejected.push(rejection);
+        }
+
+        (ledger.clients()[&1].clone(), rejected)
+    }
+
+    /// Test that a second withdrawal within the window over the velocity limit is rejected, and the same two
+    /// withdrawals further apart both succeed.
+    #[test]
+    fn test_withdrawal_velocity() {
+        let (client, rejected) = withdrawal_velocity(Duration::from_secs(30 * 60));
+        assert_eq!(client.get_available(), Decimal::new(140, 0));
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(rejected[0].transaction.get_tx_id(), 3);
+        assert_eq!(rejected[0].reason, RejectionReason::VelocityExceeded);
+
+        let (client, rejected) = withdrawal_velocity(Duration::from_secs(90 * 60));
+        assert_eq!(client.get_available(), Decimal::new(90, 0));
+        assert!(rejected.is_empty());
+    }
+
+    /// Test that disputing a deposit twice holds it once, and that a resolve or chargeback of a transaction that
+    /// isn't disputed is rejected.
+    #[test]
+    fn test_double_dispute() {
+        let (rejections, mut rejections_rx) = mpsc::unbounded_channel();
+        let mut ledger = Ledger::new(Default::default()).with_rejections(rejections);
+
+        for transaction in [
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(Decimal::new(10, 0))),
+            Transaction::new(TransactionType::Deposit, 1, 2, Some(Decimal::new(5, 0))),
+            Transaction::new(TransactionType::Dispute, 1, 1, None),
+            Transaction::new(TransactionType::Dispute, 1, 1, None),
+            Transaction::new(TransactionType::Resolve, 1, 2, None),
+            Transaction::new(TransactionType::Chargeback, 1, 2, None),
+        ] {
+            ledger.apply(transaction);
+        }
+
+        let client = &ledger.clients()[&1];
+        assert_eq!(client.get_available(), Decimal::new(5, 0));
+        assert_eq!(client.get_held(), Decimal::new(10, 0));
+        assert!(!client.is_locked());
+
+        let mut rejected = Vec::new();
+        while let Ok(rejection) = rejections_rx.try_recv() {
+            rejected.push(rejection.reason);
+        }
+        assert_eq!(
+            rejected,
+            [
+                RejectionReason::AlreadyDisputed,
+                RejectionReason::NotDisputed,
+                RejectionReason::NotDisputed
+            ]
+        );
+
+        // A second resolve of the deposit doesn't release it twice.
+        ledger.apply(Transaction::new(TransactionType::Resolve, 1, 1, None));
+        ledger.apply(Transaction::new(TransactionType::Resolve, 1, 1, None));
+        assert_eq!(ledger.clients()[&1].get_available(), Decimal::new(15, 0));
+        assert_eq!(ledger.clients()[&1].get_held(), Decimal::ZERO);
+    }
+
+    /// Test that a dispute of a withdrawal is rejected as unknown and changes nothing.
+    #[test]
+    fn test_dispute_withdrawal() {
+        let (rejections, mut rejections_rx) = mpsc::unbounded_channel();
+        let mut ledger = Ledger::new(Default::default()).with_rejections(rejections);
+
+        ledger.apply(Transaction::new(
+            TransactionType::Deposit,
+            1,
+            1,
+            Some(Decimal::new(10, 0)),
+        ));
+        ledger.apply(Transaction::new(
+            TransactionType::Withdrawal,
+            1,
+            2,
+            Some(Decimal::new(4, 0)),
+        ));
+        let before = ledger.clients()[&1].clone();
+
+        ledger.apply(Transaction::new(TransactionType::Dispute, 1, 2, None));
+        ledger.apply(Transaction::new(TransactionType::Chargeback, 1, 2, None));
+
+        let client = &ledger.clients()[&1];
+        assert_eq!(*client, before);
+        assert_eq!(client.get_available(), Decimal::new(6, 0));
+        assert_eq!(client.get_held(), Decimal::ZERO);
+        assert_eq!(ledger.dispute_state(1, 2), None);
+
+        assert_eq!(
+            rejections_rx.try_recv().unwrap().reason,
+            RejectionReason::UnknownTransaction
+        );
+        assert_eq!(
+            rejections_rx.try_recv().unwrap().reason,
+            RejectionReason::UnknownTransaction
+        );
+    }
+
+    /// Test that amounts with more than four decimal places are rejected, leaving the balance as it was.
+    #[test]
+    fn test_excess_precision() {
+        let (rejections, mut rejections_rx) = mpsc::unbounded_channel();
+        let mut ledger = Ledger::new(Default::default()).with_rejections(rejections);
+
+        ledger.apply(Transaction::new(
+            TransactionType::Deposit,
+            1,
+            1,
+            Some("1.23456".parse().unwrap()),
+        ));
+        assert_eq!(ledger.clients()[&1].get_available(), Decimal::ZERO);
+
+        // Trailing zeros don't count.
+        ledger.apply(Transaction::new(
+            TransactionType::Deposit,
+            1,
+            2,
+            Some("1.500000".parse().unwrap()),
+        ));
+        ledger.apply(Transaction::new(
+            TransactionType::Withdrawal,
+            1,
+            3,
+            Some("0.00001".parse().unwrap()),
+        ));
+        assert_eq!(ledger.clients()[&1].get_available(), Decimal::new(15, 1));
+
+        for tx_id in [1, 3] {
+            let rejection = rejections_rx.try_recv().unwrap();
+            assert_eq!(rejection.transaction.get_tx_id(), tx_id);
+            assert_eq!(rejection.reason, RejectionReason::InvalidAmount);
+        }
+    }
+
+    /// Test that an account locked by a chargeback refuses a withdrawal until it is unlocked.
+    #[test]
+    fn test_unlock() {
+        let mut ledger = Ledger::new(Default::default());
+        for transaction in [
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(Decimal::TEN)),
+            Transaction::new(TransactionType::Deposit, 1, 2, Some(Decimal::new(4, 0))),
+            Transaction::new(TransactionType::Dispute, 1, 1, None),
+            Transaction::new(TransactionType::Chargeback, 1, 1, None),
+            Transaction::new(TransactionType::Withdrawal, 1, 3, Some(Decimal::ONE)),
+        ] {
+            ledger.apply(transaction);
+        }
+        assert!(ledger.clients()[&1].is_locked());
+        assert_eq!(ledger.clients()[&1].get_available(), Decimal::new(4, 0));
+
+        ledger.apply(Transaction::new(TransactionType::Unlock, 1, 4, None));
+        ledger.apply(Transaction::new(
+            TransactionType::Withdrawal,
+            1,
+            5,
+            Some(Decimal::ONE),
+        ));
+
+        let client = &ledger.clients()[&1];
+        assert!(!client.is_locked());
+        assert_eq!(client.get_lock_trigger(), None);
+        assert_eq!(client.get_available(), Decimal::new(3, 0));
+    }
+
+    /// Test that only deposits are kept, so withdrawals don't grow the memory, and deposits are still disputed.
+    #[test]
+    fn test_only_deposits_kept() {
+        let mut ledger = Ledger::new(Default::default());
+        ledger.apply(Transaction::new(
+            TransactionType::Deposit,
+            1,
+            1,
+            Some(Decimal::new(1000, 0)),
+        ));
+        for tx in 2..=501 {
+            ledger.apply(Transaction::new(
+                TransactionType::Withdrawal,
+                1,
+                tx,
+                Some(Decimal::ONE),
+            ));
+        }
+        ledger.apply(Transaction::new(
+            TransactionType::Deposit,
+            1,
+            502,
+            Some(Decimal::TEN),
+        ));
+        assert_eq!(ledger.transactions.len(), 2);
+
+        ledger.apply(Transaction::new(TransactionType::Dispute, 1, 502, None));
+        let client = &ledger.clients()[&1];
+        assert_eq!(client.get_held(), Decimal::TEN);
+        assert_eq!(client.get_available(), Decimal::new(500, 0));
+        assert_eq!(ledger.transactions.len(), 2);
+    }
+
+    /// Test that a deposit or withdrawal reusing the tx id of an earlier one, even of another client or rejected,
+    /// is rejected and changes nothing.
+    #[test]
+    fn test_duplicate_tx_id() {
+        let (rejections, mut rejections_rx) = mpsc::unbounded_channel();
+        let mut ledger = Ledger::new(Default::default()).with_rejections(rejections);
+
+        for transaction in [
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(Decimal::TEN)),
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(Decimal::ONE)),
+            Transaction::new(TransactionType::Withdrawal, 2, 1, Some(Decimal::ONE)),
+            Transaction::new(TransactionType::Withdrawal, 1, 2, Some(Decimal::new(20, 0))),
+            Transaction::new(TransactionType::Deposit, 1, 2, Some(Decimal::ONE)),
+            Transaction::new(TransactionType::Dispute, 1, 1, None),
+        ] {
+            ledger.apply(transaction);
+        }
+
+        let reasons = std::iter::from_fn(|| rejections_rx.try_recv().ok())
+            .map(|rejection| (rejection.transaction.get_tx_id(), rejection.reason))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            reasons,
+            [
+                (1, RejectionReason::DuplicateTransaction),
+                (1, RejectionReason::DuplicateTransaction),
+                (2, RejectionReason::InsufficientFunds),
+                (2, RejectionReason::DuplicateTransaction),
+            ]
+        );
+        assert_eq!(ledger.stats().duplicates, 3);
+
+        // The dispute holds the first deposit, not the one that reused its tx id.
+        let client = &ledger.clients()[&1];
+        assert_eq!(client.get_held(), Decimal::TEN);
+        assert_eq!(client.get_available(), Decimal::ZERO);
+    }
+
+    /// Test that a dispute before the deposit it refers to is applied once the deposit comes, and that one whose
+    /// deposit never comes is rejected at the end of the input.
+    #[test]
+    fn test_dispute_before_deposit() {
+        let (rejections, mut rejections_rx) = mpsc::unbounded_channel();
+        let mut ledger = Ledger::new(Default::default()).with_rejections(rejections);
+
+        ledger.apply(Transaction::new(TransactionType::Dispute, 1, 1, None));
+        ledger.apply(Transaction::new(TransactionType::Dispute, 1, 5, None));
+        assert_eq!(ledger.stats().pending_disputes, 2);
+        assert_eq!(ledger.clients()[&1].get_held(), Decimal::ZERO);
+
+        ledger.apply(Transaction::new(
+            TransactionType::Deposit,
+            1,
+            1,
+            Some(Decimal::TEN),
+        ));
+        let client = &ledger.clients()[&1];
+        assert_eq!(client.get_held(), Decimal::TEN);
+        assert_eq!(client.get_available(), Decimal::ZERO);
+        assert_eq!(ledger.dispute_state(1, 1), Some(DisputeState::Disputed));
+        assert_eq!(ledger.stats().pending_disputes, 1);
+        assert!(rejections_rx.try_recv().is_err());
+
+        ledger.finish();
+        let rejection = rejections_rx.try_recv().unwrap();
+        assert_eq!(rejection.transaction.get_tx_id(), 5);
+        assert_eq!(rejection.reason, RejectionReason::UnknownTransaction);
+        assert_eq!(ledger.stats().pending_disputes, 0);
+        assert_eq!(ledger.stats().unmatched_disputes, 1);
+        assert_eq!(ledger.stats().rejected, 1);
+    }
+
+    /// Test that once the pending disputes are at capacity, a dispute of an unknown deposit is rejected at once.
+    #[test]
+    fn test_pending_disputes_capacity() {
+        let (rejections, mut rejections_rx) = mpsc::unbounded_channel();
+        let mut ledger = Ledger::new(Default::default()).with_rejections(rejections);
+
+        for tx_id in 0..=PENDING_DISPUTES_CAPACITY as u32 {
+            ledger.apply(Transaction::new(TransactionType::Dispute, 1, tx_id, None));
+        }
+
+        assert_eq!(ledger.stats().pending_disputes, PENDING_DISPUTES_CAPACITY);
+        let rejection = rejections_rx.try_recv().unwrap();
+        assert_eq!(
+            rejection.transaction.get_tx_id(),
+            PENDING_DISPUTES_CAPACITY as u32
+        );
+        assert_eq!(rejection.reason, RejectionReason::UnknownTransaction);
+    }
+    /// Test that a client's balances in two currencies are kept apart, with a dispute in each settled on its own,
+    /// and that a chargeback only locks the account of its currency.
+    #[test]
+    fn test_currencies() {
+        let usd = "USD".parse().ok();
+        let eur = "EUR".parse().ok();
+        let (rejections, mut rejections_rx) = mpsc::unbounded_channel();
+        let mut ledger = Ledger::new(Config {
+            currencies: true,
+            ..Default::default()
+        })
+        .with_rejections(rejections);
+
+        for transaction in [
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(Decimal::TEN)).with_currency(usd),
+            Transaction::new(TransactionType::Deposit, 1, 2, Some(Decimal::new(5, 0)))
+                .with_currency(eur),
+            Transaction::new(TransactionType::Dispute, 1, 1, None).with_currency(usd),
+            Transaction::new(TransactionType::Dispute, 1, 2, None).with_currency(eur),
+            // A deposit is only referenced in its own currency.
+            Transaction::new(TransactionType::Resolve, 1, 1, None).with_currency(eur),
+            Transaction::new(TransactionType::Resolve, 1, 1, None).with_currency(usd),
+            Transaction::new(TransactionType::Chargeback, 1, 2, None).with_currency(eur),
+            Transaction::new(TransactionType::Deposit, 1, 3, Some(Decimal::ONE)).with_currency(eur),
+            Transaction::new(TransactionType::Deposit, 1, 4, Some(Decimal::ONE)).with_currency(usd),
+        ] {
+            ledger.apply(transaction);
+        }
+
+        let reasons = std::iter::from_fn(|| rejections_rx.try_recv().ok())
+            .map(|rejection| (rejection.transaction.get_tx_id(), rejection.reason))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            reasons,
+            [
+                (1, RejectionReason::UnknownTransaction),
+                (3, RejectionReason::ClientLocked),
+            ]
+        );
+
+        let client = &ledger.clients()[&1];
+        let usd_account = client.account(usd).unwrap();
+        assert_eq!(usd_account.get_available(), Decimal::new(11, 0));
+        assert_eq!(usd_account.get_held(), Decimal::ZERO);
+        assert!(!usd_account.is_locked());
+
+        let eur_account = client.account(eur).unwrap();
+        assert_eq!(eur_account.get_total(), Decimal::ZERO);
+        assert!(eur_account.is_locked());
+
+        // The client's own balances were never used, so only its accounts in currencies are written.
+        let currencies = client
+            .accounts()
+            .map(Client::get_currency)
+            .collect::<Vec<_>>();
+        assert_eq!(currencies, [eur, usd]);
+    }
+
+    /// Test that a dispute that waited for its deposit is counted once by its source, not again when applied.
+    #[test]
+    fn test_pending_dispute_source() {
+        let mut ledger = Ledger::new(Default::default());
+
+        ledger.apply(Transaction::new(TransactionType::Dispute, 1, 1, None).with_source("bank"));
+        ledger.apply(
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(Decimal::TEN))
+                .with_source("bank"),
+        );
+
+        assert_eq!(ledger.clients()[&1].get_held(), Decimal::TEN);
+        assert_eq!(ledger.stats().processed_by_source["bank"], 2);
+    }
+
+    /// Test that a resolve or chargeback that can't take the disputed funds from held is rejected without closing
+    /// the dispute, so it can still be settled once the funds are back in held.
+    #[test]
+    fn test_failed_settlement_keeps_dispute() {
+        for dispute_policy in [
+            DisputePolicy::RequireAvailable,
+            DisputePolicy::AvailableFirst,
+        ] {
+            let (rejections, mut rejections_rx) = mpsc::unbounded_channel();
+            let mut ledger = Ledger::new(Config {
+                dispute_policy,
+                ..Default::default()
+            })
+            .with_rejections(rejections);
+
+            ledger.apply(Transaction::new(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Decimal::TEN),
+            ));
+            ledger.apply(Transaction::new(TransactionType::Dispute, 1, 1, None));
+
+            // Held short of the disputed amount.
+            let four = Decimal::new(4, 0);
+            let client = ledger.clients.get_mut(&1).unwrap();
+            client.subtract_held(four).unwrap();
+
+            ledger.apply(Transaction::new(TransactionType::Resolve, 1, 1, None));
+            ledger.apply(Transaction::new(TransactionType::Chargeback, 1, 1, None));
+
+            let reasons = std::iter::from_fn(|| rejections_rx.try_recv().ok())
+                .map(|rejection| (rejection.transaction.get_type(), rejection.reason))
+                .collect::<Vec<_>>();
+            assert_eq!(
+                reasons,
+                [
+                    (TransactionType::Resolve, RejectionReason::InsufficientFunds),
+                    (
+                        TransactionType::Chargeback,
+                        RejectionReason::InsufficientFunds
+                    ),
+                ]
+            );
+            assert_eq!(ledger.dispute_state(1, 1), Some(DisputeState::Disputed));
+            assert!(!ledger.clients()[&1].is_locked());
+
+            // Once the funds are back, the same dispute is resolved.
+            let client = ledger.clients.get_mut(&1).unwrap();
+            client.add_available(four).unwrap();
+            client.transfer_available_to_held(four).unwrap();
+            ledger.apply(Transaction::new(TransactionType::Resolve, 1, 1, None));
+
+            let client = &ledger.clients()[&1];
+            assert_eq!(client.get_held(), Decimal::ZERO);
+            assert_eq!(client.get_available(), Decimal::TEN);
+            assert_eq!(ledger.dispute_state(1, 1), Some(DisputeState::Resolved));
+        }
+    }
+
+    /// Test that the tx id set tells apart the inserted ids, across pages and at both ends of the u32 space.
+    #[test]
+    fn test_tx_id_set() {
+        let mut tx_ids = TxIdSet::default();
+        for tx_id in [0, 1, 63, 64, 65_535, 65_536, u32::MAX] {
+            assert!(!tx_ids.contains(tx_id));
+            assert!(tx_ids.insert(tx_id));
+            assert!(!tx_ids.insert(tx_id));
+            assert!(tx_ids.contains(tx_id));
+        }
+
+        assert!(!tx_ids.contains(2));
+        assert!(!tx_ids.contains(65_537));
+        assert!(!tx_ids.contains(u32::MAX - 1));
+        assert_eq!(tx_ids.pages.iter().flatten().count(), 3);
+    }
+}
@@ -0,0 +1,25 @@
+//! Engine of the transactions processor: reading transactions, applying them to the clients in parallel, and
+//! writing the results. The `rct` binary is a thin wrapper over it.
+
+pub mod binary;
+pub mod client;
+pub mod clock;
+pub mod config;
+pub mod generate;
+pub mod input;
+pub mod ledger;
+pub mod normalize;
+pub mod output;
+pub mod persist;
+pub mod priority;
+pub mod s3;
+pub mod service;
+pub mod transaction;
+pub mod transaction_processor;
+pub mod verify;
+
+pub use client::{Client, ClientError};
+pub use transaction::{Transaction, TransactionType};
+pub use transaction_processor::{
+    TransactionProcessor, TransactionProcessorBuilder, TransactionSubmitter,
+};
@@ -1,140 +1,737 @@
-mod client;
-mod transaction;
-mod transaction_processor;
+mod options;
 
-use anyhow::{Context, Result};
-use csv_async::Trim;
-use futures::stream::StreamExt;
-use tokio::sync::mpsc;
-use transaction::Transaction;
-use transaction_processor::TransactionProcessor;
+use anyhow::{bail, ensure, Context, Result};
+use options::Options;
+use rct::{
+    config::{Config, NegativeTotalPolicy},
+    generate, input,
+    input::{MalformedRecord, TransactionReader},
+    ledger::Ledger,
+    normalize, output,
+    output::{OutputFormat, RetryWriter},
+    persist::{
+        ChecksumCsvPersister, CsvPersister, DeltaCsvPersister, JsonPersister,
+        LockedReportPersister, ParquetPersister, PartitionedCsvPersister, ResultPersister,
+        SnapshotPersister, TablePersister, TeePersister,
+    },
+    s3::S3Location,
+    service, verify, Client, Transaction, TransactionProcessor, TransactionSubmitter,
+};
+use std::collections::HashMap;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, BufWriter},
+    time::Instant,
+};
 
 #[tokio::main]
 async fn main() -> Result<()> {
     // Handle application arguments.
-    let mut args = std::env::args();
-    let exe_name = args.next().context("Unable to get executable name.")?;
-    let transactions_file_path = args
-        .next()
-        .context(format!("Usage: {exe_name} <transactions.csv>"))?;
+    let options = Options::parse(std::env::args())?;
 
-    // Process transactions.
-    let results = {
-        // Create the channel and the transaction processor.
-        let (client_tx, client_rx) = mpsc::unbounded_channel();
-        let clients = TransactionProcessor::new(client_rx);
-
-        // Open the CSV file with the transactions to be processed.
-        let transaction_file = tokio::fs::File::open(transactions_file_path).await?;
-
-        // Construct a CVS reader to parse the file.
-        let mut reader = csv_async::AsyncReaderBuilder::new()
-            .trim(Trim::All) // Make sure we trim everything to avoid parsing errors.
-            .create_reader(transaction_file);
-
-        // Submit all transactions to be processed in parallel.
-        let mut records = reader.records();
-        while let Some(record) = records.next().await {
-            if let Ok(record) = record {
-                let transaction = record.deserialize::<Transaction>(None);
-                if let Ok(transaction) = transaction {
-                    client_tx.send(transaction)?;
-                }
+    if options.print_schema {
+        println!(
+            "{:#}",
+            output::schema(
+                options.output_format,
+                options.bool_format,
+                &options.output_columns()
+            )
+        );
+        return Ok(());
+    }
+
+    if let Some(count) = options.generate {
+        let transactions = generate::generate(count, options.seed);
+        match &options.output_path {
+            Some(output_path) => {
+                let output_file = tokio::fs::File::create(output_path).await?;
+                generate::write_transactions(output_file, &transactions).await?;
             }
+            None => generate::write_transactions(tokio::io::stdout(), &transactions).await?,
         }
+        return Ok(());
+    }
+
+    // Only check the inputs, without computing any balance.
+    if options.validate_only {
+        let validation = validate(&options).await?;
+        print!("{validation}");
+        ensure!(
+            validation.failures.is_empty(),
+            "The input has invalid rows."
+        );
+        return Ok(());
+    }
 
-        // We get the results future but we don't await for them here. We need to drop the 'client_tx' to
-        // inform the transaction processor that we don't have any more data to process. Otherwise will be
-        // in a deadlock state.
-        clients.get_results()
+    if let Some(warning) = options.config.worker_count_warning() {
+        eprintln!("{warning}");
     }
-    .await?;
+
+    // In service mode transactions come from HTTP requests instead of a file.
+    if let Some(serve_address) = &options.serve_address {
+        return service::serve(serve_address, options.config.clone(), options.bool_format).await;
+    }
+
+    // Each worker's clients are written as soon as it is done, without ever holding all of them.
+    if options.stream_output {
+        ensure!(
+            options.partition_output.is_none()
+                && options.output_format == OutputFormat::Csv
+                && !options.checksum
+                && options.locked_report_path.is_none()
+                && options.baseline_path.is_none()
+                && !options.verify_parallel
+                && options.tx_store_path.is_none(),
+            "The output is only streamed to a single CSV output, without anything else that needs every client."
+        );
+        let Submission {
+            submitter,
+            processor,
+            skipped,
+            ..
+        } = submit(&options).await?;
+        skipped.report();
+        let results = processor.get_results_stream(submitter);
+        let output_writer = match &options.output_path {
+            Some(output_path) => {
+                output_writer(tokio::fs::File::create(output_path).await?, &options)
+            }
+            None => output_writer(tokio::io::stdout(), &options),
+        };
+        return output::write_csv_stream(
+            output_writer,
+            results,
+            options.flush_per_row,
+            options.bool_format,
+            &options.output_columns(),
+        )
+        .await;
+    }
+
+    // Process transactions.
+    let results = process(&options).await?;
+
+    // Only the clients that differ from the baseline are written when there is one.
+    let baseline = match &options.baseline_path {
+        Some(baseline_path) => {
+            ensure!(
+                options.partition_output.is_none()
+                    && options.output_format == OutputFormat::Csv
+                    && !options.checksum,
+                "The baseline is only compared against for a single CSV output without checksum."
+            );
+            let baseline_file = tokio::fs::File::open(baseline_path).await?;
+            Some(output::read_csv(baseline_file).await?)
+        }
+        None => None,
+    };
 
     // Output results.
-    let mut writer = csv_async::AsyncWriter::from_writer(tokio::io::stdout());
-    writer
-        .write_record(&["client", "available", "held", "total", "locked"])
-        .await?;
-
-    for (_, client) in results {
-        writer
-            .write_record(&[
-                client.get_id().to_string(),
-                client.get_available().to_string(),
-                client.get_held().to_string(),
-                client.get_total().to_string(),
-                client.is_locked().to_string(),
-            ])
-            .await?;
+    let mut persisters: Vec<Box<dyn ResultPersister>> = Vec::new();
+    if let Some(locked_report_path) = &options.locked_report_path {
+        let locked_report_file = tokio::fs::File::create(locked_report_path).await?;
+        persisters.push(Box::new(LockedReportPersister::new(locked_report_file)));
     }
 
+    persisters.push(match (options.partition_output, options.output_format) {
+        (Some(partitions), _) => {
+            let output_dir = options
+                .output_dir
+                .as_ref()
+                .context("Partitioned output requires an --output-dir.")?;
+            Box::new(
+                PartitionedCsvPersister::new(output_dir, partitions, options.flush_per_row)
+                    .with_bool_format(options.bool_format)
+                    .with_columns(options.output_columns()),
+            )
+        }
+
+        (None, OutputFormat::Csv) if baseline.is_some() => {
+            let output_writer = match &options.output_path {
+                Some(output_path) => {
+                    output_writer(tokio::fs::File::create(output_path).await?, &options)
+                }
+                None => output_writer(tokio::io::stdout(), &options),
+            };
+            Box::new(
+                DeltaCsvPersister::new(output_writer, baseline.unwrap_or_default())
+                    .with_bool_format(options.bool_format)
+                    .with_columns(options.output_columns()),
+            )
+        }
+
+        (None, OutputFormat::Csv) => match &options.output_path {
+            Some(output_path) => {
+                let output_file = tokio::fs::File::create(output_path).await?;
+                let output_file = output_writer(output_file, &options);
+                if options.checksum {
+                    Box::new(
+                        ChecksumCsvPersister::new(output_file, output_path, options.flush_per_row)
+                            .with_bool_format(options.bool_format)
+                            .with_columns(options.output_columns()),
+                    )
+                } else {
+                    Box::new(
+                        CsvPersister::new(output_file, options.flush_per_row)
+                            .with_bool_format(options.bool_format)
+                            .with_columns(options.output_columns()),
+                    )
+                }
+            }
+
+            None if options.checksum => bail!("The checksum requires an --output path."),
+            None => Box::new(
+                CsvPersister::new(
+                    output_writer(tokio::io::stdout(), &options),
+                    options.flush_per_row,
+                )
+                .with_bool_format(options.bool_format)
+                .with_columns(options.output_columns()),
+            ),
+        },
+
+        (None, OutputFormat::Json) => {
+            ensure!(
+                !options.checksum,
+                "The checksum is only written for CSV output."
+            );
+            let output_writer = match &options.output_path {
+                Some(output_path) => {
+                    output_writer(tokio::fs::File::create(output_path).await?, &options)
+                }
+                None => output_writer(tokio::io::stdout(), &options),
+            };
+            Box::new(
+                JsonPersister::new(output_writer)
+                    .with_bool_format(options.bool_format)
+                    .with_columns(options.output_columns()),
+            )
+        }
+
+        (None, OutputFormat::Table) => {
+            ensure!(
+                !options.checksum,
+                "The checksum is only written for CSV output."
+            );
+            let output_writer = match &options.output_path {
+                Some(output_path) => {
+                    output_writer(tokio::fs::File::create(output_path).await?, &options)
+                }
+                None => output_writer(tokio::io::stdout(), &options),
+            };
+            Box::new(
+                TablePersister::new(output_writer)
+                    .with_bool_format(options.bool_format)
+                    .with_columns(options.output_columns()),
+            )
+        }
+
+        (None, OutputFormat::Parquet) => {
+            ensure!(
+                !options.checksum,
+                "The checksum is only written for CSV output."
+            );
+            let output_path = options
+                .output_path
+                .as_ref()
+                .context("The Parquet format requires an --output path.")?;
+            Box::new(ParquetPersister::new(output_path).with_columns(options.output_columns()))
+        }
+    });
+
+    TeePersister::new(persisters).persist(&results).await?;
+
     Ok(())
 }
 
+/// Writer of the CSV output, retrying transient failures, and buffered when a buffer size is given.
+fn output_writer<W>(writer: W, options: &Options) -> Box<dyn AsyncWrite + Unpin + Send>
+where
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let writer = RetryWriter::new(writer, options.write_retries);
+    match options.write_buffer_size {
+        Some(capacity) => Box::new(BufWriter::with_capacity(capacity, writer)),
+        None => Box::new(writer),
+    }
+}
+
+/// Process the transactions file and then, if given, the disputes file. The disputes are submitted strictly after
+/// every transaction of the main file, so the transactions they reference are always known.
+async fn process(options: &Options) -> Result<HashMap<u16, Client>> {
+    let Submission {
+        submitter,
+        processor,
+        reference,
+        skipped,
+    } = submit(options).await?;
+    skipped.report();
+
+    // Handing over the submitter closes the transactions channel, so the processor knows everything was submitted.
+    let (results, stored) = processor.get_results_with_store(submitter).await?;
+
+    if let Some(tx_store_path) = &options.tx_store_path {
+        let tx_store_file = tokio::fs::File::create(tx_store_path).await?;
+        output::write_tx_store(tx_store_file, &stored).await?;
+    }
+
+    if let Some(reference) = reference {
+        verify::compare(&reference, &results)?;
+    }
+
+    Ok(results)
+}
+
+/// Transactions submitted to a processor, whose results are still to be got, see `submit`.
+struct Submission {
+    submitter: TransactionSubmitter,
+    processor: TransactionProcessor,
+
+    /// Results of the single-threaded ledger, when verifying the parallel ones against it.
+    reference: Option<HashMap<u16, Client>>,
+    skipped: Skipped,
+}
+
+/// Records of the inputs that didn't make it into the balances.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Skipped {
+    /// Rows that couldn't be read as a transaction, such as of an unknown type.
+    parse_errors: usize,
+
+    /// Transactions the ledger rejected, such as for an invalid amount.
+    rejected: usize,
+}
+
+impl Skipped {
+    fn total(&self) -> usize {
+        self.parse_errors + self.rejected
+    }
+
+    /// Print the summary to stderr, if anything was skipped.
+    fn report(&self) {
+        if self.total() > 0 {
+            eprintln!("{self}");
+        }
+    }
+}
+
+impl std::fmt::Display for Skipped {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Skipped {} records ({} parse errors, {} rejected).",
+            self.total(),
+            self.parse_errors,
+            self.rejected
+        )
+    }
+}
+
+/// Submit every transaction of the inputs to a new processor, see `process`.
+async fn submit(options: &Options) -> Result<Submission> {
+    // Create the transaction processor, and the submitter of its transactions.
+    let config = Config {
+        collect_tx_store: options.tx_store_path.is_some(),
+        ..options.config.clone()
+    };
+    let (submitter, clients) = TransactionProcessor::with_config(config);
+
+    let mut snapshots = match options.snapshot_every {
+        Some(every) => {
+            let snapshot_dir = options
+                .snapshot_dir
+                .as_ref()
+                .context("Snapshots require a --snapshot-out directory.")?;
+            let persister = SnapshotPersister::new(snapshot_dir)
+                .with_bool_format(options.bool_format)
+                .with_columns(options.output_columns());
+
+            Some(Snapshots::new(every, persister))
+        }
+        None => None,
+    };
+
+    let deadline = options.deadline.map(|deadline| Instant::now() + deadline);
+
+    // Submit all transactions to be processed in parallel. When normalizing or verifying, the whole batch must be
+    // read first so redundant operations can be elided, or the batch replayed on the reference, before anything
+    // is submitted.
+    let batching = options.normalize || options.verify_parallel;
+    let mut batch = Vec::new();
+    let mut duplicates = 0;
+    let mut parse_errors = 0;
+    for path in inputs(options).await? {
+        let mut reader = open_reader(&path, options).await?;
+        if let Some(deadline) = deadline {
+            reader = reader.with_deadline(deadline);
+        }
+        if options.strict {
+            reader = reader.strict();
+        }
+
+        let mut previous: Option<Transaction> = None;
+        while let Some(transaction) = reader.next().await? {
+            if options.dedup_consecutive {
+                if previous
+                    .as_ref()
+                    .is_some_and(|previous| previous.is_same_row(&transaction))
+                {
+                    duplicates += 1;
+                    continue;
+                }
+
+                previous = Some(transaction.clone());
+            }
+
+            // A skipped transaction is never applied, so its amount can't fail the strict mode either.
+            if options.skip_tx.contains(&transaction.get_tx_id()) {
+                eprintln!(
+                    "Skipping transaction {} of client {}.",
+                    transaction.get_tx_id(),
+                    transaction.get_client_id()
+                );
+                continue;
+            }
+
+            ensure!(
+                !options.strict || !options.config.rejects_amount(&transaction),
+                "Transaction {} of client {} has an invalid amount: {}.",
+                transaction.get_tx_id(),
+                transaction.get_client_id(),
+                transaction
+                    .get_amount()
+                    .map_or("none".into(), |amount| amount.to_string())
+            );
+
+            if batching {
+                batch.push(transaction);
+            } else {
+                submitter.submit(transaction).await?;
+                if let Some(snapshots) = &mut snapshots {
+                    snapshots.submitted(&clients).await?;
+                }
+            }
+        }
+
+        parse_errors += reader.stats().malformed;
+        if reader.stats().truncated {
+            eprintln!("Processing truncated by the deadline, the results are partial.");
+            break;
+        }
+    }
+
+    if duplicates > 0 {
+        eprintln!("Dropped {duplicates} rows identical to the row before them.");
+    }
+
+    if options.normalize {
+        batch = normalize::normalize(batch);
+    }
+
+    let reference = options
+        .verify_parallel
+        .then(|| Ledger::process(options.config.clone(), batch.iter().cloned()));
+
+    for transaction in batch {
+        submitter.submit(transaction).await?;
+        if let Some(snapshots) = &mut snapshots {
+            snapshots.submitted(&clients).await?;
+        }
+    }
+
+    let stats = clients.flush_stats().await?;
+    if !stats.held_residual.is_zero() {
+        eprintln!(
+            "Resolves and chargebacks were clamped to held, leaving a residual of {}.",
+            stats.held_residual
+        );
+    }
+    if !stats.capped_shortfall.is_zero() {
+        eprintln!(
+            "Disputes were held up to the held cap, leaving {} unheld.",
+            stats.capped_shortfall
+        );
+    }
+    if stats.phantom_clients > 0 {
+        eprintln!(
+            "{} clients were only in transactions that didn't change them, such as disputes of unknown ones.",
+            stats.phantom_clients
+        );
+    }
+    if stats.panics > 0 {
+        eprintln!(
+            "{} transactions were skipped because applying them panicked.",
+            stats.panics
+        );
+    }
+    for divergence in &stats.divergences {
+        let account = match divergence.currency {
+            Some(currency) => format!("client {} in {currency}", divergence.client),
+            None => format!("client {}", divergence.client),
+        };
+        eprintln!(
+            "The total of {account} is {}, but its transactions add up to {}.",
+            divergence.total, divergence.recomputed
+        );
+    }
+    if stats.negative_totals > 0 {
+        match options.config.negative_total_policy {
+            NegativeTotalPolicy::Allow => {}
+            NegativeTotalPolicy::RejectTransaction => eprintln!(
+                "{} transactions were rejected because they left the total of their client negative.",
+                stats.negative_totals
+            ),
+            NegativeTotalPolicy::Warn => eprintln!(
+                "{} transactions left the total of their client negative.",
+                stats.negative_totals
+            ),
+        }
+    }
+    if stats.precision_losses > 0 {
+        eprintln!(
+            "{} deposits and withdrawals were rejected because the balance would have lost precision.",
+            stats.precision_losses
+        );
+    }
+    // Every transaction was submitted, so the disputes still pending will never get their deposit.
+    if stats.pending_disputes > 0 {
+        eprintln!(
+            "{} disputes were rejected because the deposit they refer to never came.",
+            stats.pending_disputes
+        );
+    }
+    if let Some(limit) = options
+        .config
+        .withdrawal_velocity
+        .filter(|_| stats.velocity_exceeded > 0)
+    {
+        eprintln!(
+            "{} withdrawals were rejected because they would have exceeded the velocity limit of {} within {:?}.",
+            stats.velocity_exceeded, limit.amount, limit.window
+        );
+    }
+    if stats.duplicates > 0 {
+        eprintln!(
+            "{} deposits and withdrawals were rejected because they reused the tx id of an earlier one.",
+            stats.duplicates
+        );
+    }
+
+    Ok(Submission {
+        submitter,
+        processor: clients,
+        reference,
+        skipped: Skipped {
+            parse_errors,
+            rejected: stats.rejected + stats.pending_disputes,
+        },
+    })
+}
+
+/// Snapshots of every client taken while the transactions are submitted, one after every `every` of them.
+struct Snapshots {
+    every: usize,
+    submitted: usize,
+    persister: SnapshotPersister,
+}
+
+impl Snapshots {
+    fn new(every: usize, persister: SnapshotPersister) -> Self {
+        Self {
+            every,
+            submitted: 0,
+            persister,
+        }
+    }
+
+    /// Count a submitted transaction, and persist a snapshot if it is time for one. The snapshot waits for every
+    /// transaction submitted so far to be applied.
+    async fn submitted(&mut self, clients: &TransactionProcessor) -> Result<()> {
+        self.submitted += 1;
+        if self.submitted.is_multiple_of(self.every) {
+            let snapshot = clients.flush_snapshot().await?;
+            self.persister.persist(&snapshot).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Every input, in the order they are read: the transactions file, the files of the input directory, and then
+/// the disputes file.
+async fn inputs(options: &Options) -> Result<Vec<String>> {
+    let mut inputs = Vec::from_iter(options.transactions_file_path.clone());
+    if let Some(input_dir) = &options.input_dir {
+        inputs.extend(input::list_input_dir(input_dir, options.input_order).await?);
+    }
+    inputs.extend(options.disputes_path.clone());
+
+    Ok(inputs)
+}
+
+/// Open an input and construct a reader to parse it, in the format and with the layout of the options.
+async fn open_reader(
+    path: &str,
+    options: &Options,
+) -> Result<TransactionReader<Box<dyn AsyncRead + Unpin + Send>>> {
+    let mut transaction_file = input::decompressed(open_input(path).await?).await?;
+    if let Some(records) = options.read_ahead {
+        transaction_file = Box::new(input::ReadAhead::new(transaction_file, records));
+    }
+    let reader = if options.positional {
+        TransactionReader::positional(transaction_file, options.input_format, !options.no_headers)
+    } else {
+        TransactionReader::with_format(transaction_file, options.input_format)
+    };
+
+    Ok(reader
+        .with_directions(options.directions.clone())
+        .with_invalid_utf8(options.invalid_utf8))
+}
+
+/// Rows of the inputs that are valid on their own, and where and why each of the others isn't.
+struct Validation {
+    valid: usize,
+    failures: Vec<String>,
+}
+
+impl std::fmt::Display for Validation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{} valid rows, {} invalid rows.",
+            self.valid,
+            self.failures.len()
+        )?;
+        for failure in &self.failures {
+            writeln!(f, "{failure}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Read every input and check each row on its own, see `Transaction::validate`, without applying any.
+async fn validate(options: &Options) -> Result<Validation> {
+    let mut validation = Validation {
+        valid: 0,
+        failures: Vec::new(),
+    };
+
+    for path in inputs(options).await? {
+        // Strict, so a malformed row is named, and reading goes on after it.
+        let mut reader = open_reader(&path, options).await?.strict();
+        loop {
+            let transaction = match reader.next().await {
+                Ok(Some(transaction)) => transaction,
+                Ok(None) => break,
+                Err(error) if error.is::<MalformedRecord>() => {
+                    validation.failures.push(format!("{path}: {error}"));
+                    continue;
+                }
+                Err(error) => return Err(error),
+            };
+
+            match transaction.validate() {
+                Ok(()) => validation.valid += 1,
+                Err(error) => validation.failures.push(format!(
+                    "{path}: Invalid record at line {}: {error}",
+                    reader.line()
+                )),
+            }
+        }
+    }
+
+    Ok(validation)
+}
+
+/// Open an input, either a local file, an `s3://bucket/key` object or the standard input.
+async fn open_input(path: &str) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+    if path == input::STDIN_PATH {
+        return Ok(Box::new(tokio::io::stdin()));
+    }
+
+    if let Some(location) = S3Location::parse(path) {
+        #[cfg(feature = "aws")]
+        {
+            use rct::s3::ObjectStore;
+            return rct::s3::S3ObjectStore::from_env()
+                .await
+                .get_object(&location)
+                .await;
+        }
+
+        #[cfg(not(feature = "aws"))]
+        anyhow::bail!("Reading {location:?} requires building with the aws feature.");
+    }
+
+    Ok(Box::new(tokio::fs::File::open(path).await?))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::transaction::TransactionType;
+    use futures::{StreamExt, TryStreamExt};
+    use rct::{
+        ledger::{ClientSeed, DisputableRecord, DisputeState, OpenDispute, RejectionReason},
+        transaction::{Transaction, TransactionType},
+    };
     use rust_decimal::Decimal;
+    use std::{io::Write, sync::Arc};
 
     /// Test if the system is capable of performing a valid deposit.
     #[tokio::test]
     async fn test_valid_deposit() {
-        let tp = {
-            let (tp_tx, tp_rx) = mpsc::unbounded_channel();
-            let tp = TransactionProcessor::new(tp_rx);
+        let (submitter, tp) = TransactionProcessor::new();
 
-            // Deposit 10 credits.
-            tp_tx
-                .send(Transaction::new(
-                    TransactionType::Deposit,
-                    1,
-                    1,
-                    Some(Decimal::new(10, 0)),
-                ))
-                .unwrap();
+        // Deposit 10 credits.
+        submitter
+            .submit(Transaction::new(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Decimal::new(10, 0)),
+            ))
+            .await
+            .unwrap();
 
-            tp
-        };
-
-        let clients = tp.get_results().await.unwrap();
-        let client = clients.get(&1).unwrap();
+        let clients = tp.get_results(submitter).await.unwrap();
 
         // Check if we have the 10 credits we deposited.
-        assert_eq!(client.get_total(), Decimal::new(10, 0));
+        let mut expected = Client::new(1);
+        expected.add_available(Decimal::new(10, 0)).unwrap();
+
+        assert_eq!(clients.get(&1), Some(&expected));
     }
 
     /// Test if the system is capable of performing a withdrawal.
     #[tokio::test]
     async fn test_valid_withdraw() {
-        let tp = {
-            let (tp_tx, tp_rx) = mpsc::unbounded_channel();
-            let tp = TransactionProcessor::new(tp_rx);
+        let (submitter, tp) = TransactionProcessor::new();
 
-            // We deposit 10 credits.
-            tp_tx
-                .send(Transaction::new(
-                    TransactionType::Deposit,
-                    1,
-                    1,
-                    Some(Decimal::new(10, 0)),
-                ))
-                .unwrap();
-
-            // We withdraw 9 credits
-            tp_tx
-                .send(Transaction::new(
-                    TransactionType::Withdrawal,
-                    1,
-                    2,
-                    Some(Decimal::new(9, 0)),
-                ))
-                .unwrap();
+        // We deposit 10 credits.
+        submitter
+            .submit(Transaction::new(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Decimal::new(10, 0)),
+            ))
+            .await
+            .unwrap();
 
-            tp
-        };
+        // We withdraw 9 credits
+        submitter
+            .submit(Transaction::new(
+                TransactionType::Withdrawal,
+                1,
+                2,
+                Some(Decimal::new(9, 0)),
+            ))
+            .await
+            .unwrap();
 
-        let clients = tp.get_results().await.unwrap();
+        let clients = tp.get_results(submitter).await.unwrap();
         let client = clients.get(&1).unwrap();
 
         assert_eq!(client.get_total(), Decimal::new(1, 0)); // We should have 1 credit left.
@@ -144,24 +741,20 @@ mod tests {
     /// Test if the system is handles invalid deposits.
     #[tokio::test]
     async fn test_invalid_deposit() {
-        let tp = {
-            let (tp_tx, tp_rx) = mpsc::unbounded_channel();
-            let tp = TransactionProcessor::new(tp_rx);
+        let (submitter, tp) = TransactionProcessor::new();
 
-            // We try to deposit a negative 10 credits.
-            tp_tx
-                .send(Transaction::new(
-                    TransactionType::Deposit,
-                    1,
-                    1,
-                    Some(Decimal::new(-10, 0)),
-                ))
-                .unwrap();
-
-            tp
-        };
+        // We try to deposit a negative 10 credits.
+        submitter
+            .submit(Transaction::new(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Decimal::new(-10, 0)),
+            ))
+            .await
+            .unwrap();
 
-        let clients = tp.get_results().await.unwrap();
+        let clients = tp.get_results(submitter).await.unwrap();
         let client = clients.get(&1).unwrap();
 
         assert_eq!(client.get_total(), Decimal::new(0, 0)); // We should still have zero credits.
@@ -171,34 +764,31 @@ mod tests {
     /// Test how the system handles an invalid withdrawal.
     #[tokio::test]
     async fn test_invalid_withdraw() {
-        let tp = {
-            let (tp_tx, tp_rx) = mpsc::unbounded_channel();
-            let tp = TransactionProcessor::new(tp_rx);
-
-            // Deposit 10 credits.
-            tp_tx
-                .send(Transaction::new(
-                    TransactionType::Deposit,
-                    1,
-                    1,
-                    Some(Decimal::new(10, 0)),
-                ))
-                .unwrap();
+        let (submitter, tp) = TransactionProcessor::new();
 
-            // Try the withdrawal 11 credits!
-            tp_tx
-                .send(Transaction::new(
-                    TransactionType::Withdrawal,
-                    1,
-                    2,
-                    Some(Decimal::new(11, 0)),
-                ))
-                .unwrap();
+        // Deposit 10 credits.
+        submitter
+            .submit(Transaction::new(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Decimal::new(10, 0)),
+            ))
+            .await
+            .unwrap();
 
-            tp
-        };
+        // Try the withdrawal 11 credits!
+        submitter
+            .submit(Transaction::new(
+                TransactionType::Withdrawal,
+                1,
+                2,
+                Some(Decimal::new(11, 0)),
+            ))
+            .await
+            .unwrap();
 
-        let clients = tp.get_results().await.unwrap();
+        let clients = tp.get_results(submitter).await.unwrap();
         let client = clients.get(&1).unwrap();
 
         assert_eq!(client.get_total(), Decimal::new(10, 0)); // We should have the initial amount.
@@ -208,104 +798,1150 @@ mod tests {
     /// Test a scenario where a dispute was resolved.
     #[tokio::test]
     async fn test_resolved_dispute() {
-        let tp = {
-            let (tp_tx, tp_rx) = mpsc::unbounded_channel();
-            let tp = TransactionProcessor::new(tp_rx);
+        let (submitter, tp) = TransactionProcessor::new();
+
+        // Deposit 10 credits.
+        submitter
+            .submit(Transaction::new(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Decimal::new(10, 0)),
+            ))
+            .await
+            .unwrap();
+
+        // Deposit 5 more.
+        submitter
+            .submit(Transaction::new(
+                TransactionType::Deposit,
+                1,
+                2,
+                Some(Decimal::new(5, 0)),
+            ))
+            .await
+            .unwrap();
+
+        // Dispute the last transaction.
+        submitter
+            .submit(Transaction::new(TransactionType::Dispute, 1, 2, None))
+            .await
+            .unwrap();
+
+        // Resolve the last transaction.
+        submitter
+            .submit(Transaction::new(TransactionType::Resolve, 1, 2, None))
+            .await
+            .unwrap();
+
+        let clients = tp.get_results(submitter).await.unwrap();
+        let client = clients.get(&1).unwrap();
+
+        assert_eq!(client.get_total(), Decimal::new(15, 0)); // We should have all deposited credits.
+        assert!(!client.is_locked()); // The account should not be locked.
+    }
+
+    /// Test a scenario where the client will be locked and all subsequent transactions ignored.
+    #[tokio::test]
+    async fn test_locked_down() {
+        let (submitter, tp) = TransactionProcessor::new();
+
+        // Deposit 10 credits.
+        submitter
+            .submit(Transaction::new(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Decimal::new(10, 0)),
+            ))
+            .await
+            .unwrap();
 
-            // Deposit 10 credits.
-            tp_tx
-                .send(Transaction::new(
+        // Deposit 5 more.
+        submitter
+            .submit(Transaction::new(
+                TransactionType::Deposit,
+                1,
+                2,
+                Some(Decimal::new(5, 0)),
+            ))
+            .await
+            .unwrap();
+
+        // Dispute the first deposit (10 credits).
+        submitter
+            .submit(Transaction::new(TransactionType::Dispute, 1, 1, None))
+            .await
+            .unwrap();
+
+        // Chargeback the dispute.
+        submitter
+            .submit(Transaction::new(TransactionType::Chargeback, 1, 1, None))
+            .await
+            .unwrap();
+
+        // This withdrawal should fail because the client account should be locked by now.
+        submitter
+            .submit(Transaction::new(
+                TransactionType::Withdrawal,
+                1,
+                3,
+                Some(Decimal::new(5, 0)),
+            ))
+            .await
+            .unwrap();
+
+        let clients = tp.get_results(submitter).await.unwrap();
+        let client = clients.get(&1).unwrap();
+
+        assert_eq!(client.get_total(), Decimal::new(5, 0));
+        assert!(client.is_locked());
+    }
+
+    /// Test that a snapshot is a point-in-time view that excludes transactions submitted after it.
+    #[tokio::test]
+    async fn test_flush_snapshot() {
+        let (submitter, tp) = TransactionProcessor::new();
+
+        // Deposit 10 credits to two clients.
+        for client in 1..=2 {
+            submitter
+                .submit(Transaction::new(
                     TransactionType::Deposit,
-                    1,
-                    1,
+                    client,
+                    client as u32,
                     Some(Decimal::new(10, 0)),
                 ))
+                .await
                 .unwrap();
+        }
+
+        let snapshot = tp.flush_snapshot().await.unwrap();
 
-            // Deposit 5 more.
-            tp_tx
-                .send(Transaction::new(
+        // Deposit 5 more to the same clients and 5 to a new one.
+        for client in 1..=3 {
+            submitter
+                .submit(Transaction::new(
                     TransactionType::Deposit,
-                    1,
-                    2,
+                    client,
+                    10 + client as u32,
                     Some(Decimal::new(5, 0)),
                 ))
+                .await
                 .unwrap();
+        }
+        let clients = tp.get_results(submitter).await.unwrap();
 
-            // Dispute the last transaction.
-            tp_tx
-                .send(Transaction::new(TransactionType::Dispute, 1, 2, None))
-                .unwrap();
+        // The snapshot only has the first batch.
+        assert_eq!(snapshot.len(), 2);
+        assert_eq!(snapshot.get(&1).unwrap().get_total(), Decimal::new(10, 0));
+        assert_eq!(snapshot.get(&2).unwrap().get_total(), Decimal::new(10, 0));
+
+        // The final results have both.
+        assert_eq!(clients.len(), 3);
+        assert_eq!(clients.get(&1).unwrap().get_total(), Decimal::new(15, 0));
+        assert_eq!(clients.get(&3).unwrap().get_total(), Decimal::new(5, 0));
+    }
+
+    /// Test that a snapshot requested while the channel is full waits for its turn behind the transactions
+    /// already submitted, and isn't overtaken by the ones submitted after it.
+    #[tokio::test]
+    async fn test_flush_snapshot_full_channel() {
+        const CAPACITY: u32 = 500;
+        let (submitter, tp) = TransactionProcessor::builder()
+            .workers(1)
+            .channel_capacity(CAPACITY as usize)
+            .build();
+        let deposit =
+            |tx_id| Transaction::new(TransactionType::Deposit, 1, tx_id, Some(Decimal::ONE));
+
+        // The load balancer doesn't run in between, so these fill the channel.
+        for tx_id in 1..=CAPACITY {
+            submitter.submit(deposit(tx_id)).await.unwrap();
+        }
+
+        let (snapshot, ()) = tokio::join!(tp.flush_snapshot(), async {
+            for tx_id in CAPACITY + 1..=2 * CAPACITY {
+                submitter.submit(deposit(tx_id)).await.unwrap();
+            }
+        });
+        assert_eq!(snapshot.unwrap()[&1].get_total(), Decimal::from(CAPACITY));
+
+        let clients = tp.get_results(submitter).await.unwrap();
+        assert_eq!(clients[&1].get_total(), Decimal::from(2 * CAPACITY));
+    }
+
+    /// Test that snapshots taken by a monitor while another task submits are each a point in the stream across the
+    /// workers, and don't disturb the processing.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_monitor_snapshots() {
+        const COUNT: u32 = 5000;
+        let (submitter, tp) = TransactionProcessor::builder().workers(2).build();
+        let tp = Arc::new(tp);
 
-            // Resolve the last transaction.
-            tp_tx
-                .send(Transaction::new(TransactionType::Resolve, 1, 2, None))
+        // Clients 1 and 2 route to different workers and get a deposit each in turn.
+        let submitting = tokio::spawn(async move {
+            for tx_id in 0..2 * COUNT {
+                let client = 1 + (tx_id % 2) as u16;
+                let deposit =
+                    Transaction::new(TransactionType::Deposit, client, tx_id, Some(Decimal::ONE));
+                submitter.submit(deposit).await.unwrap();
+            }
+
+            submitter
+        });
+
+        let total = |snapshot: &HashMap<u16, Client>, id| {
+            snapshot.get(&id).map(Client::get_total).unwrap_or_default()
+        };
+        let mut last = Decimal::ZERO;
+        while !submitting.is_finished() {
+            let snapshot = tp.flush_snapshot().await.unwrap();
+            let difference = total(&snapshot, 1) - total(&snapshot, 2);
+            assert!(difference == Decimal::ZERO || difference == Decimal::ONE);
+            assert!(total(&snapshot, 1) >= last);
+            last = total(&snapshot, 1);
+        }
+
+        let submitter = submitting.await.unwrap();
+        let clients = Arc::into_inner(tp)
+            .unwrap()
+            .get_results(submitter)
+            .await
+            .unwrap();
+        assert_eq!(clients[&1].get_total(), Decimal::from(COUNT));
+        assert_eq!(clients[&2].get_total(), Decimal::from(COUNT));
+    }
+
+    /// Test querying the stored transactions of a client after two deposits and a dispute of one of them.
+    #[tokio::test]
+    async fn test_query_transactions() {
+        let (submitter, tp) = TransactionProcessor::new();
+
+        for transaction in [
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(Decimal::new(10, 0))),
+            Transaction::new(TransactionType::Deposit, 1, 2, Some(Decimal::new(5, 0))),
+            Transaction::new(TransactionType::Deposit, 2, 3, Some(Decimal::new(7, 0))),
+            Transaction::new(TransactionType::Dispute, 1, 2, None),
+        ] {
+            submitter.submit(transaction).await.unwrap();
+        }
+
+        let records = tp.query_transactions(1).await.unwrap();
+        assert_eq!(
+            records,
+            [
+                DisputableRecord {
+                    tx_id: 1,
+                    transaction_type: TransactionType::Deposit,
+                    amount: Decimal::new(10, 0),
+                    disputed: false,
+                },
+                DisputableRecord {
+                    tx_id: 2,
+                    transaction_type: TransactionType::Deposit,
+                    amount: Decimal::new(5, 0),
+                    disputed: true,
+                },
+            ]
+        );
+
+        // A client without transactions has nothing stored.
+        assert!(tp.query_transactions(9).await.unwrap().is_empty());
+    }
+
+    /// Test querying the dispute state of a deposit after each step of its lifecycle.
+    #[tokio::test]
+    async fn test_dispute_state() {
+        let (submitter, tp) = TransactionProcessor::new();
+
+        submitter
+            .submit(Transaction::new(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Decimal::new(10, 0)),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(
+            tp.dispute_state(1, 1).await.unwrap(),
+            Some(DisputeState::Open)
+        );
+
+        for (transaction_type, state) in [
+            (TransactionType::Dispute, DisputeState::Disputed),
+            (TransactionType::Resolve, DisputeState::Resolved),
+            (TransactionType::Dispute, DisputeState::Disputed),
+            (TransactionType::Chargeback, DisputeState::ChargedBack),
+        ] {
+            submitter
+                .submit(Transaction::new(transaction_type, 1, 1, None))
+                .await
                 .unwrap();
+            assert_eq!(tp.dispute_state(1, 1).await.unwrap(), Some(state));
+        }
+
+        // Unknown transactions, or of another client, have no state.
+        assert_eq!(tp.dispute_state(1, 2).await.unwrap(), None);
+        assert_eq!(tp.dispute_state(2, 1).await.unwrap(), None);
+    }
 
-            tp
+    /// Test that a client resumed with an open dispute is locked by a chargeback of it.
+    #[tokio::test]
+    async fn test_seeded_open_dispute() {
+        let seed = ClientSeed {
+            client: Client::with_balances(1, Decimal::new(5, 0), Decimal::new(10, 0)),
+            open_disputes: vec![OpenDispute {
+                tx_id: 1,
+                amount: Decimal::new(10, 0),
+            }],
+        };
+        let untouched = ClientSeed {
+            client: Client::with_balances(2, Decimal::new(3, 0), Decimal::ZERO),
+            open_disputes: Vec::new(),
         };
 
-        let clients = tp.get_results().await.unwrap();
+        let (submitter, tp) =
+            TransactionProcessor::with_seed(Default::default(), vec![seed, untouched]);
+
+        submitter
+            .submit(Transaction::new(TransactionType::Chargeback, 1, 1, None))
+            .await
+            .unwrap();
+
+        let clients = tp.get_results(submitter).await.unwrap();
+
         let client = clients.get(&1).unwrap();
+        assert!(client.is_locked());
+        assert_eq!(client.get_available(), Decimal::new(5, 0));
+        assert_eq!(client.get_held(), Decimal::ZERO);
 
-        assert_eq!(client.get_total(), Decimal::new(15, 0)); // We should have all deposited credits.
-        assert!(!client.is_locked()); // The account should not be locked.
+        // A seeded client without transactions is still in the results.
+        assert_eq!(clients.get(&2).unwrap().get_available(), Decimal::new(3, 0));
     }
 
-    /// Test a scenario where the client will be locked and all subsequent transactions ignored.
+    /// Test that only the workers the clients route to are spawned.
     #[tokio::test]
-    async fn test_locked_down() {
-        let tp = {
-            let (tp_tx, tp_rx) = mpsc::unbounded_channel();
-            let tp = TransactionProcessor::new(tp_rx);
+    async fn test_lazy_workers() {
+        let config = Config {
+            workers: Some(rct::config::available_cpus() * rct::config::MAX_WORKERS_PER_CPU),
+            ..Default::default()
+        };
+        let (submitter, tp) = TransactionProcessor::with_config(config);
 
-            // Deposit 10 credits.
-            tp_tx
-                .send(Transaction::new(
+        // Clients 1 and 2 route to different workers, whatever the worker count.
+        for client in 1..=2 {
+            submitter
+                .submit(Transaction::new(
                     TransactionType::Deposit,
-                    1,
-                    1,
+                    client,
+                    client as u32,
                     Some(Decimal::new(10, 0)),
                 ))
+                .await
                 .unwrap();
+        }
+
+        // The snapshot only returns once both transactions reached their worker.
+        let snapshot = tp.flush_snapshot().await.unwrap();
+        assert_eq!(snapshot.len(), 2);
+        assert_eq!(tp.spawned_workers(), 2);
+        assert_eq!(tp.get_results(submitter).await.unwrap().len(), 2);
+    }
+
+    /// Test that one worker, as given with `--workers`, and four workers end with the same balances.
+    #[tokio::test]
+    async fn test_worker_counts() {
+        let transactions = generate::generate(5000, 11);
+        let process_with = |workers: &str| {
+            let options =
+                Options::parse(["rct", "--workers", workers, "-"].map(String::from)).unwrap();
+            let (submitter, processor) = TransactionProcessor::with_config(options.config);
+            let transactions = &transactions;
+            async move {
+                for transaction in transactions.iter().cloned() {
+                    submitter.submit(transaction).await.unwrap();
+                }
+
+                let by_worker = processor.get_results_by_worker(submitter).await.unwrap();
+                (
+                    by_worker.len(),
+                    by_worker.into_iter().flatten().collect::<HashMap<_, _>>(),
+                )
+            }
+        };
+
+        let (workers, single) = process_with("1").await;
+        assert_eq!(workers, 1);
+        assert!(!single.is_empty());
+
+        let (workers, parallel) = process_with("4").await;
+        assert_eq!(workers, 4);
+        assert!(verify::diff(&single, &parallel).is_empty());
+    }
+
+    /// Test that submitting waits once the transactions channel is full, and that many times its capacity still
+    /// ends with the balances of the single-threaded ledger.
+    #[tokio::test]
+    async fn test_bounded_channel() {
+        use futures::FutureExt;
+
+        let config = Config {
+            channel_capacity: Some(4),
+            workers: Some(2),
+            ..Default::default()
+        };
+        let transactions = generate::generate(2000, 5);
+        let reference = Ledger::process(config.clone(), transactions.iter().cloned());
+
+        // The processor only runs when the test yields, so the channel fills up and the next submit waits.
+        let (submitter, processor) = TransactionProcessor::with_config(config);
+        let mut transactions = transactions.into_iter();
+        for transaction in transactions.by_ref().take(4) {
+            submitter
+                .submit(transaction)
+                .now_or_never()
+                .unwrap()
+                .unwrap();
+        }
+        let next = transactions.next().unwrap();
+        assert!(submitter.submit(next.clone()).now_or_never().is_none());
+
+        submitter.submit(next).await.unwrap();
+        for transaction in transactions {
+            submitter.submit(transaction).await.unwrap();
+        }
+
+        let clients = processor.get_results(submitter).await.unwrap();
+        assert!(verify::diff(&reference, &clients).is_empty());
+    }
 
-            // Deposit 5 more.
-            tp_tx
-                .send(Transaction::new(
+    /// Test that a huge number of workers is clamped and still processes everything.
+    #[tokio::test]
+    async fn test_clamped_workers() {
+        let (submitter, tp) = TransactionProcessor::with_config(Config {
+            workers: Some(100000),
+            ..Default::default()
+        });
+
+        for client in 0..1000 {
+            submitter
+                .submit(Transaction::new(
                     TransactionType::Deposit,
-                    1,
-                    2,
-                    Some(Decimal::new(5, 0)),
+                    client,
+                    client as u32,
+                    Some(Decimal::new(10, 0)),
                 ))
+                .await
                 .unwrap();
+        }
 
-            // Dispute the first deposit (10 credits).
-            tp_tx
-                .send(Transaction::new(TransactionType::Dispute, 1, 1, None))
-                .unwrap();
+        let clients = tp.get_results(submitter).await.unwrap();
+
+        assert_eq!(clients.len(), 1000);
+    }
+
+    /// Test that the transactions of a client keep their order, by comparing thousands of interleaved deposits and
+    /// dispute lifecycles against the single-threaded ledger. With and without priorities, which must not reorder
+    /// the transactions of a client.
+    #[tokio::test]
+    async fn test_client_ordering() {
+        let mut transactions = Vec::new();
+        for tx in 1..=5000 {
+            // The client under test, plus some noise from other clients competing for the workers.
+            transactions.push(Transaction::new(
+                TransactionType::Deposit,
+                1,
+                tx,
+                Some(Decimal::new(tx as i64 % 97 + 1, 2)),
+            ));
+            transactions.push(Transaction::new(
+                TransactionType::Deposit,
+                2 + (tx % 50) as u16,
+                100000 + tx,
+                Some(Decimal::new(1, 0)),
+            ));
+
+            // Open a dispute on every other deposit and settle it a few transactions later.
+            if tx % 2 == 0 {
+                transactions.push(Transaction::new(TransactionType::Dispute, 1, tx, None));
+            }
+
+            if tx % 2 == 0 && tx > 6 {
+                let settlement = match tx % 1000 {
+                    0 => TransactionType::Chargeback,
+                    _ => TransactionType::Resolve,
+                };
+                transactions.push(Transaction::new(settlement, 1, tx - 6, None));
+            }
+        }
+
+        let reference = Ledger::process(Default::default(), transactions.iter().cloned());
+        assert!(reference.get(&1).unwrap().is_locked());
+
+        let prioritized = Config {
+            priorities: [
+                (TransactionType::Chargeback, 10),
+                (TransactionType::Dispute, 5),
+            ]
+            .into(),
+            ..Default::default()
+        };
+
+        for config in [Config::default(), prioritized] {
+            let (submitter, tp) = TransactionProcessor::with_config(config);
+
+            for transaction in transactions.iter().cloned() {
+                submitter.submit(transaction).await.unwrap();
+            }
+
+            let clients = tp.get_results(submitter).await.unwrap();
+
+            assert!(verify::compare(&reference, &clients).is_ok());
+        }
+    }
+
+    /// Test that a disputes file is applied after the main file, charging back one of its deposits.
+    #[tokio::test]
+    async fn test_disputes_file() {
+        let mut transactions_file = tempfile::NamedTempFile::new().unwrap();
+        write!(
+            transactions_file,
+            "type, client, tx, amount\n\
+             deposit, 1, 1, 10.0\n\
+             deposit, 2, 2, 5.0\n\
+             deposit, 1, 3, 2.5\n"
+        )
+        .unwrap();
+
+        let mut disputes_file = tempfile::NamedTempFile::new().unwrap();
+        write!(
+            disputes_file,
+            "type, client, tx, amount\n\
+             dispute, 1, 1,\n\
+             chargeback, 1, 1,\n"
+        )
+        .unwrap();
+
+        let options = Options {
+            transactions_file_path: Some(transactions_file.path().to_str().unwrap().into()),
+            disputes_path: Some(disputes_file.path().to_str().unwrap().into()),
+            ..Default::default()
+        };
+
+        let clients = process(&options).await.unwrap();
+
+        let client = clients.get(&1).unwrap();
+        assert!(client.is_locked());
+        assert_eq!(client.get_total(), Decimal::new(25, 1));
+        assert!(!clients.get(&2).unwrap().is_locked());
+    }
+
+    /// Test that a skipped deposit, and the rows referring to it, never reach the workers.
+    #[tokio::test]
+    async fn test_skip_tx() {
+        let mut transactions_file = tempfile::NamedTempFile::new().unwrap();
+        write!(
+            transactions_file,
+            "type, client, tx, amount\n\
+             deposit, 1, 1, 10.0\n\
+             deposit, 1, 2, 99.0\n\
+             dispute, 1, 2,\n\
+             deposit, 1, 3, 2.5\n"
+        )
+        .unwrap();
+
+        let options = Options {
+            transactions_file_path: Some(transactions_file.path().to_str().unwrap().into()),
+            skip_tx: [2].into(),
+            ..Default::default()
+        };
+
+        let clients = process(&options).await.unwrap();
+
+        let client = clients.get(&1).unwrap();
+        assert_eq!(client.get_available(), Decimal::new(125, 1));
+        assert_eq!(client.get_held(), Decimal::ZERO);
+    }
+
+    /// Test that the reject hook is called with the reason of an over-withdrawal, without keeping the rejection.
+    #[tokio::test]
+    async fn test_on_reject() {
+        let rejected = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let config = Config::default().on_reject({
+            let rejected = rejected.clone();
+            move |transaction: &Transaction, reason| {
+                rejected
+                    .lock()
+                    .unwrap()
+                    .push((transaction.get_tx_id(), reason))
+            }
+        });
+
+        let (submitter, processor) = TransactionProcessor::with_config(config);
+        for transaction in [
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(Decimal::new(5, 0))),
+            Transaction::new(TransactionType::Withdrawal, 1, 2, Some(Decimal::new(8, 0))),
+        ] {
+            submitter.submit(transaction).await.unwrap();
+        }
+        let (_, _, kept) = processor.finalize(submitter).await.unwrap();
+
+        assert_eq!(
+            *rejected.lock().unwrap(),
+            [(2, RejectionReason::InsufficientFunds)]
+        );
+        // Only the hook is called, the rejections aren't kept without `collect_rejections`.
+        assert!(kept.is_empty());
+    }
+
+    /// Test that the CSV files of a directory are processed in name order as one stream, skipping other files.
+    #[tokio::test]
+    async fn test_input_dir() {
+        let directory = tempfile::tempdir().unwrap();
+        std::fs::write(
+            directory.path().join("1.csv"),
+            "type, client, tx, amount\ndeposit, 1, 1, 10.0\n",
+        )
+        .unwrap();
+        std::fs::write(
+            directory.path().join("2.csv"),
+            "type, client, tx, amount\ndispute, 1, 1,\n",
+        )
+        .unwrap();
+        std::fs::write(directory.path().join("notes.txt"), "not a transaction").unwrap();
+
+        let options = Options {
+            input_dir: Some(directory.path().to_str().unwrap().into()),
+            ..Default::default()
+        };
 
-            // Chargeback the dispute.
-            tp_tx
-                .send(Transaction::new(TransactionType::Chargeback, 1, 1, None))
+        let clients = process(&options).await.unwrap();
+
+        // The dispute in the second file found the deposit of the first one.
+        assert_eq!(clients.get(&1).unwrap().get_held(), Decimal::TEN);
+
+        let empty = tempfile::tempdir().unwrap();
+        let options = Options {
+            input_dir: Some(empty.path().to_str().unwrap().into()),
+            ..Default::default()
+        };
+        assert!(process(&options).await.unwrap().is_empty());
+    }
+
+    /// Test that a gzipped input gives the same balances as its uncompressed twin, whatever its name, and that a
+    /// gzipped file of an input directory is read too.
+    #[tokio::test]
+    async fn test_gzip_input() {
+        use async_compression::tokio::write::GzipEncoder;
+        use tokio::io::AsyncWriteExt;
+
+        let mut csv = Vec::new();
+        generate::write_transactions(&mut csv, &generate::generate(500, 3))
+            .await
+            .unwrap();
+        let mut encoder = GzipEncoder::new(Vec::new());
+        encoder.write_all(&csv).await.unwrap();
+        encoder.shutdown().await.unwrap();
+        let gzipped = encoder.into_inner();
+
+        let directory = tempfile::tempdir().unwrap();
+        std::fs::write(directory.path().join("plain.csv"), &csv).unwrap();
+        std::fs::write(directory.path().join("gzipped.csv.gz"), &gzipped).unwrap();
+        std::fs::write(directory.path().join("gzipped.csv"), &gzipped).unwrap();
+
+        let process_file = |name: &str| {
+            let options = Options {
+                transactions_file_path: Some(directory.path().join(name).to_str().unwrap().into()),
+                ..Default::default()
+            };
+            async move { process(&options).await.unwrap() }
+        };
+        let plain = process_file("plain.csv").await;
+        assert!(!plain.is_empty());
+        assert!(verify::diff(&plain, &process_file("gzipped.csv.gz").await).is_empty());
+        assert!(verify::diff(&plain, &process_file("gzipped.csv").await).is_empty());
+
+        let files = input::list_input_dir(directory.path(), input::InputOrder::Name)
+            .await
+            .unwrap();
+        assert_eq!(files.len(), 3);
+    }
+
+    /// Test that rows that can't be read, and transactions the ledger rejects, are counted as skipped.
+    #[tokio::test]
+    async fn test_skipped_summary() {
+        let mut transactions_file = tempfile::NamedTempFile::new().unwrap();
+        write!(
+            transactions_file,
+            "type, client, tx, amount\n\
+             deposit, 1, 1, 10.0\n\
+             transfer, 1, 2, 1.0\n\
+             deposit, one, 3, 1.0\n\
+             withdrawal, 1, 4, 20.0\n\
+             deposit, 1, 5, -1.0\n\
+             dispute, 1, 99,\n\
+             withdrawal, 1, 6, 1.0\n"
+        )
+        .unwrap();
+
+        let options = Options {
+            transactions_file_path: Some(transactions_file.path().to_str().unwrap().into()),
+            ..Default::default()
+        };
+
+        let submission = submit(&options).await.unwrap();
+        assert_eq!(
+            submission.skipped,
+            Skipped {
+                parse_errors: 2,
+                rejected: 3,
+            }
+        );
+        assert_eq!(
+            submission.skipped.to_string(),
+            "Skipped 5 records (2 parse errors, 3 rejected)."
+        );
+
+        let clients = submission
+            .processor
+            .get_results(submission.submitter)
+            .await
+            .unwrap();
+        assert_eq!(clients[&1].get_available(), Decimal::new(9, 0));
+    }
+
+    /// Test that a snapshot is written every few transactions, each one with the balances up to then.
+    #[tokio::test]
+    async fn test_snapshot_every() {
+        let mut input = tempfile::NamedTempFile::new().unwrap();
+        writeln!(input, "type, client, tx, amount").unwrap();
+        for tx in 1..=7 {
+            writeln!(input, "deposit, 1, {tx}, 1.0").unwrap();
+        }
+        let snapshot_dir = tempfile::tempdir().unwrap();
+
+        let options = Options {
+            transactions_file_path: Some(input.path().to_str().unwrap().into()),
+            snapshot_every: Some(2),
+            snapshot_dir: Some(snapshot_dir.path().to_str().unwrap().into()),
+            ..Default::default()
+        };
+        let clients = process(&options).await.unwrap();
+        assert_eq!(clients.get(&1).unwrap().get_total(), Decimal::new(7, 0));
+
+        // The seventh deposit doesn't complete another snapshot.
+        let mut snapshots = std::fs::read_dir(snapshot_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect::<Vec<_>>();
+        snapshots.sort();
+        assert_eq!(snapshots.len(), 3);
+
+        let totals = snapshots
+            .iter()
+            .map(|snapshot| {
+                let snapshot = std::fs::read_to_string(snapshot).unwrap();
+                let row = snapshot.lines().nth(1).unwrap().to_string();
+                row.split(',').nth(3).unwrap().parse::<Decimal>().unwrap()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            totals,
+            [Decimal::new(2, 0), Decimal::new(4, 0), Decimal::new(6, 0)]
+        );
+    }
+
+    /// Test that the exported transaction store has the stored deposits, but no withdrawal or dispute rows.
+    #[tokio::test]
+    async fn test_tx_store_out() {
+        let mut input = tempfile::NamedTempFile::new().unwrap();
+        write!(
+            input,
+            "type, client, tx, amount\n\
+             deposit, 2, 3, 5.0\n\
+             deposit, 1, 1, 10.0\n\
+             withdrawal, 1, 2, 2.5\n\
+             deposit, 1, 4, 1.0\n\
+             dispute, 1, 4,\n\
+             dispute, 2, 3,\n\
+             resolve, 2, 3,\n"
+        )
+        .unwrap();
+        let tx_store = tempfile::NamedTempFile::new().unwrap();
+
+        let options = Options {
+            transactions_file_path: Some(input.path().to_str().unwrap().into()),
+            tx_store_path: Some(tx_store.path().to_str().unwrap().into()),
+            ..Default::default()
+        };
+        process(&options).await.unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(tx_store.path()).unwrap(),
+            "client,tx,type,amount,disputed\n\
+             1,1,deposit,10.0,false\n\
+             1,4,deposit,1.0,true\n\
+             2,3,deposit,5.0,false\n"
+        );
+    }
+
+    /// Test that the output written through a buffer, even one smaller than a row, is the same as without it.
+    #[tokio::test]
+    async fn test_write_buffer_size() {
+        let clients = (1..=200)
+            .map(|id| {
+                (
+                    id,
+                    Client::with_balances(id, Decimal::new(id.into(), 2), Decimal::ONE),
+                )
+            })
+            .collect::<HashMap<_, _>>();
+
+        let mut outputs = Vec::new();
+        for write_buffer_size in [None, Some(7), Some(64 * 1024)] {
+            let options = Options {
+                write_buffer_size,
+                ..Default::default()
+            };
+            let file = tempfile::NamedTempFile::new().unwrap();
+            let writer = output_writer(
+                tokio::fs::File::create(file.path()).await.unwrap(),
+                &options,
+            );
+            CsvPersister::new(writer, false)
+                .persist(&clients)
+                .await
                 .unwrap();
 
-            // This withdrawal should fail because the client account should be locked by now.
-            tp_tx
-                .send(Transaction::new(
-                    TransactionType::Withdrawal,
-                    1,
-                    3,
-                    Some(Decimal::new(5, 0)),
+            outputs.push(std::fs::read(file.path()).unwrap());
+        }
+
+        assert_eq!(String::from_utf8_lossy(&outputs[0]).lines().count(), 201);
+        assert_eq!(outputs[1], outputs[0]);
+        assert_eq!(outputs[2], outputs[0]);
+    }
+
+    /// Test that the results by worker follow the routing, each client in exactly one map.
+    #[tokio::test]
+    async fn test_results_by_worker() {
+        let config = Config {
+            workers: Some(4),
+            ..Default::default()
+        };
+
+        let (submitter, processor) = TransactionProcessor::with_config(config);
+        for client in 1..=20 {
+            submitter
+                .submit(Transaction::new(
+                    TransactionType::Deposit,
+                    client,
+                    client as u32,
+                    Some(Decimal::ONE),
                 ))
+                .await
                 .unwrap();
+        }
+
+        let results = processor.get_results_by_worker(submitter).await.unwrap();
+        let workers = results.len();
+        for client in 1..=20 {
+            let holders = results
+                .iter()
+                .enumerate()
+                .filter(|(_, clients)| clients.contains_key(&client))
+                .map(|(index, _)| index)
+                .collect::<Vec<_>>();
 
-            tp
+            assert_eq!(
+                holders,
+                [TransactionProcessor::worker_index(client, workers)]
+            );
+        }
+    }
+
+    /// Test that streaming the results gives the same clients as collecting them, more than fit in the stream's
+    /// buffer, and that another processor's submitter ends the stream with an error.
+    #[tokio::test]
+    async fn test_results_stream() {
+        let config = Config {
+            workers: Some(4),
+            ..Default::default()
         };
+        let transactions = generate::generate(20_000, 7);
+
+        let (submitter, processor) = TransactionProcessor::with_config(config.clone());
+        for transaction in transactions.iter().cloned() {
+            submitter.submit(transaction).await.unwrap();
+        }
+        let collected = processor.get_results(submitter).await.unwrap();
+
+        let (submitter, processor) = TransactionProcessor::with_config(config);
+        for transaction in transactions {
+            submitter.submit(transaction).await.unwrap();
+        }
+        let streamed = processor
+            .get_results_stream(submitter)
+            .map(|client| client.map(|client| (client.get_id(), client)))
+            .try_collect::<HashMap<_, _>>()
+            .await
+            .unwrap();
+
+        assert_eq!(streamed.len(), collected.len());
+        assert!(verify::diff(&collected, &streamed).is_empty());
+
+        let (submitter, processor) = TransactionProcessor::new();
+        let (other_submitter, _other) = TransactionProcessor::new();
+        let results = processor
+            .get_results_stream(other_submitter)
+            .collect::<Vec<_>>()
+            .await;
+        assert!(matches!(results[..], [Err(_)]));
+        drop(submitter);
+    }
+
+    /// Test that a worker skips a transaction that panics and keeps applying the next ones.
+    #[tokio::test]
+    async fn test_catch_panics() {
+        /// Clock that panics when read, which counting a dispute towards the rapid disputes does.
+        #[derive(Debug)]
+        struct PanickingClock;
+
+        impl rct::clock::Clock for PanickingClock {
+            fn now(&self) -> std::time::SystemTime {
+                panic!("The clock is broken.")
+            }
+        }
+
+        let config = Config {
+            rapid_disputes: Some(rct::config::RapidDisputeRule {
+                count: 2,
+                window: std::time::Duration::from_secs(60),
+            }),
+            clock: Some(Arc::new(PanickingClock)),
+            catch_panics: true,
+            ..Default::default()
+        };
+
+        let (submitter, processor) = TransactionProcessor::with_config(config);
+        for transaction in [
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(Decimal::TEN)),
+            Transaction::new(TransactionType::Dispute, 1, 1, None),
+            Transaction::new(TransactionType::Deposit, 1, 2, Some(Decimal::ONE)),
+        ] {
+            submitter.submit(transaction).await.unwrap();
+        }
+
+        assert_eq!(processor.flush_stats().await.unwrap().panics, 1);
+        let clients = processor.get_results(submitter).await.unwrap();
 
-        let clients = tp.get_results().await.unwrap();
         let client = clients.get(&1).unwrap();
+        assert_eq!(client.get_available(), Decimal::new(11, 0));
+        assert_eq!(client.get_held(), Decimal::ZERO);
+    }
 
-        assert_eq!(client.get_total(), Decimal::new(5, 0));
-        assert!(client.is_locked());
+    /// Test that the results can be awaited with the submitter still in scope, since getting them takes it, and
+    /// that another processor's submitter is refused instead of waiting forever.
+    #[tokio::test]
+    async fn test_results_take_submitter() {
+        let (submitter, processor) = TransactionProcessor::new();
+        submitter
+            .submit(Transaction::new(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Decimal::ONE),
+            ))
+            .await
+            .unwrap();
+
+        let clients = tokio::time::timeout(
+            std::time::Duration::from_secs(10),
+            processor.get_results(submitter),
+        )
+        .await
+        .expect("Getting the results deadlocked.")
+        .unwrap();
+        assert_eq!(clients[&1].get_available(), Decimal::ONE);
+
+        let (submitter, processor) = TransactionProcessor::new();
+        let (other_submitter, _other) = TransactionProcessor::new();
+        assert!(processor.get_results(other_submitter).await.is_err());
+        drop(submitter);
+    }
+
+    /// Test that finalizing returns the clients, the stats and the rejections of a mixed input.
+    #[tokio::test]
+    async fn test_finalize() {
+        let config = Config {
+            collect_rejections: true,
+            ..Default::default()
+        };
+        let (submitter, processor) = TransactionProcessor::with_config(config);
+        for transaction in [
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(Decimal::TEN)),
+            Transaction::new(TransactionType::Withdrawal, 1, 2, Some(Decimal::new(20, 0))),
+            Transaction::new(TransactionType::Deposit, 2, 3, Some(Decimal::ONE)),
+            Transaction::new(TransactionType::Dispute, 2, 9, None),
+        ] {
+            submitter
+                .submit(transaction.with_source("feed"))
+                .await
+                .unwrap();
+        }
+
+        let (clients, stats, rejected) = processor.finalize(submitter).await.unwrap();
+
+        assert_eq!(clients.len(), 2);
+        assert_eq!(clients.get(&1).unwrap().get_available(), Decimal::TEN);
+        assert_eq!(stats.processed_by_source, [("feed".to_string(), 4)].into());
+
+        let mut rejected = rejected
+            .iter()
+            .map(|rejection| (rejection.transaction.get_tx_id(), rejection.reason))
+            .collect::<Vec<_>>();
+        rejected.sort_by_key(|(tx_id, _)| *tx_id);
+        assert_eq!(
+            rejected,
+            [
+                (2, RejectionReason::InsufficientFunds),
+                (9, RejectionReason::UnknownTransaction)
+            ]
+        );
+    }
+
+    /// Test that a deposit repeated on the next row is dropped before reaching the processor, and only when it is
+    /// consecutive.
+    #[tokio::test]
+    async fn test_dedup_consecutive() {
+        let mut transactions_file = tempfile::NamedTempFile::new().unwrap();
+        write!(
+            transactions_file,
+            "type, client, tx, amount\n\
+             deposit, 1, 1, 10.0\n\
+             deposit, 1, 1, 10.0\n\
+             deposit, 1, 2, 1.0\n\
+             deposit, 1, 3, 1.0\n\
+             deposit, 1, 2, 1.0\n"
+        )
+        .unwrap();
+
+        let options = Options {
+            transactions_file_path: Some(transactions_file.path().to_str().unwrap().into()),
+            dedup_consecutive: true,
+            ..Default::default()
+        };
+
+        let clients = process(&options).await.unwrap();
+
+        // The repeated tx 2 isn't consecutive, so it isn't dropped, but the processor rejects it as a duplicate.
+        assert_eq!(
+            clients.get(&1).unwrap().get_available(),
+            Decimal::new(120, 1)
+        );
+    }
+
+    /// Test that a tx id reused by another client, on another worker, is rejected as a duplicate, and only the
+    /// first transaction with it takes effect.
+    #[tokio::test]
+    async fn test_duplicate_tx_id() {
+        let config = Config {
+            workers: Some(2),
+            collect_rejections: true,
+            ..Default::default()
+        };
+        let (submitter, processor) = TransactionProcessor::with_config(config);
+        for transaction in [
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(Decimal::TEN)),
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(Decimal::ONE)),
+            Transaction::new(TransactionType::Deposit, 2, 1, Some(Decimal::ONE)),
+            Transaction::new(TransactionType::Withdrawal, 2, 1, Some(Decimal::ONE)),
+            Transaction::new(TransactionType::Deposit, 2, 2, Some(Decimal::TWO)),
+            Transaction::new(TransactionType::Dispute, 1, 1, None),
+        ] {
+            submitter.submit(transaction).await.unwrap();
+        }
+
+        let (clients, stats, rejected) = processor.finalize(submitter).await.unwrap();
+
+        assert_eq!(clients[&1].get_held(), Decimal::TEN);
+        assert_eq!(clients[&1].get_available(), Decimal::ZERO);
+        assert_eq!(clients[&2].get_available(), Decimal::TWO);
+        assert_eq!(stats.duplicates, 3);
+        assert_eq!(stats.rejected, 3);
+        assert_eq!(rejected.len(), 3);
+        assert!(rejected
+            .iter()
+            .all(|rejection| rejection.reason == RejectionReason::DuplicateTransaction));
+    }
+
+    /// Test that strict mode fails on a bad row, naming it, where the lenient mode skips it.
+    #[tokio::test]
+    async fn test_strict() {
+        for (row, error) in [
+            (
+                "deposit, 1, 2, x",
+                "Malformed record at line 3: deposit, 1, 2, x.",
+            ),
+            (
+                "refund, 1, 2, 1.0",
+                "Malformed record at line 3: refund, 1, 2, 1.0.",
+            ),
+            (
+                "deposit, 1, 2, 1.00001",
+                "Transaction 2 of client 1 has an invalid amount: 1.00001.",
+            ),
+        ] {
+            let mut transactions_file = tempfile::NamedTempFile::new().unwrap();
+            write!(
+                transactions_file,
+                "type, client, tx, amount\n\
+                 deposit, 1, 1, 10.0\n\
+                 {row}\n\
+                 deposit, 1, 3, 1.0\n"
+            )
+            .unwrap();
+            let options = Options {
+                transactions_file_path: Some(transactions_file.path().to_str().unwrap().into()),
+                ..Default::default()
+            };
+
+            let clients = process(&options).await.unwrap();
+            assert_eq!(clients[&1].get_available(), Decimal::new(11, 0));
+
+            let options = Options {
+                strict: true,
+                ..options
+            };
+            assert_eq!(process(&options).await.unwrap_err().to_string(), error);
+        }
+    }
+
+    /// Test that validating reports the valid rows and the line of every invalid one, reading past them.
+    #[tokio::test]
+    async fn test_validate_only() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/invalid.csv");
+        let options = Options {
+            transactions_file_path: Some(path.into()),
+            validate_only: true,
+            ..Default::default()
+        };
+
+        let validation = validate(&options).await.unwrap();
+        assert_eq!(validation.valid, 3);
+        assert_eq!(
+            validation.failures,
+            [
+                format!("{path}: Invalid record at line 3: A withdrawal needs an amount."),
+                format!("{path}: Malformed record at line 5: deposit, 2, x, 1.0."),
+                format!(
+                    "{path}: Invalid record at line 7: The amount 1.00001 has more than 4 decimal places."
+                ),
+            ]
+        );
+        assert!(validation
+            .to_string()
+            .starts_with("3 valid rows, 3 invalid rows.\n"));
     }
 }
@@ -0,0 +1,171 @@
+use crate::transaction::{Transaction, TransactionType};
+use std::collections::HashMap;
+
+/// Elide redundant operations from a batch of transactions before it is processed.
+///
+/// The only pair currently elided is a `dispute` immediately followed by a `resolve` of the same transaction.
+/// The pair is dropped when all of the following hold, which guarantees the dispute would have succeeded and
+/// the resolve would have put the balances back exactly as they were, as long as resolves aren't held back for
+/// hold days and disputes aren't counted to flag clients, see `Config::hold_days` and `Config::rapid_disputes`:
+///
+/// * The resolve is the next row of the same client after the dispute (no intervening activity), and no
+///   deposit or withdrawal reusing the transaction id appears between them.
+/// * The referenced transaction is an earlier `deposit` of the same client, with a non-negative amount, and it
+///   is the only deposit or withdrawal seen with that transaction id.
+/// * The client had no `withdrawal` or `dispute` rows between that deposit and the pair, so the deposited
+///   amount is still available to be held.
+///
+/// Every other row is kept, in its original order.
+pub fn normalize(transactions: Vec<Transaction>) -> Vec<Transaction> {
+    // For each row, the index of the next row of the same client and of the next deposit or withdrawal
+    // reusing the same transaction id. These are used to check that the pair is adjacent.
+    let mut next_client_row = vec![None; transactions.len()];
+    let mut next_tx_row = vec![None; transactions.len()];
+    let mut last_client_seen = HashMap::new();
+    let mut last_tx_seen = HashMap::new();
+    for (index, transaction) in transactions.iter().enumerate().rev() {
+        next_client_row[index] = last_client_seen.insert(transaction.get_client_id(), index);
+        next_tx_row[index] = last_tx_seen.get(&transaction.get_ref_tx_id()).copied();
+
+        if matches!(
+            transaction.get_type(),
+            TransactionType::Deposit | TransactionType::Withdrawal
+        ) {
+            last_tx_seen.insert(transaction.get_tx_id(), index);
+        }
+    }
+
+    let mut deposits: HashMap<u32, Option<usize>> = HashMap::new();
+    let mut last_debit: HashMap<u16, usize> = HashMap::new();
+    let mut elided = vec![false; transactions.len()];
+
+    for (index, transaction) in transactions.iter().enumerate() {
+        if elided[index] {
+            continue;
+        }
+
+        let client_id = transaction.get_client_id();
+        match transaction.get_type() {
+            TransactionType::Deposit | TransactionType::Withdrawal => {
+                // A reused transaction id makes the referenced amount ambiguous, so it is never elided.
+                let is_valid_deposit = transaction.get_type() == TransactionType::Deposit
+                    && matches!(transaction.get_amount(), Some(amount) if !amount.is_sign_negative());
+
+                deposits
+                    .entry(transaction.get_tx_id())
+                    .and_modify(|deposit| *deposit = None)
+                    .or_insert_with(|| is_valid_deposit.then_some(index));
+
+                if transaction.get_type() == TransactionType::Withdrawal {
+                    last_debit.insert(client_id, index);
+                }
+            }
+
+            TransactionType::Dispute => {
+                let resolve_index = next_client_row[index].filter(|&next| {
+                    transactions[next].get_type() == TransactionType::Resolve
+                        && transactions[next].get_ref_tx_id() == transaction.get_ref_tx_id()
+                        && next_tx_row[index].is_none_or(|reuse| reuse > next)
+                });
+
+                let deposit_index = deposits
+                    .get(&transaction.get_ref_tx_id())
+                    .copied()
+                    .flatten()
+                    .filter(|&deposit| transactions[deposit].get_client_id() == client_id)
+                    .filter(|&deposit| {
+                        last_debit
+                            .get(&client_id)
+                            .is_none_or(|&debit| debit < deposit)
+                    });
+
+                match (resolve_index, deposit_index) {
+                    (Some(resolve_index), Some(_)) => {
+                        elided[index] = true;
+                        elided[resolve_index] = true;
+                    }
+
+                    _ => {
+                        last_debit.insert(client_id, index);
+                    }
+                }
+            }
+
+            _ => {}
+        }
+    }
+
+    transactions
+        .into_iter()
+        .zip(elided)
+        .filter_map(|(transaction, elided)| (!elided).then_some(transaction))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ledger::Ledger;
+    use rust_decimal::Decimal;
+
+    fn process(transactions: Vec<Transaction>) -> (Decimal, Decimal, bool) {
+        let mut ledger = Ledger::default();
+        for transaction in transactions {
+            ledger.apply(transaction);
+        }
+
+        let clients = ledger.into_clients();
+        let client = clients.get(&1).unwrap();
+
+        (
+            client.get_available(),
+            client.get_held(),
+            client.is_locked(),
+        )
+    }
+
+    fn transactions() -> Vec<Transaction> {
+        vec![
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(Decimal::new(10, 0))),
+            Transaction::new(TransactionType::Deposit, 1, 2, Some(Decimal::new(5, 0))),
+            Transaction::new(TransactionType::Dispute, 1, 2, None),
+            Transaction::new(TransactionType::Resolve, 1, 2, None),
+            Transaction::new(TransactionType::Withdrawal, 1, 3, Some(Decimal::new(3, 0))),
+        ]
+    }
+
+    /// Test that an adjacent dispute/resolve pair is elided and doesn't change the result.
+    #[test]
+    fn test_elided_dispute_resolve() {
+        let normalized = normalize(transactions());
+
+        assert_eq!(normalized.len(), 3); // The dispute and the resolve are gone.
+        assert_eq!(process(normalized), process(transactions()));
+    }
+
+    /// Test that a pair with intervening activity for the same client is kept.
+    #[test]
+    fn test_intervening_activity() {
+        let transactions = vec![
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(Decimal::new(10, 0))),
+            Transaction::new(TransactionType::Dispute, 1, 1, None),
+            Transaction::new(TransactionType::Withdrawal, 1, 2, Some(Decimal::new(3, 0))),
+            Transaction::new(TransactionType::Resolve, 1, 1, None),
+        ];
+
+        assert_eq!(normalize(transactions).len(), 4);
+    }
+
+    /// Test that a pair whose dispute could fail for lack of available funds is kept.
+    #[test]
+    fn test_withdrawal_before_pair() {
+        let transactions = vec![
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(Decimal::new(10, 0))),
+            Transaction::new(TransactionType::Withdrawal, 1, 2, Some(Decimal::new(8, 0))),
+            Transaction::new(TransactionType::Dispute, 1, 1, None),
+            Transaction::new(TransactionType::Resolve, 1, 1, None),
+        ];
+
+        assert_eq!(normalize(transactions).len(), 4);
+    }
+}
@@ -0,0 +1,546 @@
+use anyhow::{bail, Context, Result};
+use rct::{
+    config::{
+        Config, DisputePolicy, HeldCapPolicy, MissingAmountPolicy, NegativeTotalPolicy,
+        PhantomClientPolicy, PostLockChargebackPolicy, RapidDisputeRule, VelocityLimit,
+    },
+    input::{DirectionMap, InputFormat, InputOrder, InvalidUtf8Policy, STDIN_PATH},
+    output::{BoolFormat, Column, OutputFormat, COLUMNS},
+};
+use rust_decimal::Decimal;
+use std::{collections::HashSet, env, time::Duration};
+
+/// Environment variable with the number of workers, overridden by `--workers`.
+const WORKERS_ENV: &str = "RCT_WORKERS";
+
+/// Options given on the command line.
+#[derive(Debug, Default)]
+pub struct Options {
+    pub config: Config,
+    pub normalize: bool,
+    pub flush_per_row: bool,
+    pub locked_report_path: Option<String>,
+
+    /// Where the stored transactions of the run are written as CSV, for offline analysis.
+    pub tx_store_path: Option<String>,
+    pub verify_parallel: bool,
+    pub serve_address: Option<String>,
+    pub output_format: OutputFormat,
+    pub output_path: Option<String>,
+
+    /// Times a write of the CSV output that failed transiently is retried, see `RetryWriter`.
+    pub write_retries: u32,
+
+    /// Capacity of the buffer the CSV output is written through, in bytes. Not buffered beyond the CSV writer's
+    /// own buffer when not set.
+    pub write_buffer_size: Option<usize>,
+
+    /// How `locked` is written to the CSV output and the service's JSON.
+    pub bool_format: BoolFormat,
+
+    /// Print the schema of the output format as JSON, instead of processing anything.
+    pub print_schema: bool,
+
+    /// Number of synthetic transactions to write, generated from `seed`, instead of processing anything.
+    pub generate: Option<usize>,
+    pub seed: u64,
+
+    /// Write the SHA-256 of the CSV output next to it, in `<output>.sha256`.
+    pub checksum: bool,
+
+    /// Number of CSV files to split the results into, written to `output_dir`.
+    pub partition_output: Option<u16>,
+    pub output_dir: Option<String>,
+
+    /// Number of submitted transactions between snapshots of every client, written to `snapshot_dir`.
+    pub snapshot_every: Option<usize>,
+    pub snapshot_dir: Option<String>,
+    pub deadline: Option<Duration>,
+    pub disputes_path: Option<String>,
+
+    /// Results of a previous run, only the clients that differ from them are written.
+    pub baseline_path: Option<String>,
+
+    /// Write each worker's clients to the CSV output as soon as it is done, instead of all of them sorted at the
+    /// end, see `TransactionProcessor::get_results_stream`.
+    pub stream_output: bool,
+    pub input_format: InputFormat,
+    pub invalid_utf8: InvalidUtf8Policy,
+
+    /// Read the CSV columns by position instead of by the names in the header, and whether there is no header.
+    pub positional: bool,
+    pub no_headers: bool,
+
+    /// Transaction types of the values of a `direction` column, for a CSV input without a type column.
+    pub directions: DirectionMap,
+
+    /// Number of raw records read ahead of parsing, see `ReadAhead`.
+    pub read_ahead: Option<usize>,
+
+    /// Directory whose `*.csv` files are processed, after the transactions file, as one stream.
+    pub input_dir: Option<String>,
+    pub input_order: InputOrder,
+
+    /// Transaction ids whose rows are dropped while reading, to replay an input without known bad transactions.
+    pub skip_tx: HashSet<u32>,
+
+    /// Drop a row identical to the row right before it in the same input, as emitted by retries upstream.
+    pub dedup_consecutive: bool,
+
+    /// Fail on the first malformed row or invalid amount, naming it, instead of skipping it.
+    pub strict: bool,
+
+    /// Only check that every row parses and is valid on its own, reporting the invalid ones, without computing
+    /// any balance.
+    pub validate_only: bool,
+
+    /// Local path, `s3://bucket/key` when built with the `aws` feature, or `-` for the standard input, which is
+    /// read when there is no input at all.
+    pub transactions_file_path: Option<String>,
+}
+
+impl Options {
+    /// Parse the options from the application arguments, the first one being the executable name.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self> {
+        let mut args = args.into_iter();
+        let exe_name = args.next().context("Unable to get executable name.")?;
+
+        let usage = format!(
+            "Usage: {exe_name} [--normalize] [--dispute-policy require-available|available-first] \
+             [--flush-per-row] [--locked-report-out <path>] [--verify-parallel] [--format csv|parquet|json|table] \
+             [--output <path>] [--deadline <duration>] [--disputes <path>] [--max-amount <amount>] \
+             [--normalize-signs] [--clamp-to-held] [--skip-tx <id,id,...>] [--bool-format word|int] \
+             [--partition-output <n> --output-dir <dir>] \
+             [--input-format csv|binary|split-amount] [--negative-total allow|reject|warn] [--checksum] \
+             [--priority <type>=<n>,...] [--held-cap <fraction>] [--held-cap-policy partial-hold|reject] \
+             [--phantom-clients keep|flag|suppress] [--hold-days <n>] [--dedup-consecutive] [--verify-ledger] \
+             [--catch-panics] [--flag-rapid-disputes <count>/<duration>] [--read-ahead <records>] \
+             [--direction-map <direction>=<type>,...] [--snapshot-every <n> --snapshot-out <dir>] \
+             [--tx-store-out <path>] [--write-retries <n>] [--write-buffer-size <bytes>] \
+             [--missing-amount reject|zero|skip] [--withdrawal-velocity <amount>/<duration>] \
+             [--post-lock-chargeback ignore|apply] [--positional [--no-headers]] \
+             [--decimal-places <type>=<n>,...] [--max-integer-digits <n>] [--invalid-utf8 abort|skip|replace] \
+             [--baseline <path>] [--stream-output] [--workers <n>] [--channel-capacity <n>] [--strict] \
+             [--currencies] \
+             [transactions.csv | - | --input s3://bucket/key | --input-dir <dir> [--input-order name|modified]]\n\
+             Usage: {exe_name} [--dispute-policy require-available|available-first] [--priority <type>=<n>,...] \
+             [--bool-format word|int] [--catch-panics] [--workers <n>] --serve <address>\n\
+             Usage: {exe_name} [--format csv|parquet|json|table] [--bool-format word|int] --print-schema\n\
+             Usage: {exe_name} [--input-format csv|binary|split-amount] [--positional [--no-headers]] \
+             [--direction-map <direction>=<type>,...] [--invalid-utf8 abort|skip|replace] --validate-only \
+             [transactions.csv | - | --input s3://bucket/key | --input-dir <dir> [--input-order name|modified]]\n\
+             Usage: {exe_name} --generate <count> [--seed <s>] [--output <path>]"
+        );
+
+        let mut options = Self::default();
+        if let Ok(workers) = env::var(WORKERS_ENV) {
+            options.config.workers = Some(
+                workers
+                    .parse()
+                    .with_context(|| format!("Invalid {WORKERS_ENV} {workers}."))?,
+            );
+        }
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--normalize" => options.normalize = true,
+                "--flush-per-row" => options.flush_per_row = true,
+                "--checksum" => options.checksum = true,
+                "--stream-output" => options.stream_output = true,
+                "--positional" => options.positional = true,
+                "--no-headers" => options.no_headers = true,
+                "--print-schema" => options.print_schema = true,
+                "--dedup-consecutive" => options.dedup_consecutive = true,
+                "--strict" => options.strict = true,
+                "--validate-only" => options.validate_only = true,
+                "--verify-ledger" => options.config.verify_ledger = true,
+                "--catch-panics" => options.config.catch_panics = true,
+                "--verify-parallel" => options.verify_parallel = true,
+                "--normalize-signs" => options.config.normalize_signs = true,
+                "--clamp-to-held" => options.config.clamp_to_held = true,
+                "--currencies" => options.config.currencies = true,
+                "--deadline" => {
+                    let deadline = args.next().context(usage.clone())?;
+                    options.deadline = Some(parse_duration(&deadline).context(usage.clone())?);
+                }
+                "--max-amount" => {
+                    let max_amount = args.next().context(usage.clone())?;
+                    options.config.max_amount = Some(max_amount.parse().context(usage.clone())?);
+                }
+                "--skip-tx" => {
+                    let ids = args.next().context(usage.clone())?;
+                    for id in ids.split(',') {
+                        options
+                            .skip_tx
+                            .insert(id.trim().parse().context(usage.clone())?);
+                    }
+                }
+                "--disputes" => options.disputes_path = Some(args.next().context(usage.clone())?),
+                "--baseline" => options.baseline_path = Some(args.next().context(usage.clone())?),
+                "--input" => {
+                    options.transactions_file_path = Some(args.next().context(usage.clone())?)
+                }
+                "--partition-output" => {
+                    let partitions = args.next().context(usage.clone())?;
+                    let partitions = partitions.parse().ok().filter(|&partitions| partitions > 0);
+                    options.partition_output = Some(partitions.context(usage.clone())?);
+                }
+                "--output-dir" => options.output_dir = Some(args.next().context(usage.clone())?),
+                "--snapshot-every" => {
+                    let every = args.next().context(usage.clone())?;
+                    let every = every.parse().ok().filter(|&every| every > 0);
+                    options.snapshot_every = Some(every.context(usage.clone())?);
+                }
+                "--snapshot-out" => {
+                    options.snapshot_dir = Some(args.next().context(usage.clone())?)
+                }
+                "--output" => options.output_path = Some(args.next().context(usage.clone())?),
+                "--write-buffer-size" => {
+                    let capacity = args.next().context(usage.clone())?;
+                    let capacity = capacity.parse().ok().filter(|&capacity| capacity > 0);
+                    options.write_buffer_size = Some(capacity.context(usage.clone())?);
+                }
+                "--write-retries" => {
+                    let retries = args.next().context(usage.clone())?;
+                    options.write_retries = retries.parse().context(usage.clone())?;
+                }
+                "--workers" => {
+                    let workers = args.next().context(usage.clone())?;
+                    options.config.workers = Some(workers.parse().context(usage.clone())?);
+                }
+                "--channel-capacity" => {
+                    let capacity = args.next().context(usage.clone())?;
+                    options.config.channel_capacity =
+                        Some(capacity.parse().context(usage.clone())?);
+                }
+                "--read-ahead" => {
+                    let records = args.next().context(usage.clone())?;
+                    options.read_ahead = Some(records.parse().context(usage.clone())?);
+                }
+                "--input-dir" => options.input_dir = Some(args.next().context(usage.clone())?),
+                "--input-order" => {
+                    options.input_order = match args.next().as_deref() {
+                        Some("name") => InputOrder::Name,
+                        Some("modified") => InputOrder::Modified,
+                        _ => bail!(usage),
+                    }
+                }
+                "--input-format" => {
+                    options.input_format = match args.next().as_deref() {
+                        Some("csv") => InputFormat::Csv,
+                        Some("binary") => InputFormat::Binary,
+                        Some("split-amount") => InputFormat::SplitAmountCsv,
+                        _ => bail!(usage),
+                    }
+                }
+                "--direction-map" => {
+                    let directions = args.next().context(usage.clone())?;
+                    options.directions = DirectionMap::empty();
+                    for direction in directions.split(',') {
+                        let (direction, transaction_type) =
+                            direction.split_once('=').context(usage.clone())?;
+                        options.directions.insert(
+                            direction.trim(),
+                            transaction_type.trim().parse().context(usage.clone())?,
+                        );
+                    }
+                }
+                "--invalid-utf8" => {
+                    options.invalid_utf8 = match args.next().as_deref() {
+                        Some("abort") => InvalidUtf8Policy::Abort,
+                        Some("skip") => InvalidUtf8Policy::Skip,
+                        Some("replace") => InvalidUtf8Policy::Replace,
+                        _ => bail!(usage),
+                    }
+                }
+                "--bool-format" => {
+                    options.bool_format = match args.next().as_deref() {
+                        Some("word") => BoolFormat::Word,
+                        Some("int") => BoolFormat::Int,
+                        _ => bail!(usage),
+                    }
+                }
+                "--format" => {
+                    options.output_format = match args.next().as_deref() {
+                        Some("csv") => OutputFormat::Csv,
+                        Some("parquet") => OutputFormat::Parquet,
+                        Some("json") => OutputFormat::Json,
+                        Some("table") => OutputFormat::Table,
+                        _ => bail!(usage),
+                    }
+                }
+                "--generate" => {
+                    let count = args.next().context(usage.clone())?;
+                    options.generate = Some(count.parse().context(usage.clone())?);
+                }
+                "--seed" => {
+                    let seed = args.next().context(usage.clone())?;
+                    options.seed = seed.parse().context(usage.clone())?;
+                }
+                "--serve" => options.serve_address = Some(args.next().context(usage.clone())?),
+                "--tx-store-out" => {
+                    options.tx_store_path = Some(args.next().context(usage.clone())?)
+                }
+                "--locked-report-out" => {
+                    options.locked_report_path = Some(args.next().context(usage.clone())?)
+                }
+                "--priority" => {
+                    let priorities = args.next().context(usage.clone())?;
+                    for priority in priorities.split(',') {
+                        let (transaction_type, priority) =
+                            priority.split_once('=').context(usage.clone())?;
+                        options.config.priorities.insert(
+                            transaction_type.trim().parse().context(usage.clone())?,
+                            priority.trim().parse().context(usage.clone())?,
+                        );
+                    }
+                }
+                "--decimal-places" => {
+                    let decimal_places = args.next().context(usage.clone())?;
+                    for decimal_places in decimal_places.split(',') {
+                        let (transaction_type, decimal_places) =
+                            decimal_places.split_once('=').context(usage.clone())?;
+                        options.config.decimal_places.insert(
+                            transaction_type.trim().parse().context(usage.clone())?,
+                            decimal_places.trim().parse().context(usage.clone())?,
+                        );
+                    }
+                }
+                "--max-integer-digits" => {
+                    let max_integer_digits = args.next().context(usage.clone())?;
+                    options.config.max_integer_digits =
+                        Some(max_integer_digits.parse().context(usage.clone())?);
+                }
+                "--held-cap" => {
+                    let held_cap = args.next().context(usage.clone())?;
+                    // A fraction of the total, so holding never needs more than the whole of it.
+                    let held_cap = held_cap
+                        .parse::<Decimal>()
+                        .ok()
+                        .filter(|held_cap| (Decimal::ZERO..=Decimal::ONE).contains(held_cap));
+                    options.config.held_cap = Some(held_cap.context(usage.clone())?);
+                }
+                "--held-cap-policy" => {
+                    options.config.held_cap_policy = match args.next().as_deref() {
+                        Some("partial-hold") => HeldCapPolicy::PartialHold,
+                        Some("reject") => HeldCapPolicy::Reject,
+                        _ => bail!(usage),
+                    }
+                }
+                "--phantom-clients" => {
+                    options.config.phantom_client_policy = match args.next().as_deref() {
+                        Some("keep") => PhantomClientPolicy::Keep,
+                        Some("flag") => PhantomClientPolicy::Flag,
+                        Some("suppress") => PhantomClientPolicy::Suppress,
+                        _ => bail!(usage),
+                    }
+                }
+                "--missing-amount" => {
+                    options.config.missing_amount_policy = match args.next().as_deref() {
+                        Some("reject") => MissingAmountPolicy::Reject,
+                        Some("zero") => MissingAmountPolicy::TreatAsZero,
+                        Some("skip") => MissingAmountPolicy::Skip,
+                        _ => bail!(usage),
+                    }
+                }
+                "--post-lock-chargeback" => {
+                    options.config.post_lock_chargeback_policy = match args.next().as_deref() {
+                        Some("ignore") => PostLockChargebackPolicy::Ignore,
+                        Some("apply") => PostLockChargebackPolicy::Apply,
+                        _ => bail!(usage),
+                    }
+                }
+                "--flag-rapid-disputes" => {
+                    let rule = args.next().context(usage.clone())?;
+                    let (count, window) = rule.split_once('/').context(usage.clone())?;
+                    options.config.rapid_disputes = Some(RapidDisputeRule {
+                        count: count.parse().context(usage.clone())?,
+                        window: parse_duration(window).context(usage.clone())?,
+                    });
+                }
+                "--withdrawal-velocity" => {
+                    let limit = args.next().context(usage.clone())?;
+                    let (amount, window) = limit.split_once('/').context(usage.clone())?;
+                    options.config.withdrawal_velocity = Some(VelocityLimit {
+                        amount: amount.parse().context(usage.clone())?,
+                        window: parse_duration(window).context(usage.clone())?,
+                    });
+                }
+                "--hold-days" => {
+                    let hold_days = args.next().context(usage.clone())?;
+                    options.config.hold_days = Some(hold_days.parse().context(usage.clone())?);
+                }
+                "--negative-total" => {
+                    options.config.negative_total_policy = match args.next().as_deref() {
+                        Some("allow") => NegativeTotalPolicy::Allow,
+                        Some("reject") => NegativeTotalPolicy::RejectTransaction,
+                        Some("warn") => NegativeTotalPolicy::Warn,
+                        _ => bail!(usage),
+                    }
+                }
+                "--dispute-policy" => {
+                    options.config.dispute_policy = match args.next().as_deref() {
+                        Some("require-available") => DisputePolicy::RequireAvailable,
+                        Some("available-first") => DisputePolicy::AvailableFirst,
+                        _ => bail!(usage),
+                    }
+                }
+                // A misspelled option would otherwise be taken for the input path.
+                unknown if unknown.starts_with("--") => bail!(usage),
+                _ => options.transactions_file_path = Some(arg),
+            }
+        }
+
+        // Without a header, the columns can only be told apart by position.
+        if options.no_headers && !options.positional {
+            bail!(usage);
+        }
+
+        // A dispute and its resolve only cancel out when the resolve isn't held back, and the dispute isn't
+        // counted.
+        if options.normalize && options.config.hold_days.is_some() {
+            bail!("--normalize can't be combined with --hold-days, a resolve may come before the hold matured.");
+        }
+        if options.normalize && options.config.rapid_disputes.is_some() {
+            bail!(
+                "--normalize can't be combined with --flag-rapid-disputes, the disputes it drops would not be \
+                 counted."
+            );
+        }
+
+        if options.transactions_file_path.is_none() && options.input_dir.is_none() {
+            options.transactions_file_path = Some(STDIN_PATH.into());
+        }
+
+        Ok(options)
+    }
+
+    /// Columns of the CSV output: the base ones, `currency` after the client when currencies are kept apart, and
+    /// `flagged` when a rule can flag clients.
+    pub fn output_columns(&self) -> Vec<Column> {
+        let mut columns = COLUMNS.to_vec();
+        if self.config.currencies {
+            columns.insert(1, Column::Currency);
+        }
+        if self.config.rapid_disputes.is_some() {
+            columns.push(Column::Flagged);
+        }
+
+        columns
+    }
+}
+
+/// Parse a duration such as `500ms`, `30s`, `5m` or `1h`. A number without a unit is in seconds.
+fn parse_duration(duration: &str) -> Option<Duration> {
+    let unit_start = duration
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(duration.len());
+    let (value, unit) = duration.split_at(unit_start);
+    let value = value.parse::<f64>().ok()?;
+
+    let seconds = match unit {
+        "ms" => value / 1000.0,
+        "" | "s" => value,
+        "m" => value * 60.0,
+        "h" => value * 3600.0,
+        _ => return None,
+    };
+
+    Duration::try_from_secs_f64(seconds).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test parsing deadline durations.
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("500ms"), Some(Duration::from_millis(500)));
+        assert_eq!(parse_duration("1.5"), Some(Duration::from_millis(1500)));
+        assert_eq!(parse_duration("2m"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_duration("1h"), Some(Duration::from_secs(3600)));
+        assert_eq!(parse_duration("1d"), None);
+        assert_eq!(parse_duration("soon"), None);
+    }
+
+    /// Test that the standard input is read for `-`, or without any input, but not besides an input directory.
+    #[test]
+    fn test_stdin_input() {
+        let parse = |args: &[&str]| {
+            Options::parse(["rct"].iter().chain(args).map(|arg| arg.to_string()))
+                .unwrap()
+                .transactions_file_path
+        };
+
+        assert_eq!(parse(&["-"]).as_deref(), Some(STDIN_PATH));
+        assert_eq!(parse(&["--flush-per-row"]).as_deref(), Some(STDIN_PATH));
+        assert_eq!(parse(&["--input-dir", "inputs"]), None);
+        assert_eq!(
+            parse(&["transactions.csv"]).as_deref(),
+            Some("transactions.csv")
+        );
+    }
+
+    fn parse(args: &[&str]) -> Result<Options> {
+        Options::parse(["rct"].iter().chain(args).map(|arg| arg.to_string()))
+    }
+
+    /// Test that normalizing is refused with hold days, under which a resolve may not release the dispute.
+    #[test]
+    fn test_normalize_with_hold_days() {
+        assert!(parse(&["--normalize"]).is_ok());
+        assert!(parse(&["--hold-days", "3"]).is_ok());
+        assert!(parse(&["--normalize", "--hold-days", "3"])
+            .unwrap_err()
+            .to_string()
+            .contains("--hold-days"));
+    }
+
+    /// Test that normalizing is refused when flagging rapid disputes, which counts the disputes it would drop.
+    #[test]
+    fn test_normalize_with_rapid_disputes() {
+        assert!(parse(&["--flag-rapid-disputes", "3/1h"]).is_ok());
+        assert!(parse(&["--normalize", "--flag-rapid-disputes", "3/1h"])
+            .unwrap_err()
+            .to_string()
+            .contains("--flag-rapid-disputes"));
+    }
+
+    /// Test that an unknown option is refused rather than taken for the input path, which `-` still is.
+    #[test]
+    fn test_unknown_option() {
+        assert!(parse(&["--strcit"])
+            .unwrap_err()
+            .to_string()
+            .starts_with("Usage:"));
+        assert_eq!(
+            parse(&["-"]).unwrap().transactions_file_path.as_deref(),
+            Some("-")
+        );
+        assert_eq!(
+            parse(&["--strict", "transactions.csv"])
+                .unwrap()
+                .transactions_file_path
+                .as_deref(),
+            Some("transactions.csv")
+        );
+    }
+
+    /// Test that the held cap is only accepted as a fraction of the total, from 0 to 1.
+    #[test]
+    fn test_held_cap_range() {
+        for held_cap in ["0", "0.5", "1"] {
+            assert_eq!(
+                parse(&["--held-cap", held_cap]).unwrap().config.held_cap,
+                Some(held_cap.parse().unwrap())
+            );
+        }
+        for held_cap in ["-0.1", "1.01", "79228162514264337593543950335", "half"] {
+            assert!(parse(&["--held-cap", held_cap])
+                .unwrap_err()
+                .to_string()
+                .starts_with("Usage:"));
+        }
+    }
+}
@@ -0,0 +1,1362 @@
+use crate::{
+    client::{self, Client, Currency},
+    ledger::StoredTransaction,
+    verify::{self, Change},
+};
+use anyhow::{bail, ensure, Context as _, Result};
+use arrow::{
+    array::{ArrayRef, BooleanArray, Decimal128Array, StringArray, UInt16Array},
+    datatypes::{DataType, Field, Schema},
+    record_batch::RecordBatch,
+};
+use comfy_table::{presets, CellAlignment, Table};
+use futures::{Stream, StreamExt};
+use parquet::arrow::ArrowWriter;
+use rust_decimal::Decimal;
+use serde::{ser::SerializeMap, Deserialize, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fs::File,
+    future::Future,
+    io::{self, ErrorKind},
+    path::Path,
+    pin::{pin, Pin},
+    sync::Arc,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    time::{sleep, Sleep},
+};
+
+/// Delay before the first retry of a failed write, doubled on every next retry of the same write.
+const WRITE_RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Format of the client results.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    #[default]
+    Csv,
+    Parquet,
+
+    /// An array of one object per client, keyed by the column names.
+    Json,
+
+    /// A table aligned for reading in a terminal, with the numbers right-aligned.
+    Table,
+}
+
+/// How booleans, such as `locked`, are written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BoolFormat {
+    /// `true` or `false`.
+    #[default]
+    Word,
+
+    /// `1` or `0`, for consumers that can't parse the words.
+    Int,
+}
+
+impl BoolFormat {
+    /// The boolean as a JSON value, whose text is also what is written to CSV.
+    pub fn to_json(self, value: bool) -> serde_json::Value {
+        match self {
+            BoolFormat::Word => value.into(),
+            BoolFormat::Int => u8::from(value).into(),
+        }
+    }
+}
+
+/// Column of the client results.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Column {
+    Client,
+
+    /// Currency of the account, only written when currencies are kept apart. Empty for a client's own balances.
+    Currency,
+    Available,
+    Held,
+    Total,
+    Locked,
+
+    /// Whether the client was flagged, only written when a rule can flag clients.
+    Flagged,
+}
+
+/// Base columns of the client results, in the order they are written. The order is part of the output format and
+/// is defined here only, never by the field order of `Client`, so reordering its fields doesn't change the output.
+/// Extra columns, such as `Flagged`, come after these, except `Currency`, which follows the client it keys.
+pub const COLUMNS: [Column; 5] = [
+    Column::Client,
+    Column::Available,
+    Column::Held,
+    Column::Total,
+    Column::Locked,
+];
+
+impl Column {
+    pub fn name(self) -> &'static str {
+        match self {
+            Column::Client => "client",
+            Column::Currency => "currency",
+            Column::Available => "available",
+            Column::Held => "held",
+            Column::Total => "total",
+            Column::Locked => "locked",
+            Column::Flagged => "flagged",
+        }
+    }
+
+    /// Value of the column for a client, as written to the CSV output.
+    pub fn value(self, client: &Client, bool_format: BoolFormat) -> Result<String> {
+        Ok(match self.json_value(client, bool_format)? {
+            serde_json::Value::String(value) => value,
+            value => value.to_string(),
+        })
+    }
+
+    /// Value of the column for a client, as written to the JSON output. Amounts are strings, so they keep their
+    /// exact decimal value. Fails rather than writing a clamped total when it overflows.
+    pub fn json_value(self, client: &Client, bool_format: BoolFormat) -> Result<serde_json::Value> {
+        Ok(match self {
+            Column::Client => client.get_id().into(),
+            Column::Currency => client
+                .get_currency()
+                .map(|currency| currency.to_string())
+                .unwrap_or_default()
+                .into(),
+            Column::Available => client.format_available().into(),
+            Column::Held => client.format_held().into(),
+            Column::Total => total(client)?.into(),
+            Column::Locked => bool_format.to_json(client.is_locked()),
+            Column::Flagged => bool_format.to_json(client.is_flagged()),
+        })
+    }
+}
+
+/// Total of a client as written to the outputs, failing with its id when it overflows.
+fn total(client: &Client) -> Result<String> {
+    client
+        .format_total()
+        .with_context(|| format!("The total of client {} can't be written.", client.get_id()))
+}
+
+/// Machine-readable description of the client results in a format: the columns in order, with their type, and
+/// the unit and rounding of the amounts.
+pub fn schema(
+    format: OutputFormat,
+    bool_format: BoolFormat,
+    columns: &[Column],
+) -> serde_json::Value {
+    let (format_name, integer_type, amount_type, boolean_type) = match (format, bool_format) {
+        (OutputFormat::Csv, BoolFormat::Word) => ("csv", "integer", "decimal", "boolean"),
+        (OutputFormat::Csv, BoolFormat::Int) => ("csv", "integer", "decimal", "integer"),
+        (OutputFormat::Json, BoolFormat::Word) => ("json", "integer", "decimal string", "boolean"),
+        (OutputFormat::Json, BoolFormat::Int) => ("json", "integer", "decimal string", "integer"),
+        (OutputFormat::Table, BoolFormat::Word) => ("table", "integer", "decimal", "boolean"),
+        (OutputFormat::Table, BoolFormat::Int) => ("table", "integer", "decimal", "integer"),
+        // The scale is the largest among the written amounts, see `write_parquet`.
+        (OutputFormat::Parquet, _) => ("parquet", "uint16", "decimal128(38, scale)", "boolean"),
+    };
+    let rounding = match format {
+        OutputFormat::Csv | OutputFormat::Json | OutputFormat::Table => {
+            "four decimal places, half to even"
+        }
+        OutputFormat::Parquet => "none, amounts keep the decimal places of the input",
+    };
+
+    let columns = columns
+        .iter()
+        .map(|column| match column {
+            Column::Client => serde_json::json!({ "name": column.name(), "type": integer_type }),
+            Column::Currency => serde_json::json!({ "name": column.name(), "type": "string" }),
+            Column::Available | Column::Held | Column::Total => serde_json::json!({
+                "name": column.name(),
+                "type": amount_type,
+                "unit": "currency of the input amounts, or of the row",
+                "rounding": rounding,
+            }),
+            Column::Locked | Column::Flagged => {
+                serde_json::json!({ "name": column.name(), "type": boolean_type })
+            }
+        })
+        .collect::<Vec<_>>();
+
+    serde_json::json!({ "format": format_name, "columns": columns })
+}
+
+/// The accounts of the clients by ascending id, then currency, the order of every output, so the same results are
+/// always written the same way. A client has a single account unless currencies are kept apart.
+fn sorted(clients: &HashMap<u16, Client>) -> Vec<&Client> {
+    let mut clients = clients.values().collect::<Vec<_>>();
+    clients.sort_unstable_by_key(|client| client.get_id());
+
+    clients.into_iter().flat_map(Client::accounts).collect()
+}
+
+/// Write the clients as CSV rows, one per client by ascending id, with the given columns.
+///
+/// When `flush_per_row` is set every row is flushed to the underlying writer as soon as it is written, so a
+/// consumer on the other end of a pipe sees each client immediately. This costs one write (and usually one
+/// system call) per row instead of one per buffer, which is much slower for large outputs.
+pub async fn write_csv<W>(
+    writer: W,
+    clients: &HashMap<u16, Client>,
+    flush_per_row: bool,
+    bool_format: BoolFormat,
+    columns: &[Column],
+) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut writer = csv_async::AsyncWriter::from_writer(writer);
+    writer
+        .write_record(columns.iter().copied().map(Column::name))
+        .await?;
+
+    for client in sorted(clients) {
+        writer
+            .write_record(
+                columns
+                    .iter()
+                    .map(|column| column.value(client, bool_format))
+                    .collect::<Result<Vec<_>>>()?,
+            )
+            .await?;
+
+        if flush_per_row {
+            writer.flush().await?;
+        }
+    }
+
+    writer.flush().await?;
+
+    Ok(())
+}
+
+/// Write clients as CSV rows in the order they come from a stream, instead of sorted, see `write_csv`. The first
+/// error of the stream is returned after the rows before it are written.
+pub async fn write_csv_stream<W, S>(
+    writer: W,
+    clients: S,
+    flush_per_row: bool,
+    bool_format: BoolFormat,
+    columns: &[Column],
+) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+    S: Stream<Item = Result<Client>>,
+{
+    let mut clients = pin!(clients);
+    let mut writer = csv_async::AsyncWriter::from_writer(writer);
+    writer
+        .write_record(columns.iter().copied().map(Column::name))
+        .await?;
+
+    while let Some(client) = clients.next().await {
+        let client = client?;
+        for account in client.accounts() {
+            writer
+                .write_record(
+                    columns
+                        .iter()
+                        .map(|column| column.value(account, bool_format))
+                        .collect::<Result<Vec<_>>>()?,
+                )
+                .await?;
+        }
+
+        if flush_per_row {
+            writer.flush().await?;
+        }
+    }
+
+    writer.flush().await?;
+
+    Ok(())
+}
+
+/// Object of a client in the JSON output, with its keys in the order of the columns.
+struct JsonRow<'a> {
+    client: &'a Client,
+    bool_format: BoolFormat,
+    columns: &'a [Column],
+}
+
+impl Serialize for JsonRow<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut row = serializer.serialize_map(Some(self.columns.len()))?;
+        for column in self.columns {
+            let value = column
+                .json_value(self.client, self.bool_format)
+                .map_err(serde::ser::Error::custom)?;
+            row.serialize_entry(column.name(), &value)?;
+        }
+
+        row.end()
+    }
+}
+
+/// Write the clients as a JSON array, one object per client by ascending id, with the given columns as keys.
+pub async fn write_json<W>(
+    mut writer: W,
+    clients: &HashMap<u16, Client>,
+    bool_format: BoolFormat,
+    columns: &[Column],
+) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let rows = sorted(clients)
+        .into_iter()
+        .map(|client| JsonRow {
+            client,
+            bool_format,
+            columns,
+        })
+        .collect::<Vec<_>>();
+
+    let mut json = serde_json::to_vec(&rows)?;
+    json.push(b'\n');
+    writer.write_all(&json).await?;
+    writer.flush().await?;
+
+    Ok(())
+}
+
+/// Write the clients as an aligned table, one row per client by ascending id, with the given columns. Only meant
+/// for reading, the CSV output is the one to parse.
+pub async fn write_table<W>(
+    mut writer: W,
+    clients: &HashMap<u16, Client>,
+    bool_format: BoolFormat,
+    columns: &[Column],
+) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut table = Table::new();
+    table
+        .load_preset(presets::ASCII_FULL_CONDENSED)
+        .set_header(columns.iter().copied().map(Column::name));
+
+    for client in sorted(clients) {
+        table.add_row(
+            columns
+                .iter()
+                .map(|column| column.value(client, bool_format))
+                .collect::<Result<Vec<_>>>()?,
+        );
+    }
+
+    for (index, column) in columns.iter().enumerate() {
+        if matches!(
+            column,
+            Column::Client | Column::Available | Column::Held | Column::Total
+        ) {
+            if let Some(table_column) = table.column_mut(index) {
+                table_column.set_cell_alignment(CellAlignment::Right);
+            }
+        }
+    }
+
+    writer.write_all(format!("{table}\n").as_bytes()).await?;
+    writer.flush().await?;
+
+    Ok(())
+}
+
+/// Write stored transactions as CSV rows: the client, tx id, type and amount of each, and whether it is disputed.
+pub async fn write_tx_store<W>(writer: W, stored: &[StoredTransaction]) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut writer = csv_async::AsyncWriter::from_writer(writer);
+    writer
+        .write_record(["client", "tx", "type", "amount", "disputed"])
+        .await?;
+
+    for stored in stored {
+        writer
+            .write_record([
+                stored.client.to_string(),
+                stored.record.tx_id.to_string(),
+                stored.record.transaction_type.name().to_string(),
+                stored.record.amount.to_string(),
+                stored.record.disputed.to_string(),
+            ])
+            .await?;
+    }
+
+    writer.flush().await?;
+
+    Ok(())
+}
+
+/// Writer that keeps a SHA-256 of every byte written through it, as it is written.
+pub struct ChecksumWriter<W> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W> ChecksumWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// Hex digest of everything written so far.
+    pub fn hex_digest(&self) -> String {
+        self.hasher
+            .clone()
+            .finalize()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+}
+
+impl<W> AsyncWrite for ChecksumWriter<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+
+        // Only the bytes the inner writer accepted are written, the rest will be offered again.
+        if let Poll::Ready(Ok(written)) = poll {
+            self.hasher.update(&buf[..written]);
+        }
+
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Writer that retries a write, flush or shutdown that failed with a transient error, such as a sink that timed
+/// out, up to a number of times with a doubling backoff, before giving up with the error. Failed writes didn't
+/// write anything, so retrying them writes every byte exactly once.
+pub struct RetryWriter<W> {
+    inner: W,
+    retries: u32,
+
+    /// Retries of the current operation so far, and the backoff before the next one.
+    attempts: u32,
+    backoff: Option<Pin<Box<Sleep>>>,
+}
+
+impl<W> RetryWriter<W> {
+    pub fn new(inner: W, retries: u32) -> Self {
+        Self {
+            inner,
+            retries,
+            attempts: 0,
+            backoff: None,
+        }
+    }
+
+    /// Poll an operation of the inner writer, retrying it after a backoff while it fails transiently.
+    fn poll_retrying<T>(
+        &mut self,
+        cx: &mut Context<'_>,
+        mut operation: impl FnMut(Pin<&mut W>, &mut Context<'_>) -> Poll<io::Result<T>>,
+    ) -> Poll<io::Result<T>>
+    where
+        W: Unpin,
+    {
+        loop {
+            if let Some(backoff) = &mut self.backoff {
+                ready!(backoff.as_mut().poll(cx));
+                self.backoff = None;
+            }
+
+            match ready!(operation(Pin::new(&mut self.inner), cx)) {
+                Err(error) if is_transient(&error) && self.attempts < self.retries => {
+                    self.backoff = Some(Box::pin(sleep(
+                        WRITE_RETRY_BACKOFF * 2u32.pow(self.attempts),
+                    )));
+                    self.attempts += 1;
+                }
+                result => {
+                    self.attempts = 0;
+                    return Poll::Ready(result);
+                }
+            }
+        }
+    }
+}
+
+/// Whether a write error may go away when the write is retried.
+fn is_transient(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        ErrorKind::Interrupted
+            | ErrorKind::WouldBlock
+            | ErrorKind::TimedOut
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+    )
+}
+
+impl<W> AsyncWrite for RetryWriter<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.poll_retrying(cx, |inner, cx| inner.poll_write(cx, buf))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.poll_retrying(cx, |inner, cx| inner.poll_flush(cx))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.poll_retrying(cx, |inner, cx| inner.poll_shutdown(cx))
+    }
+}
+
+/// Write the clients as a Parquet file with the given columns, typed as described by `schema`. The amounts are
+/// written as decimals, all with the largest scale found among them so no precision is lost.
+pub fn write_parquet(
+    path: impl AsRef<Path>,
+    clients: &HashMap<u16, Client>,
+    columns: &[Column],
+) -> Result<()> {
+    let clients = sorted(clients);
+    // Checked first, so no total written below is clamped.
+    for client in &clients {
+        total(client)?;
+    }
+    let scale = clients
+        .iter()
+        .flat_map(|client| {
+            [
+                client.get_available(),
+                client.get_held(),
+                client.get_total(),
+            ]
+        })
+        .map(|amount| amount.scale())
+        .max()
+        .unwrap_or_default();
+
+    let decimal_type = DataType::Decimal128(38, scale as i8);
+    let decimals = |amount: fn(&Client) -> Decimal| -> Result<ArrayRef> {
+        let mantissas = clients
+            .iter()
+            .map(|client| {
+                let mut amount = amount(client);
+                amount.rescale(scale);
+                ensure!(
+                    amount.scale() == scale,
+                    "Fail to rescale {amount} to {scale} decimal places."
+                );
+
+                Ok(amount.mantissa())
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Arc::new(
+            Decimal128Array::from(mantissas).with_precision_and_scale(38, scale as i8)?,
+        ))
+    };
+
+    let booleans = |value: fn(&Client) -> bool| -> ArrayRef {
+        Arc::new(BooleanArray::from_iter(
+            clients.iter().map(|client| Some(value(client))),
+        ))
+    };
+
+    let (fields, arrays) = columns
+        .iter()
+        .map(|column| {
+            let (data_type, array) = match column {
+                Column::Client => (
+                    DataType::UInt16,
+                    Arc::new(UInt16Array::from_iter_values(
+                        clients.iter().map(|client| client.get_id()),
+                    )) as ArrayRef,
+                ),
+                Column::Currency => (
+                    DataType::Utf8,
+                    Arc::new(StringArray::from_iter_values(clients.iter().map(
+                        |client| {
+                            client
+                                .get_currency()
+                                .map(|currency| currency.to_string())
+                                .unwrap_or_default()
+                        },
+                    ))) as ArrayRef,
+                ),
+                Column::Available => (decimal_type.clone(), decimals(Client::get_available)?),
+                Column::Held => (decimal_type.clone(), decimals(Client::get_held)?),
+                Column::Total => (decimal_type.clone(), decimals(Client::get_total)?),
+                Column::Locked => (DataType::Boolean, booleans(Client::is_locked)),
+                Column::Flagged => (DataType::Boolean, booleans(Client::is_flagged)),
+            };
+
+            Ok((Field::new(column.name(), data_type, false), array))
+        })
+        .collect::<Result<(Vec<_>, Vec<_>)>>()?;
+
+    let schema = Arc::new(Schema::new(fields));
+    let batch = RecordBatch::try_new(schema.clone(), arrays)?;
+
+    let mut writer = ArrowWriter::try_new(File::create(path)?, schema, None)?;
+    writer.write(&batch)?;
+    writer.close()?;
+
+    Ok(())
+}
+
+/// Write only the clients that were added or changed since the baseline results, as CSV rows with the given
+/// columns and a last `change` column saying which. Clients removed since the baseline aren't written.
+pub async fn write_delta_csv<W>(
+    writer: W,
+    baseline: &HashMap<u16, Client>,
+    clients: &HashMap<u16, Client>,
+    bool_format: BoolFormat,
+    columns: &[Column],
+) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut writer = csv_async::AsyncWriter::from_writer(writer);
+    writer
+        .write_record(
+            columns
+                .iter()
+                .copied()
+                .map(Column::name)
+                .chain(["change"])
+                .collect::<Vec<_>>(),
+        )
+        .await?;
+
+    for (id, change) in verify::diff(baseline, clients) {
+        let (Change::Added | Change::Changed, Some(client)) = (change, clients.get(&id)) else {
+            continue;
+        };
+
+        for account in client.accounts() {
+            writer
+                .write_record(
+                    columns
+                        .iter()
+                        .map(|column| column.value(account, bool_format))
+                        .chain([Ok(change.name().to_string())])
+                        .collect::<Result<Vec<_>>>()?,
+                )
+                .await?;
+        }
+    }
+
+    writer.flush().await?;
+
+    Ok(())
+}
+
+/// Row of a previous CSV output. The total is left out, it follows from the balances.
+#[derive(Debug, Deserialize)]
+struct OutputRow {
+    client: u16,
+
+    /// Currency of the account, when currencies were kept apart.
+    #[serde(default)]
+    currency: Option<String>,
+    available: Decimal,
+    held: Decimal,
+    locked: String,
+
+    /// Whether the client was flagged, when a rule could flag clients.
+    #[serde(default)]
+    flagged: Option<String>,
+}
+
+/// Parse a bool written in either bool format.
+fn parse_bool(value: &str) -> Option<bool> {
+    match value {
+        "true" | "1" => Some(true),
+        "false" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+/// Read the clients back from a previous CSV output, with `locked` and `flagged` in either bool format, and each
+/// row with a currency as the account of its client in it. Other extra columns are ignored.
+pub async fn read_csv<R>(reader: R) -> Result<HashMap<u16, Client>>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    let mut reader = csv_async::AsyncReaderBuilder::new()
+        .trim(csv_async::Trim::All)
+        .create_reader(reader);
+    let headers = reader.headers().await?.clone();
+
+    let mut clients = HashMap::new();
+    let mut record = csv_async::StringRecord::new();
+    while reader.read_record(&mut record).await? {
+        let row = record.deserialize::<OutputRow>(Some(&headers))?;
+        let Some(locked) = parse_bool(&row.locked) else {
+            bail!(
+                "Client {} has an invalid locked value: {}.",
+                row.client,
+                row.locked
+            );
+        };
+        let flagged = match row.flagged.as_deref().filter(|flagged| !flagged.is_empty()) {
+            Some(flagged) => parse_bool(flagged).with_context(|| {
+                format!(
+                    "Client {} has an invalid flagged value: {flagged}.",
+                    row.client
+                )
+            })?,
+            None => false,
+        };
+
+        let currency = row
+            .currency
+            .filter(|currency| !currency.is_empty())
+            .map(|currency| currency.parse::<Currency>())
+            .transpose()?;
+        let mut account =
+            Client::restored(row.client, row.available, row.held, locked).in_currency(currency);
+        if flagged {
+            account.flag();
+        }
+        clients
+            .entry(row.client)
+            .or_insert_with(|| Client::new(row.client))
+            .insert_account(account);
+    }
+
+    Ok(clients)
+}
+
+/// Write a CSV report of the locked clients with the chargeback that locked each of them.
+pub async fn write_locked_report<W>(writer: W, clients: &HashMap<u16, Client>) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut writer = csv_async::AsyncWriter::from_writer(writer);
+    writer
+        .write_record(&["client", "tx", "amount", "available", "held", "total"])
+        .await?;
+
+    for client in sorted(clients) {
+        if let Some(trigger) = client.get_lock_trigger() {
+            writer
+                .write_record(&[
+                    client.get_id().to_string(),
+                    trigger.tx_id.to_string(),
+                    client::format_amount(trigger.amount),
+                    client.format_available(),
+                    client.format_held(),
+                    total(client)?,
+                ])
+                .await?;
+        }
+    }
+
+    writer.flush().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        client::LockTrigger,
+        ledger::Ledger,
+        transaction::{Transaction, TransactionType},
+    };
+    use rust_decimal::Decimal;
+
+    /// Writer that records every write and flush it receives.
+    #[derive(Default)]
+    struct RecordingWriter {
+        events: Vec<String>,
+    }
+
+    impl AsyncWrite for RecordingWriter {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            self.events.push(String::from_utf8_lossy(buf).into_owned());
+
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            self.events.push("<flush>".into());
+
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// Writer that fails a number of writes with a timeout, then writes into a buffer.
+    struct FlakyWriter {
+        failures: usize,
+        written: Vec<u8>,
+    }
+
+    impl AsyncWrite for FlakyWriter {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Poll::Ready(Err(ErrorKind::TimedOut.into()));
+            }
+
+            self.written.extend_from_slice(buf);
+
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn clients() -> HashMap<u16, Client> {
+        (1..=2).map(|id| (id, Client::new(id))).collect()
+    }
+
+    /// Test that every row is written to a sink that times out a few times, and that the retries are bounded.
+    #[tokio::test(start_paused = true)]
+    async fn test_retry_writer() {
+        let mut writer = RetryWriter::new(
+            FlakyWriter {
+                failures: 3,
+                written: Vec::new(),
+            },
+            3,
+        );
+        write_csv(&mut writer, &clients(), true, BoolFormat::Word, &COLUMNS)
+            .await
+            .unwrap();
+
+        let written = String::from_utf8(writer.inner.written).unwrap();
+        let mut rows = written.lines().collect::<Vec<_>>();
+        rows.sort();
+        assert_eq!(
+            rows,
+            [
+                "1,0.0000,0.0000,0.0000,false",
+                "2,0.0000,0.0000,0.0000,false",
+                "client,available,held,total,locked"
+            ]
+        );
+
+        let mut writer = RetryWriter::new(
+            FlakyWriter {
+                failures: 4,
+                written: Vec::new(),
+            },
+            3,
+        );
+        assert!(
+            write_csv(&mut writer, &clients(), true, BoolFormat::Word, &COLUMNS)
+                .await
+                .is_err()
+        );
+    }
+
+    /// Test that the header has the documented column order.
+    #[tokio::test]
+    async fn test_column_order() {
+        let mut clients = HashMap::new();
+        clients.insert(
+            1,
+            Client::with_balances(1, Decimal::new(3, 0), Decimal::new(2, 0)),
+        );
+
+        let mut writer = RecordingWriter::default();
+        write_csv(&mut writer, &clients, false, BoolFormat::Word, &COLUMNS)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            writer.events.concat().replace("<flush>", ""),
+            "client,available,held,total,locked\n1,3.0000,2.0000,5.0000,false\n"
+        );
+    }
+
+    /// Test that the rows are written by ascending client id, whatever order the clients were processed in.
+    #[tokio::test]
+    async fn test_sorted_rows() {
+        let clients = Ledger::process(
+            Default::default(),
+            [12, 3, 40, 1, 7].map(|client| {
+                Transaction::new(
+                    TransactionType::Deposit,
+                    client,
+                    client.into(),
+                    Some(Decimal::ONE),
+                )
+            }),
+        );
+
+        let mut rows = Vec::new();
+        write_csv(&mut rows, &clients, false, BoolFormat::Word, &COLUMNS)
+            .await
+            .unwrap();
+
+        let ids = String::from_utf8(rows)
+            .unwrap()
+            .lines()
+            .skip(1)
+            .map(|row| row.split(',').next().unwrap().parse::<u16>().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, [1, 3, 7, 12, 40]);
+    }
+
+    /// Test that the JSON output parses back into the balances and lock state of each client, in id order.
+    #[tokio::test]
+    async fn test_write_json() {
+        #[derive(Deserialize)]
+        struct JsonRow {
+            client: u16,
+            available: Decimal,
+            held: Decimal,
+            total: Decimal,
+            locked: bool,
+        }
+
+        let clients = Ledger::process(
+            Default::default(),
+            [
+                Transaction::new(TransactionType::Deposit, 2, 1, Some(Decimal::new(25, 1))),
+                Transaction::new(TransactionType::Deposit, 1, 2, Some(Decimal::TEN)),
+                Transaction::new(TransactionType::Deposit, 1, 3, Some(Decimal::ONE)),
+                Transaction::new(TransactionType::Dispute, 1, 2, None),
+                Transaction::new(TransactionType::Chargeback, 1, 2, None),
+            ],
+        );
+
+        let mut json = Vec::new();
+        write_json(&mut json, &clients, BoolFormat::Word, &COLUMNS)
+            .await
+            .unwrap();
+
+        let rows = serde_json::from_slice::<Vec<JsonRow>>(&json).unwrap();
+        let rows = rows
+            .iter()
+            .map(|row| (row.client, row.available, row.held, row.total, row.locked))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rows,
+            [
+                (1, Decimal::ONE, Decimal::ZERO, Decimal::ONE, true),
+                (
+                    2,
+                    Decimal::new(25, 1),
+                    Decimal::ZERO,
+                    Decimal::new(25, 1),
+                    false
+                ),
+            ]
+        );
+    }
+
+    /// Test that `locked` is written as 1 for a locked client with the integer format.
+    #[tokio::test]
+    async fn test_int_bool_format() {
+        let mut client = Client::new(1);
+        client.lock_account(LockTrigger {
+            tx_id: 1,
+            amount: Decimal::ONE,
+        });
+        let clients = HashMap::from([(1, client)]);
+
+        let mut writer = RecordingWriter::default();
+        write_csv(&mut writer, &clients, false, BoolFormat::Int, &COLUMNS)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            writer.events.concat().replace("<flush>", ""),
+            "client,available,held,total,locked\n1,0.0000,0.0000,0.0000,1\n"
+        );
+    }
+
+    /// Test that the CSV schema lists the base columns in order, with their types.
+    #[test]
+    fn test_csv_schema() {
+        let schema = schema(OutputFormat::Csv, BoolFormat::Word, &COLUMNS);
+
+        assert_eq!(schema["format"], "csv");
+        assert_eq!(
+            schema["columns"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|column| (
+                    column["name"].as_str().unwrap(),
+                    column["type"].as_str().unwrap()
+                ))
+                .collect::<Vec<_>>(),
+            [
+                ("client", "integer"),
+                ("available", "decimal"),
+                ("held", "decimal"),
+                ("total", "decimal"),
+                ("locked", "boolean"),
+            ]
+        );
+    }
+
+    /// Group the written bytes by the flushes between them.
+    fn flushed_chunks(writer: &RecordingWriter) -> Vec<String> {
+        writer
+            .events
+            .split(|event| event == "<flush>")
+            .filter(|chunk| !chunk.is_empty())
+            .map(|chunk| chunk.concat())
+            .collect()
+    }
+
+    /// Test that every row is followed by a flush when flushing per row.
+    #[tokio::test]
+    async fn test_flush_per_row() {
+        let mut writer = RecordingWriter::default();
+        write_csv(&mut writer, &clients(), true, BoolFormat::Word, &COLUMNS)
+            .await
+            .unwrap();
+
+        let rows = flushed_chunks(&writer);
+
+        // The header goes out with the first row, then the second row on its own.
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].lines().count(), 2);
+        assert_eq!(rows[1].lines().count(), 1);
+    }
+
+    /// Test that rows are buffered together by default.
+    #[tokio::test]
+    async fn test_buffered_rows() {
+        let mut writer = RecordingWriter::default();
+        write_csv(&mut writer, &clients(), false, BoolFormat::Word, &COLUMNS)
+            .await
+            .unwrap();
+
+        let rows = flushed_chunks(&writer);
+
+        // Everything goes out at once, at the end.
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].lines().count(), 3);
+    }
+
+    /// Test that the checksum of the written rows matches one computed over the same bytes afterwards.
+    #[tokio::test]
+    async fn test_checksum_writer() {
+        let mut writer = ChecksumWriter::new(Vec::new());
+        write_csv(&mut writer, &clients(), true, BoolFormat::Word, &COLUMNS)
+            .await
+            .unwrap();
+
+        let expected = Sha256::digest(&writer.inner)
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<String>();
+
+        assert_eq!(writer.hex_digest(), expected);
+        assert_eq!(writer.hex_digest().len(), 64);
+    }
+
+    /// Test that the locked report has the charged-back client with its triggering transaction.
+    #[tokio::test]
+    async fn test_locked_report() {
+        let mut ledger = Ledger::default();
+        for transaction in [
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(Decimal::new(10, 0))),
+            Transaction::new(TransactionType::Deposit, 1, 2, Some(Decimal::new(4, 0))),
+            Transaction::new(TransactionType::Dispute, 1, 2, None),
+            Transaction::new(TransactionType::Chargeback, 1, 2, None),
+            Transaction::new(TransactionType::Deposit, 2, 3, Some(Decimal::new(7, 0))),
+        ] {
+            ledger.apply(transaction);
+        }
+
+        let mut writer = RecordingWriter::default();
+        write_locked_report(&mut writer, ledger.clients())
+            .await
+            .unwrap();
+
+        let report = writer.events.concat().replace("<flush>", "");
+        assert_eq!(
+            report,
+            "client,tx,amount,available,held,total\n1,2,4.0000,10.0000,0.0000,10.0000\n"
+        );
+    }
+
+    /// Test writing a small Parquet file and reading back its schema and values.
+    #[test]
+    fn test_parquet_roundtrip() {
+        use arrow::array::AsArray;
+        use arrow::datatypes::{Decimal128Type, UInt16Type};
+        use arrow::record_batch::RecordBatchReader;
+        use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+        let clients = Ledger::process(
+            Default::default(),
+            [
+                Transaction::new(TransactionType::Deposit, 1, 1, Some(Decimal::new(105, 1))),
+                Transaction::new(TransactionType::Deposit, 1, 2, Some(Decimal::new(25, 2))),
+                Transaction::new(TransactionType::Dispute, 1, 2, None),
+                Transaction::new(TransactionType::Deposit, 2, 3, Some(Decimal::new(3, 0))),
+                Transaction::new(TransactionType::Dispute, 2, 3, None),
+                Transaction::new(TransactionType::Chargeback, 2, 3, None),
+            ],
+        );
+
+        let file = tempfile::NamedTempFile::new().unwrap();
+        write_parquet(file.path(), &clients, &COLUMNS).unwrap();
+
+        let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(file.path()).unwrap())
+            .unwrap()
+            .build()
+            .unwrap();
+
+        let schema = reader.schema();
+        let columns = schema
+            .fields()
+            .iter()
+            .map(|field| (field.name().as_str(), field.data_type().clone()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            columns,
+            [
+                ("client", DataType::UInt16),
+                ("available", DataType::Decimal128(38, 2)),
+                ("held", DataType::Decimal128(38, 2)),
+                ("total", DataType::Decimal128(38, 2)),
+                ("locked", DataType::Boolean),
+            ]
+        );
+
+        // Collected by client id for an order independent comparison.
+        let batches = reader.collect::<Result<Vec<_>, _>>().unwrap();
+        let rows = batches
+            .iter()
+            .flat_map(|batch| {
+                let ids = batch.column(0).as_primitive::<UInt16Type>();
+                let available = batch.column(1).as_primitive::<Decimal128Type>();
+                let held = batch.column(2).as_primitive::<Decimal128Type>();
+                let locked = batch.column(4).as_boolean();
+
+                (0..batch.num_rows())
+                    .map(|row| {
+                        (
+                            ids.value(row),
+                            (
+                                Decimal::from_i128_with_scale(available.value(row), 2),
+                                Decimal::from_i128_with_scale(held.value(row), 2),
+                                locked.value(row),
+                            ),
+                        )
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<HashMap<_, _>>();
+
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[&1], (Decimal::new(105, 1), Decimal::new(25, 2), false));
+        assert_eq!(rows[&2], (Decimal::ZERO, Decimal::ZERO, true));
+    }
+
+    /// Test that every output fails on a client whose total overflows, rather than writing a clamped total.
+    #[tokio::test]
+    async fn test_total_overflow() {
+        let clients = HashMap::from([(7, Client::with_balances(7, Decimal::MAX, Decimal::ONE))]);
+
+        let error = write_csv(Vec::new(), &clients, false, BoolFormat::Word, &COLUMNS)
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "The total of client 7 can't be written.");
+
+        assert!(write_json(Vec::new(), &clients, BoolFormat::Word, &COLUMNS)
+            .await
+            .is_err());
+        let file = tempfile::NamedTempFile::new().unwrap();
+        assert!(write_parquet(file.path(), &clients, &COLUMNS).is_err());
+
+        // Without the total column there is nothing to overflow.
+        write_csv(Vec::new(), &clients, false, BoolFormat::Word, &COLUMNS[..3])
+            .await
+            .unwrap();
+    }
+
+    /// Test that the table has a row of each client by id, with the numbers right-aligned.
+    #[tokio::test]
+    async fn test_write_table() {
+        let clients = Ledger::process(
+            Default::default(),
+            [
+                Transaction::new(TransactionType::Deposit, 12, 1, Some(Decimal::new(3, 0))),
+                Transaction::new(TransactionType::Deposit, 1, 2, Some(Decimal::new(105, 1))),
+                Transaction::new(TransactionType::Withdrawal, 1, 3, Some(Decimal::new(25, 2))),
+            ],
+        );
+
+        let mut table = Vec::new();
+        write_table(&mut table, &clients, BoolFormat::Word, &COLUMNS)
+            .await
+            .unwrap();
+        let table = String::from_utf8(table).unwrap();
+
+        let rows = table
+            .lines()
+            .filter(|line| line.starts_with('|'))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rows,
+            [
+                "| client | available |   held |   total | locked |",
+                "|      1 |   10.2500 | 0.0000 | 10.2500 | false  |",
+                "|     12 |    3.0000 | 0.0000 |  3.0000 | false  |",
+            ]
+        );
+    }
+    /// Test that each currency of a client is a row of its own, and is read back as the same account.
+    #[tokio::test]
+    async fn test_currency_rows() {
+        let mut client = Client::new(1);
+        client
+            .account_mut("USD".parse().ok())
+            .add_available(Decimal::TEN)
+            .unwrap();
+        client
+            .account_mut("EUR".parse().ok())
+            .add_available(Decimal::ONE)
+            .unwrap();
+        let clients = HashMap::from([(1, client)]);
+        let columns = [
+            Column::Client,
+            Column::Currency,
+            Column::Available,
+            Column::Held,
+            Column::Total,
+            Column::Locked,
+        ];
+
+        let mut output = Vec::new();
+        write_csv(&mut output, &clients, false, BoolFormat::Word, &columns)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            String::from_utf8(output.clone()).unwrap(),
+            "client,currency,available,held,total,locked\n\
+             1,EUR,1.0000,0.0000,1.0000,false\n\
+             1,USD,10.0000,0.0000,10.0000,false\n"
+        );
+        assert_eq!(read_csv(&output[..]).await.unwrap(), clients);
+    }
+
+    /// Test that the flag is read back from a previous output, so a client whose only change is its flag differs.
+    #[tokio::test]
+    async fn test_read_flagged() {
+        let mut flagged = Client::new(2);
+        flagged.flag();
+        let clients = HashMap::from([(1, Client::new(1)), (2, flagged)]);
+        let columns = [
+            Column::Client,
+            Column::Available,
+            Column::Held,
+            Column::Total,
+            Column::Locked,
+            Column::Flagged,
+        ];
+
+        let mut output = Vec::new();
+        write_csv(&mut output, &clients, false, BoolFormat::Int, &columns)
+            .await
+            .unwrap();
+
+        assert_eq!(read_csv(&output[..]).await.unwrap(), clients);
+        assert!(read_csv(
+            &b"client,available,held,total,locked,flagged\n1,0,0,0,false,maybe\n"[..]
+        )
+        .await
+        .is_err());
+    }
+
+    /// Test that the Parquet file has the columns its schema describes, including the currency and the flag.
+    #[test]
+    fn test_parquet_schema() {
+        use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+        let mut client = Client::new(1);
+        client.flag();
+        client
+            .account_mut("EUR".parse().ok())
+            .add_available(Decimal::ONE)
+            .unwrap();
+        let clients = HashMap::from([(1, client)]);
+        let columns = [
+            Column::Client,
+            Column::Currency,
+            Column::Available,
+            Column::Held,
+            Column::Total,
+            Column::Locked,
+            Column::Flagged,
+        ];
+
+        let file = tempfile::NamedTempFile::new().unwrap();
+        write_parquet(file.path(), &clients, &columns).unwrap();
+
+        let reader =
+            ParquetRecordBatchReaderBuilder::try_new(File::open(file.path()).unwrap()).unwrap();
+        let written = reader
+            .schema()
+            .fields()
+            .iter()
+            .map(|field| field.name().clone())
+            .collect::<Vec<_>>();
+        let described = schema(OutputFormat::Parquet, BoolFormat::Word, &columns)["columns"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|column| column["name"].as_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+
+        assert_eq!(written, described);
+        assert_eq!(reader.metadata().file_metadata().num_rows(), 2);
+    }
+}
@@ -0,0 +1,642 @@
+use crate::{
+    client::Client,
+    output::{self, BoolFormat, ChecksumWriter, Column, COLUMNS},
+};
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use futures::future;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+use tokio::io::AsyncWrite;
+
+/// Destination of the final results. Every output of the application is a persister, so embedders can add their
+/// own (a message queue, a REST call, a database) next to the built-in files.
+#[async_trait]
+pub trait ResultPersister: Send {
+    async fn persist(&mut self, clients: &HashMap<u16, Client>) -> Result<()>;
+}
+
+/// Persist the results to every one of a list of persisters at once, such as a local file and a remote store.
+/// Every persister is given the results even if another one fails, and the errors of all the failed ones are
+/// reported together.
+pub struct TeePersister {
+    persisters: Vec<Box<dyn ResultPersister>>,
+}
+
+impl TeePersister {
+    pub fn new(persisters: Vec<Box<dyn ResultPersister>>) -> Self {
+        Self { persisters }
+    }
+}
+
+#[async_trait]
+impl ResultPersister for TeePersister {
+    async fn persist(&mut self, clients: &HashMap<u16, Client>) -> Result<()> {
+        let results = future::join_all(
+            self.persisters
+                .iter_mut()
+                .map(|persister| persister.persist(clients)),
+        )
+        .await;
+
+        let errors = results
+            .into_iter()
+            .filter_map(Result::err)
+            .map(|error| format!("{error:#}"))
+            .collect::<Vec<_>>();
+        if !errors.is_empty() {
+            bail!(
+                "{} of {} outputs failed: {}",
+                errors.len(),
+                self.persisters.len(),
+                errors.join("; ")
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Persist the results as CSV rows, one per client.
+pub struct CsvPersister<W> {
+    writer: W,
+    flush_per_row: bool,
+    bool_format: BoolFormat,
+    columns: Vec<Column>,
+}
+
+impl<W> CsvPersister<W> {
+    pub fn new(writer: W, flush_per_row: bool) -> Self {
+        Self {
+            writer,
+            flush_per_row,
+            bool_format: BoolFormat::default(),
+            columns: COLUMNS.to_vec(),
+        }
+    }
+
+    /// Write `locked` in the given format instead of as a word.
+    pub fn with_bool_format(mut self, bool_format: BoolFormat) -> Self {
+        self.bool_format = bool_format;
+        self
+    }
+
+    /// Write the given columns instead of the base ones.
+    pub fn with_columns(mut self, columns: Vec<Column>) -> Self {
+        self.columns = columns;
+        self
+    }
+}
+
+#[async_trait]
+impl<W> ResultPersister for CsvPersister<W>
+where
+    W: AsyncWrite + Unpin + Send,
+{
+    async fn persist(&mut self, clients: &HashMap<u16, Client>) -> Result<()> {
+        output::write_csv(
+            &mut self.writer,
+            clients,
+            self.flush_per_row,
+            self.bool_format,
+            &self.columns,
+        )
+        .await
+    }
+}
+
+/// Persist only the clients that were added or changed since a baseline, as CSV rows with a `change` column,
+/// for incremental reporting.
+pub struct DeltaCsvPersister<W> {
+    writer: W,
+    baseline: HashMap<u16, Client>,
+    bool_format: BoolFormat,
+    columns: Vec<Column>,
+}
+
+impl<W> DeltaCsvPersister<W> {
+    pub fn new(writer: W, baseline: HashMap<u16, Client>) -> Self {
+        Self {
+            writer,
+            baseline,
+            bool_format: BoolFormat::default(),
+            columns: COLUMNS.to_vec(),
+        }
+    }
+
+    /// Write `locked` in the given format instead of as a word.
+    pub fn with_bool_format(mut self, bool_format: BoolFormat) -> Self {
+        self.bool_format = bool_format;
+        self
+    }
+
+    /// Write the given columns, before the `change` one, instead of the base ones.
+    pub fn with_columns(mut self, columns: Vec<Column>) -> Self {
+        self.columns = columns;
+        self
+    }
+}
+
+#[async_trait]
+impl<W> ResultPersister for DeltaCsvPersister<W>
+where
+    W: AsyncWrite + Unpin + Send,
+{
+    async fn persist(&mut self, clients: &HashMap<u16, Client>) -> Result<()> {
+        output::write_delta_csv(
+            &mut self.writer,
+            &self.baseline,
+            clients,
+            self.bool_format,
+            &self.columns,
+        )
+        .await
+    }
+}
+
+/// Persist the results as CSV rows, like `CsvPersister`, and the SHA-256 of the rows in a sidecar file, in the
+/// `sha256sum` format. The checksum is computed while the rows are written, which are always in the same order
+/// for the same results, so the same results always have the same checksum.
+pub struct ChecksumCsvPersister<W> {
+    csv: CsvPersister<ChecksumWriter<W>>,
+    sidecar_path: PathBuf,
+    file_name: String,
+}
+
+impl<W> ChecksumCsvPersister<W> {
+    /// Write the rows to `writer`, which is the file at `path`, and the checksum to `path` plus `.sha256`.
+    pub fn new(writer: W, path: impl AsRef<Path>, flush_per_row: bool) -> Self {
+        let path = path.as_ref();
+        let mut sidecar_path = path.as_os_str().to_owned();
+        sidecar_path.push(".sha256");
+
+        Self {
+            csv: CsvPersister::new(ChecksumWriter::new(writer), flush_per_row),
+            sidecar_path: sidecar_path.into(),
+            file_name: path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned(),
+        }
+    }
+
+    /// Write `locked` in the given format instead of as a word.
+    pub fn with_bool_format(mut self, bool_format: BoolFormat) -> Self {
+        self.csv = self.csv.with_bool_format(bool_format);
+        self
+    }
+
+    /// Write the given columns instead of the base ones.
+    pub fn with_columns(mut self, columns: Vec<Column>) -> Self {
+        self.csv = self.csv.with_columns(columns);
+        self
+    }
+}
+
+#[async_trait]
+impl<W> ResultPersister for ChecksumCsvPersister<W>
+where
+    W: AsyncWrite + Unpin + Send,
+{
+    async fn persist(&mut self, clients: &HashMap<u16, Client>) -> Result<()> {
+        self.csv.persist(clients).await?;
+
+        let checksum = format!("{}  {}\n", self.csv.writer.hex_digest(), self.file_name);
+        tokio::fs::write(&self.sidecar_path, checksum).await?;
+
+        Ok(())
+    }
+}
+
+/// Persist the results as CSV files split by client id, into `clients_0.csv` to `clients_{n-1}.csv` in a
+/// directory. A client goes to the file of its id modulo the number of partitions.
+pub struct PartitionedCsvPersister {
+    directory: PathBuf,
+    partitions: u16,
+    flush_per_row: bool,
+    bool_format: BoolFormat,
+    columns: Vec<Column>,
+}
+
+impl PartitionedCsvPersister {
+    pub fn new(directory: impl Into<PathBuf>, partitions: u16, flush_per_row: bool) -> Self {
+        Self {
+            directory: directory.into(),
+            partitions,
+            flush_per_row,
+            bool_format: BoolFormat::default(),
+            columns: COLUMNS.to_vec(),
+        }
+    }
+
+    /// Write `locked` in the given format instead of as a word.
+    pub fn with_bool_format(mut self, bool_format: BoolFormat) -> Self {
+        self.bool_format = bool_format;
+        self
+    }
+
+    /// Write the given columns instead of the base ones.
+    pub fn with_columns(mut self, columns: Vec<Column>) -> Self {
+        self.columns = columns;
+        self
+    }
+}
+
+#[async_trait]
+impl ResultPersister for PartitionedCsvPersister {
+    async fn persist(&mut self, clients: &HashMap<u16, Client>) -> Result<()> {
+        let mut partitions = vec![HashMap::new(); self.partitions as usize];
+        for (id, client) in clients {
+            partitions[(id % self.partitions) as usize].insert(*id, client.clone());
+        }
+
+        for (index, partition) in partitions.iter().enumerate() {
+            let path = self.directory.join(format!("clients_{index}.csv"));
+            let file = tokio::fs::File::create(path).await?;
+            output::write_csv(
+                file,
+                partition,
+                self.flush_per_row,
+                self.bool_format,
+                &self.columns,
+            )
+            .await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Persist each set of results into a CSV file of its own in a directory, `snapshot_000000.csv` for the first
+/// one, `snapshot_000001.csv` for the next one and so on, so a sequence of snapshots over a run is kept in order.
+pub struct SnapshotPersister {
+    directory: PathBuf,
+    snapshots: usize,
+    bool_format: BoolFormat,
+    columns: Vec<Column>,
+}
+
+impl SnapshotPersister {
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        Self {
+            directory: directory.into(),
+            snapshots: 0,
+            bool_format: BoolFormat::default(),
+            columns: COLUMNS.to_vec(),
+        }
+    }
+
+    /// Write `locked` in the given format instead of as a word.
+    pub fn with_bool_format(mut self, bool_format: BoolFormat) -> Self {
+        self.bool_format = bool_format;
+        self
+    }
+
+    /// Write the given columns instead of the base ones.
+    pub fn with_columns(mut self, columns: Vec<Column>) -> Self {
+        self.columns = columns;
+        self
+    }
+}
+
+#[async_trait]
+impl ResultPersister for SnapshotPersister {
+    async fn persist(&mut self, clients: &HashMap<u16, Client>) -> Result<()> {
+        let path = self
+            .directory
+            .join(format!("snapshot_{:06}.csv", self.snapshots));
+        let file = tokio::fs::File::create(path).await?;
+        output::write_csv(file, clients, false, self.bool_format, &self.columns).await?;
+        self.snapshots += 1;
+
+        Ok(())
+    }
+}
+
+/// Persist the results as a JSON array, one object per client.
+pub struct JsonPersister<W> {
+    writer: W,
+    bool_format: BoolFormat,
+    columns: Vec<Column>,
+}
+
+impl<W> JsonPersister<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            bool_format: BoolFormat::default(),
+            columns: COLUMNS.to_vec(),
+        }
+    }
+
+    /// Write `locked` in the given format instead of as a boolean.
+    pub fn with_bool_format(mut self, bool_format: BoolFormat) -> Self {
+        self.bool_format = bool_format;
+        self
+    }
+
+    /// Write the given columns instead of the base ones.
+    pub fn with_columns(mut self, columns: Vec<Column>) -> Self {
+        self.columns = columns;
+        self
+    }
+}
+
+#[async_trait]
+impl<W> ResultPersister for JsonPersister<W>
+where
+    W: AsyncWrite + Unpin + Send,
+{
+    async fn persist(&mut self, clients: &HashMap<u16, Client>) -> Result<()> {
+        output::write_json(&mut self.writer, clients, self.bool_format, &self.columns).await
+    }
+}
+
+/// Persist the results as an aligned table, one row per client.
+pub struct TablePersister<W> {
+    writer: W,
+    bool_format: BoolFormat,
+    columns: Vec<Column>,
+}
+
+impl<W> TablePersister<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            bool_format: BoolFormat::default(),
+            columns: COLUMNS.to_vec(),
+        }
+    }
+
+    /// Write `locked` in the given format instead of as a boolean.
+    pub fn with_bool_format(mut self, bool_format: BoolFormat) -> Self {
+        self.bool_format = bool_format;
+        self
+    }
+
+    /// Write the given columns instead of the base ones.
+    pub fn with_columns(mut self, columns: Vec<Column>) -> Self {
+        self.columns = columns;
+        self
+    }
+}
+
+#[async_trait]
+impl<W> ResultPersister for TablePersister<W>
+where
+    W: AsyncWrite + Unpin + Send,
+{
+    async fn persist(&mut self, clients: &HashMap<u16, Client>) -> Result<()> {
+        output::write_table(&mut self.writer, clients, self.bool_format, &self.columns).await
+    }
+}
+
+/// Persist the results as a Parquet file. The file is written synchronously, blocking the task meanwhile.
+pub struct ParquetPersister {
+    path: PathBuf,
+    columns: Vec<Column>,
+}
+
+impl ParquetPersister {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            columns: COLUMNS.to_vec(),
+        }
+    }
+
+    /// Write the given columns instead of the base ones.
+    pub fn with_columns(mut self, columns: Vec<Column>) -> Self {
+        self.columns = columns;
+        self
+    }
+}
+
+#[async_trait]
+impl ResultPersister for ParquetPersister {
+    async fn persist(&mut self, clients: &HashMap<u16, Client>) -> Result<()> {
+        output::write_parquet(&self.path, clients, &self.columns)
+    }
+}
+
+/// Persist a report of the locked clients, with the chargeback that locked each of them.
+pub struct LockedReportPersister<W> {
+    writer: W,
+}
+
+impl<W> LockedReportPersister<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+}
+
+#[async_trait]
+impl<W> ResultPersister for LockedReportPersister<W>
+where
+    W: AsyncWrite + Unpin + Send,
+{
+    async fn persist(&mut self, clients: &HashMap<u16, Client>) -> Result<()> {
+        output::write_locked_report(&mut self.writer, clients).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal::Decimal;
+    use std::sync::{Arc, Mutex};
+
+    /// Persister that keeps a copy of what it was given.
+    #[derive(Default)]
+    struct MockPersister {
+        persisted: Vec<HashMap<u16, Client>>,
+    }
+
+    #[async_trait]
+    impl ResultPersister for MockPersister {
+        async fn persist(&mut self, clients: &HashMap<u16, Client>) -> Result<()> {
+            self.persisted.push(clients.clone());
+
+            Ok(())
+        }
+    }
+
+    /// Persister that writes CSV rows to a buffer shared with the test, or fails.
+    struct SharedPersister {
+        rows: Arc<Mutex<Vec<u8>>>,
+        fail: bool,
+    }
+
+    #[async_trait]
+    impl ResultPersister for SharedPersister {
+        async fn persist(&mut self, clients: &HashMap<u16, Client>) -> Result<()> {
+            if self.fail {
+                bail!("The sink is down.");
+            }
+
+            let mut rows = Vec::new();
+            output::write_csv(&mut rows, clients, false, BoolFormat::Word, &COLUMNS).await?;
+            self.rows.lock().unwrap().extend(rows);
+
+            Ok(())
+        }
+    }
+
+    /// Test that every sink of a tee receives the same rows, and that the errors of the failed ones are reported
+    /// together after the others were written.
+    #[tokio::test]
+    async fn test_tee_persister() {
+        let clients = (1..=3)
+            .map(|id| {
+                (
+                    id,
+                    Client::with_balances(id, Decimal::from(id), Decimal::ZERO),
+                )
+            })
+            .collect::<HashMap<_, _>>();
+
+        let sinks = [(); 3].map(|_| Arc::new(Mutex::new(Vec::new())));
+        let persister = |index: usize, fail| -> Box<dyn ResultPersister> {
+            Box::new(SharedPersister {
+                rows: sinks[index].clone(),
+                fail,
+            })
+        };
+
+        let mut tee = TeePersister::new(vec![persister(0, false), persister(1, false)]);
+        tee.persist(&clients).await.unwrap();
+
+        let rows = sinks[0].lock().unwrap().clone();
+        assert_eq!(String::from_utf8(rows.clone()).unwrap().lines().count(), 4);
+        assert_eq!(*sinks[1].lock().unwrap(), rows);
+
+        let mut tee = TeePersister::new(vec![
+            persister(2, true),
+            persister(2, false),
+            persister(2, true),
+        ]);
+        let error = tee.persist(&clients).await.unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "2 of 3 outputs failed: The sink is down.; The sink is down."
+        );
+        assert_eq!(*sinks[2].lock().unwrap(), rows);
+    }
+
+    /// Test that against a baseline only the changed and the new client are written, with their change.
+    #[tokio::test]
+    async fn test_delta_csv_persister() {
+        let baseline = "client,available,held,total,locked\n\
+                        1,5,0,5,false\n\
+                        2,3,1,4,false\n\
+                        3,0,0,0,true\n";
+        let baseline = output::read_csv(baseline.as_bytes()).await.unwrap();
+
+        let clients = HashMap::from([
+            (
+                1,
+                Client::with_balances(1, Decimal::new(50, 1), Decimal::ZERO),
+            ),
+            (2, Client::with_balances(2, Decimal::TWO, Decimal::TWO)),
+            (3, Client::restored(3, Decimal::ZERO, Decimal::ZERO, true)),
+            (4, Client::with_balances(4, Decimal::ONE, Decimal::ZERO)),
+        ]);
+
+        let mut persister = DeltaCsvPersister::new(Vec::new(), baseline);
+        persister.persist(&clients).await.unwrap();
+
+        assert_eq!(
+            String::from_utf8(persister.writer).unwrap(),
+            "client,available,held,total,locked,change\n\
+             2,2.0000,2.0000,4.0000,false,changed\n\
+             4,1.0000,0.0000,1.0000,false,added\n"
+        );
+    }
+
+    /// Test that a custom persister receives the results through the trait object.
+    #[tokio::test]
+    async fn test_mock_persister() {
+        let clients = (1..=3)
+            .map(|id| (id, Client::new(id)))
+            .collect::<HashMap<_, _>>();
+
+        let mut mock = MockPersister::default();
+        {
+            let persister: &mut dyn ResultPersister = &mut mock;
+            persister.persist(&clients).await.unwrap();
+        }
+
+        assert_eq!(mock.persisted, [clients]);
+    }
+
+    /// Test that the CSV persister writes every client.
+    #[tokio::test]
+    async fn test_csv_persister() {
+        let clients = (1..=3)
+            .map(|id| (id, Client::new(id)))
+            .collect::<HashMap<_, _>>();
+
+        let mut persister = CsvPersister::new(Vec::new(), false);
+        persister.persist(&clients).await.unwrap();
+
+        assert_eq!(
+            String::from_utf8(persister.writer).unwrap().lines().count(),
+            4
+        );
+    }
+
+    /// Test that the sidecar has the checksum of the written output file.
+    #[tokio::test]
+    async fn test_checksum_csv_persister() {
+        use sha2::{Digest, Sha256};
+
+        let clients = (1..=3)
+            .map(|id| (id, Client::new(id)))
+            .collect::<HashMap<_, _>>();
+
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("clients.csv");
+        let file = tokio::fs::File::create(&path).await.unwrap();
+
+        let mut persister = ChecksumCsvPersister::new(file, &path, false);
+        persister.persist(&clients).await.unwrap();
+
+        let recomputed = Sha256::digest(std::fs::read(&path).unwrap())
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<String>();
+
+        assert_eq!(
+            std::fs::read_to_string(directory.path().join("clients.csv.sha256")).unwrap(),
+            format!("{recomputed}  clients.csv\n")
+        );
+    }
+
+    /// Test that the partitioned persister splits the clients across its files without overlap.
+    #[tokio::test]
+    async fn test_partitioned_csv_persister() {
+        let clients = (1..=5)
+            .map(|id| (id, Client::new(id)))
+            .collect::<HashMap<_, _>>();
+
+        let directory = tempfile::tempdir().unwrap();
+        let mut persister = PartitionedCsvPersister::new(directory.path(), 2, false);
+        persister.persist(&clients).await.unwrap();
+
+        let ids = |index| {
+            std::fs::read_to_string(directory.path().join(format!("clients_{index}.csv")))
+                .unwrap()
+                .lines()
+                .skip(1)
+                .map(|row| row.split(',').next().unwrap().parse::<u16>().unwrap())
+                .collect::<std::collections::BTreeSet<_>>()
+        };
+
+        assert_eq!(ids(0), [2, 4].into());
+        assert_eq!(ids(1), [1, 3, 5].into());
+    }
+}
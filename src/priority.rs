@@ -0,0 +1,145 @@
+use crate::transaction::{Transaction, TransactionType};
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, VecDeque},
+};
+
+/// Queue of a worker's pending transactions that hands out the most urgent first, by the priority of their type.
+///
+/// A transaction never overtakes an earlier transaction of the same client, so a dispute is still applied before
+/// the resolve or chargeback that follows it. Priority only reorders transactions of different clients: each
+/// client waits in line with the priority of its oldest pending transaction, and clients with the same priority
+/// are served in the order their transactions arrived. Types without a priority have the lowest, zero.
+pub struct PriorityQueue {
+    priorities: HashMap<TransactionType, u8>,
+    pending: HashMap<u16, VecDeque<(u64, Transaction)>>,
+    heads: BinaryHeap<(u8, Reverse<u64>, u16)>,
+    next_sequence: u64,
+}
+
+impl PriorityQueue {
+    pub fn new(priorities: HashMap<TransactionType, u8>) -> Self {
+        Self {
+            priorities,
+            pending: HashMap::new(),
+            heads: BinaryHeap::new(),
+            next_sequence: 0,
+        }
+    }
+
+    pub fn push(&mut self, transaction: Transaction) {
+        let client_id = transaction.get_client_id();
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+
+        let queue = self.pending.entry(client_id).or_default();
+        queue.push_back((sequence, transaction));
+
+        // A client already waiting keeps its place, the new transaction goes after the ones it has pending.
+        if queue.len() == 1 {
+            self.heads.push(self.head_key(client_id));
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<Transaction> {
+        let (_, _, client_id) = self.heads.pop()?;
+        let queue = self.pending.get_mut(&client_id)?;
+        let (_, transaction) = queue.pop_front()?;
+
+        if queue.is_empty() {
+            self.pending.remove(&client_id);
+        } else {
+            self.heads.push(self.head_key(client_id));
+        }
+
+        Some(transaction)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.heads.is_empty()
+    }
+
+    /// Heap key of the oldest pending transaction of a client that has some.
+    fn head_key(&self, client_id: u16) -> (u8, Reverse<u64>, u16) {
+        let (sequence, transaction) = &self.pending[&client_id][0];
+        let priority = self
+            .priorities
+            .get(&transaction.get_type())
+            .copied()
+            .unwrap_or_default();
+
+        (priority, Reverse(*sequence), client_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal::Decimal;
+
+    fn queue() -> PriorityQueue {
+        PriorityQueue::new([(TransactionType::Chargeback, 10)].into())
+    }
+
+    fn pop_all(mut queue: PriorityQueue) -> Vec<(u16, TransactionType)> {
+        std::iter::from_fn(|| queue.pop())
+            .map(|transaction| (transaction.get_client_id(), transaction.get_type()))
+            .collect()
+    }
+
+    /// Test that a queued chargeback jumps ahead of pending deposits of other clients.
+    #[test]
+    fn test_priority_across_clients() {
+        let mut queue = queue();
+        for client in 1..=3 {
+            queue.push(Transaction::new(
+                TransactionType::Deposit,
+                client,
+                client as u32,
+                Some(Decimal::ONE),
+            ));
+        }
+        queue.push(Transaction::new(TransactionType::Chargeback, 4, 9, None));
+
+        assert_eq!(
+            pop_all(queue),
+            [
+                (4, TransactionType::Chargeback),
+                (1, TransactionType::Deposit),
+                (2, TransactionType::Deposit),
+                (3, TransactionType::Deposit),
+            ]
+        );
+    }
+
+    /// Test that a chargeback never overtakes the earlier transactions of its own client.
+    #[test]
+    fn test_client_order_kept() {
+        let mut queue = queue();
+        queue.push(Transaction::new(
+            TransactionType::Deposit,
+            1,
+            1,
+            Some(Decimal::ONE),
+        ));
+        queue.push(Transaction::new(
+            TransactionType::Deposit,
+            2,
+            2,
+            Some(Decimal::ONE),
+        ));
+        queue.push(Transaction::new(TransactionType::Dispute, 2, 2, None));
+        queue.push(Transaction::new(TransactionType::Chargeback, 2, 2, None));
+
+        // Client 2 waits with its deposit's priority, behind client 1, then its transactions follow in order.
+        assert_eq!(
+            pop_all(queue),
+            [
+                (1, TransactionType::Deposit),
+                (2, TransactionType::Deposit),
+                (2, TransactionType::Dispute),
+                (2, TransactionType::Chargeback),
+            ]
+        );
+    }
+}
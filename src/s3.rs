@@ -0,0 +1,138 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use tokio::io::AsyncRead;
+
+/// Location of an S3 object, given as `s3://bucket/key`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct S3Location {
+    pub bucket: String,
+    pub key: String,
+}
+
+impl S3Location {
+    /// Parse an `s3://bucket/key` path, or `None` if it isn't one.
+    pub fn parse(path: &str) -> Option<Self> {
+        let (bucket, key) = path.strip_prefix("s3://")?.split_once('/')?;
+        if bucket.is_empty() || key.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            bucket: bucket.into(),
+            key: key.into(),
+        })
+    }
+}
+
+/// Store the objects are streamed from. The AWS client is one implementation, tests use in-memory ones.
+#[async_trait]
+pub trait ObjectStore: Send + Sync {
+    async fn get_object(&self, location: &S3Location) -> Result<Box<dyn AsyncRead + Unpin + Send>>;
+}
+
+/// Objects streamed from S3, with the credentials and region taken from the environment.
+#[cfg(feature = "aws")]
+pub struct S3ObjectStore {
+    client: aws_sdk_s3::Client,
+}
+
+#[cfg(feature = "aws")]
+impl S3ObjectStore {
+    pub async fn from_env() -> Self {
+        let config = aws_config::load_defaults(aws_config::BehaviorVersion::latest()).await;
+
+        Self {
+            client: aws_sdk_s3::Client::new(&config),
+        }
+    }
+}
+
+#[cfg(feature = "aws")]
+#[async_trait]
+impl ObjectStore for S3ObjectStore {
+    async fn get_object(&self, location: &S3Location) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        let object = self
+            .client
+            .get_object()
+            .bucket(&location.bucket)
+            .key(&location.key)
+            .send()
+            .await?;
+
+        Ok(Box::new(object.body.into_async_read()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{input::TransactionReader, ledger::Ledger};
+    use anyhow::bail;
+    use rust_decimal::Decimal;
+
+    /// Store with a single object.
+    struct MockObjectStore {
+        location: S3Location,
+        body: &'static str,
+    }
+
+    #[async_trait]
+    impl ObjectStore for MockObjectStore {
+        async fn get_object(
+            &self,
+            location: &S3Location,
+        ) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+            if *location != self.location {
+                bail!("No such object.");
+            }
+
+            Ok(Box::new(self.body.as_bytes()))
+        }
+    }
+
+    /// Test parsing S3 paths.
+    #[test]
+    fn test_parse_location() {
+        assert_eq!(
+            S3Location::parse("s3://bucket/path/to/transactions.csv"),
+            Some(S3Location {
+                bucket: "bucket".into(),
+                key: "path/to/transactions.csv".into(),
+            })
+        );
+        assert_eq!(S3Location::parse("s3://bucket"), None);
+        assert_eq!(S3Location::parse("s3:///key"), None);
+        assert_eq!(S3Location::parse("transactions.csv"), None);
+    }
+
+    /// Test streaming an object body into the CSV reader.
+    #[tokio::test]
+    async fn test_read_object() {
+        let store = MockObjectStore {
+            location: S3Location::parse("s3://bucket/transactions.csv").unwrap(),
+            body: "type, client, tx, amount\ndeposit, 1, 1, 3.5\nwithdrawal, 1, 2, 1.0\n",
+        };
+
+        let object = store
+            .get_object(&S3Location::parse("s3://bucket/transactions.csv").unwrap())
+            .await
+            .unwrap();
+
+        let mut reader = TransactionReader::new(object);
+        let mut transactions = Vec::new();
+        while let Some(transaction) = reader.next().await.unwrap() {
+            transactions.push(transaction);
+        }
+
+        let clients = Ledger::process(Default::default(), transactions);
+        assert_eq!(
+            clients.get(&1).unwrap().get_available(),
+            Decimal::new(25, 1)
+        );
+
+        assert!(store
+            .get_object(&S3Location::parse("s3://bucket/other.csv").unwrap())
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,289 @@
+use crate::{
+    config::Config,
+    input::TransactionReader,
+    output::BoolFormat,
+    transaction::Transaction,
+    transaction_processor::{TransactionProcessor, TransactionSubmitter},
+};
+use anyhow::Result;
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::{header, HeaderMap, StatusCode},
+    routing::{get, post},
+    Json, Router,
+};
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+/// State shared by the request handlers.
+#[derive(Clone)]
+struct ServiceState {
+    submitter: Arc<TransactionSubmitter>,
+    processor: Arc<TransactionProcessor>,
+    bool_format: BoolFormat,
+}
+
+/// Routes of the service:
+///
+/// * `POST /transactions` takes a CSV body (or a JSON array when the content type is `application/json`),
+///   submits the transactions to the processor and replies `202 Accepted` with the number of transactions
+///   accepted.
+///   The transactions are tagged with the producer named in the `X-Source` header, if any.
+/// * `GET /clients/{id}` replies with the client's current balances, including every transaction accepted
+///   before the request.
+/// * `GET /stats` replies with the number of transactions processed from each source.
+pub fn router(
+    submitter: Arc<TransactionSubmitter>,
+    processor: Arc<TransactionProcessor>,
+    bool_format: BoolFormat,
+) -> Router {
+    Router::new()
+        .route("/transactions", post(post_transactions))
+        .route("/clients/{id}", get(get_client))
+        .route("/stats", get(get_stats))
+        .with_state(ServiceState {
+            submitter,
+            processor,
+            bool_format,
+        })
+}
+
+/// Serve the HTTP endpoints on the given address until the process is interrupted.
+pub async fn serve(address: &str, config: Config, bool_format: BoolFormat) -> Result<()> {
+    let (submitter, processor) = TransactionProcessor::with_config(config);
+
+    let listener = tokio::net::TcpListener::bind(address).await?;
+    axum::serve(
+        listener,
+        router(Arc::new(submitter), Arc::new(processor), bool_format),
+    )
+    .with_graceful_shutdown(async {
+        tokio::signal::ctrl_c().await.unwrap_or_default();
+    })
+    .await?;
+
+    Ok(())
+}
+
+async fn post_transactions(
+    State(state): State<ServiceState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<(StatusCode, Json<Value>), StatusCode> {
+    let is_json = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("application/json"));
+
+    let transactions = if is_json {
+        serde_json::from_slice::<Vec<Transaction>>(&body).map_err(|_| StatusCode::BAD_REQUEST)?
+    } else {
+        // Invalid rows are skipped, the same way as when reading a file.
+        let mut reader = TransactionReader::new(&body[..]);
+
+        let mut transactions = Vec::new();
+        while let Some(transaction) = reader.next().await.map_err(|_| StatusCode::BAD_REQUEST)? {
+            transactions.push(transaction);
+        }
+
+        transactions
+    };
+
+    let source = headers
+        .get("x-source")
+        .map(|source| source.to_str().map_err(|_| StatusCode::BAD_REQUEST))
+        .transpose()?;
+
+    let accepted = transactions.len();
+    for transaction in transactions {
+        let transaction = match source {
+            Some(source) => transaction.with_source(source),
+            None => transaction,
+        };
+
+        state
+            .submitter
+            .submit(transaction)
+            .await
+            .map_err(|_| StatusCode::SERVICE_UNAVAILABLE)?;
+    }
+
+    Ok((StatusCode::ACCEPTED, Json(json!({ "accepted": accepted }))))
+}
+
+async fn get_client(
+    State(state): State<ServiceState>,
+    Path(id): Path<u16>,
+) -> Result<Json<Value>, StatusCode> {
+    let clients = state
+        .processor
+        .flush_snapshot()
+        .await
+        .map_err(|_| StatusCode::SERVICE_UNAVAILABLE)?;
+
+    let client = clients.get(&id).ok_or(StatusCode::NOT_FOUND)?;
+
+    Ok(Json(json!({
+        "client": client.get_id(),
+        "available": client.get_available(),
+        "held": client.get_held(),
+        "total": client
+            .try_get_total()
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+        "locked": state.bool_format.to_json(client.is_locked()),
+    })))
+}
+
+async fn get_stats(State(state): State<ServiceState>) -> Result<Json<Value>, StatusCode> {
+    let stats = state
+        .processor
+        .flush_stats()
+        .await
+        .map_err(|_| StatusCode::SERVICE_UNAVAILABLE)?;
+
+    Ok(Json(
+        json!({ "processed_by_source": stats.processed_by_source }),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::Request;
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    fn service() -> Router {
+        let (submitter, processor) = TransactionProcessor::new();
+
+        router(
+            Arc::new(submitter),
+            Arc::new(processor),
+            BoolFormat::default(),
+        )
+    }
+
+    async fn send(router: &Router, request: Request<Body>) -> (StatusCode, Value) {
+        let response = router.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+
+        (status, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    /// Test posting a CSV body and then querying the balance of one of its clients.
+    #[tokio::test]
+    async fn test_post_transactions() {
+        let router = service();
+
+        let (status, body) = send(
+            &router,
+            Request::post("/transactions")
+                .header(header::CONTENT_TYPE, "text/csv")
+                .body(Body::from(
+                    "type, client, tx, amount\ndeposit, 1, 1, 10.5\nwithdrawal, 1, 2, 0.5\ndeposit, 2, 3, 1\n",
+                ))
+                .unwrap(),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert_eq!(body, json!({ "accepted": 3 }));
+
+        let (status, body) = send(
+            &router,
+            Request::get("/clients/1").body(Body::empty()).unwrap(),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["available"], "10.0");
+        assert_eq!(body["total"], "10.0");
+        assert_eq!(body["locked"], false);
+    }
+
+    /// Test posting a JSON body.
+    #[tokio::test]
+    async fn test_post_json_transactions() {
+        let router = service();
+
+        let (status, body) = send(
+            &router,
+            Request::post("/transactions")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(
+                    r#"[{"type": "deposit", "client": 7, "tx": 1, "amount": "2.5"}]"#,
+                ))
+                .unwrap(),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert_eq!(body, json!({ "accepted": 1 }));
+
+        let (_, body) = send(
+            &router,
+            Request::get("/clients/7").body(Body::empty()).unwrap(),
+        )
+        .await;
+
+        assert_eq!(body["available"], "2.5");
+    }
+
+    /// Test that transactions from two tagged producers are counted separately.
+    #[tokio::test]
+    async fn test_source_stats() {
+        let router = service();
+
+        for (source, body) in [
+            (
+                "bank-a",
+                "type, client, tx, amount\ndeposit, 1, 1, 10\ndeposit, 2, 2, 5\n",
+            ),
+            ("bank-b", "type, client, tx, amount\nwithdrawal, 1, 3, 1\n"),
+        ] {
+            let (status, _) = send(
+                &router,
+                Request::post("/transactions")
+                    .header("X-Source", source)
+                    .body(Body::from(body))
+                    .unwrap(),
+            )
+            .await;
+
+            assert_eq!(status, StatusCode::ACCEPTED);
+        }
+
+        // Untagged transactions aren't counted under any source.
+        send(
+            &router,
+            Request::post("/transactions")
+                .body(Body::from("type, client, tx, amount\ndeposit, 3, 4, 1\n"))
+                .unwrap(),
+        )
+        .await;
+
+        let (status, body) =
+            send(&router, Request::get("/stats").body(Body::empty()).unwrap()).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            json!({ "processed_by_source": { "bank-a": 2, "bank-b": 1 } })
+        );
+    }
+
+    /// Test querying a client that never had a transaction.
+    #[tokio::test]
+    async fn test_unknown_client() {
+        let (status, _) = send(
+            &service(),
+            Request::get("/clients/1").body(Body::empty()).unwrap(),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
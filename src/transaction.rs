@@ -1,60 +1,143 @@
+use crate::client::{Currency, MAX_DECIMAL_PLACES};
+use anyhow::{bail, ensure, Result};
 use rust_decimal::Decimal;
-use serde::Deserialize;
+use serde::{de, Deserialize, Deserializer};
+use std::{fmt, str::FromStr};
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum TransactionType {
     Deposit,
     Withdrawal,
     Dispute,
     Resolve,
     Chargeback,
+
+    /// Reinstate an account locked by a chargeback, after a manual review.
+    Unlock,
+}
+
+impl TransactionType {
+    /// Name of the type as it appears in the input.
+    pub fn name(&self) -> &'static str {
+        match self {
+            TransactionType::Deposit => "deposit",
+            TransactionType::Withdrawal => "withdrawal",
+            TransactionType::Dispute => "dispute",
+            TransactionType::Resolve => "resolve",
+            TransactionType::Chargeback => "chargeback",
+            TransactionType::Unlock => "unlock",
+        }
+    }
+}
+
+impl FromStr for TransactionType {
+    type Err = anyhow::Error;
+
+    /// Parse a type name as it appears in the input, in any case.
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            _ if name.eq_ignore_ascii_case("deposit") => Ok(TransactionType::Deposit),
+            _ if name.eq_ignore_ascii_case("withdrawal") => Ok(TransactionType::Withdrawal),
+            _ if name.eq_ignore_ascii_case("dispute") => Ok(TransactionType::Dispute),
+            _ if name.eq_ignore_ascii_case("resolve") => Ok(TransactionType::Resolve),
+            _ if name.eq_ignore_ascii_case("chargeback") => Ok(TransactionType::Chargeback),
+            _ if name.eq_ignore_ascii_case("unlock") => Ok(TransactionType::Unlock),
+
+            _ => bail!("Unknown transaction type {name}."),
+        }
+    }
 }
 
-#[derive(Debug, Deserialize)]
+/// Deserialized from its name in any case, like `FromStr`, without allocating. An unknown name fails the row.
+impl<'de> Deserialize<'de> for TransactionType {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct NameVisitor;
+
+        impl de::Visitor<'_> for NameVisitor {
+            type Value = TransactionType;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a transaction type")
+            }
+
+            fn visit_str<E: de::Error>(self, name: &str) -> Result<Self::Value, E> {
+                name.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_str(NameVisitor)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
 pub struct Transaction {
     #[serde(rename = "type")]
-    ttype: String,
+    ttype: TransactionType,
 
     client: u16,
     tx: u32,
     amount: Option<Decimal>,
+
+    /// Transaction referenced by a dispute, resolve or chargeback, in schemas where it isn't the row's own `tx`.
+    #[serde(default)]
+    ref_tx: Option<u32>,
+
+    /// Producer the transaction came from, when tagged at ingestion.
+    #[serde(skip)]
+    source: Option<String>,
+
+    /// Currency the transaction is in, when the input has a `currency` column, see `Config::currencies`.
+    #[serde(skip)]
+    currency: Option<Currency>,
 }
 
 impl Transaction {
-    #[allow(dead_code)]
     pub fn new(
         transaction_type: TransactionType,
         client: u16,
         tx: u32,
         amount: Option<Decimal>,
     ) -> Self {
-        let ttype = match transaction_type {
-            TransactionType::Deposit => "deposit",
-            TransactionType::Withdrawal => "withdrawal",
-            TransactionType::Dispute => "dispute",
-            TransactionType::Resolve => "resolve",
-            TransactionType::Chargeback => "chargeback",
-        }
-        .into();
-
         Self {
-            ttype,
+            ttype: transaction_type,
             client,
             tx,
             amount,
+            ref_tx: None,
+            source: None,
+            currency: None,
         }
     }
 
-    pub fn get_type(&self) -> Option<TransactionType> {
-        let type_str = self.ttype.to_ascii_lowercase();
-        match type_str.as_str() {
-            "deposit" => Some(TransactionType::Deposit),
-            "withdrawal" => Some(TransactionType::Withdrawal),
-            "dispute" => Some(TransactionType::Dispute),
-            "resolve" => Some(TransactionType::Resolve),
-            "chargeback" => Some(TransactionType::Chargeback),
+    /// The same transaction with the given amount.
+    pub fn with_amount(mut self, amount: Decimal) -> Self {
+        self.amount = Some(amount);
+        self
+    }
 
-            _ => None,
-        }
+    /// Reference another transaction than the row's own `tx`, for a dispute, resolve or chargeback.
+    pub fn with_ref_tx(mut self, ref_tx: u32) -> Self {
+        self.ref_tx = Some(ref_tx);
+        self
+    }
+
+    /// The same transaction in the given currency, or in none.
+    pub fn with_currency(mut self, currency: Option<Currency>) -> Self {
+        self.currency = currency;
+        self
+    }
+
+    /// Tag the transaction with the producer it came from.
+    pub fn with_source(mut self, source: impl Into<String>) -> Self {
+        self.source = Some(source.into());
+        self
+    }
+
+    pub fn get_type(&self) -> TransactionType {
+        self.ttype
     }
 
     pub fn get_client_id(&self) -> u16 {
@@ -68,4 +151,81 @@ impl Transaction {
     pub fn get_amount(&self) -> &Option<Decimal> {
         &self.amount
     }
+
+    /// Id of the transaction a dispute, resolve or chargeback refers to: its `ref_tx`, or its own tx id without
+    /// one. Every other transaction refers to itself.
+    pub fn get_ref_tx_id(&self) -> u32 {
+        match self.ttype {
+            TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback => {
+                self.ref_tx.unwrap_or(self.tx)
+            }
+            _ => self.tx,
+        }
+    }
+
+    pub fn get_source(&self) -> Option<&str> {
+        self.source.as_deref()
+    }
+
+    pub fn get_currency(&self) -> Option<Currency> {
+        self.currency
+    }
+
+    /// Whether the other transaction has the same type, client, transaction ids, amount and currency, such as a
+    /// row repeated by a retry upstream. The source isn't compared.
+    pub fn is_same_row(&self, other: &Transaction) -> bool {
+        self.ttype == other.ttype
+            && self.client == other.client
+            && self.tx == other.tx
+            && self.amount == other.amount
+            && self.ref_tx == other.ref_tx
+            && self.currency == other.currency
+    }
+
+    /// Check the transaction on its own, without any balance: a deposit or withdrawal has an amount, and an
+    /// amount is neither negative nor has more than `MAX_DECIMAL_PLACES` decimal places.
+    pub fn validate(&self) -> Result<()> {
+        let Some(amount) = self.amount else {
+            ensure!(
+                !matches!(
+                    self.ttype,
+                    TransactionType::Deposit | TransactionType::Withdrawal
+                ),
+                "A {} needs an amount.",
+                self.ttype.name()
+            );
+            return Ok(());
+        };
+
+        ensure!(
+            !amount.is_sign_negative(),
+            "The amount {amount} is negative."
+        );
+        ensure!(
+            amount.normalize().scale() <= MAX_DECIMAL_PLACES,
+            "The amount {amount} has more than {MAX_DECIMAL_PLACES} decimal places."
+        );
+
+        Ok(())
+    }
+
+    /// Turn a deposit with a negative amount into the equivalent withdrawal, and a withdrawal with a negative
+    /// amount into the equivalent deposit. Every other transaction is returned unchanged.
+    pub fn with_normalized_sign(self) -> Self {
+        let transaction_type = match self.ttype {
+            TransactionType::Deposit => TransactionType::Withdrawal,
+            TransactionType::Withdrawal => TransactionType::Deposit,
+            _ => return self,
+        };
+
+        match self.amount {
+            Some(amount) if amount.is_sign_negative() => Self {
+                ref_tx: self.ref_tx,
+                source: self.source,
+                currency: self.currency,
+                ..Self::new(transaction_type, self.client, self.tx, Some(-amount))
+            },
+            _ => self,
+        }
+    }
 }
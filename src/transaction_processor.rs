@@ -1,141 +1,726 @@
 use crate::{
     client::Client,
+    config::{Config, DisputePolicy},
+    input::{self, TransactionReader},
+    ledger::{
+        ClientSeed, DisputableRecord, DisputeState, Ledger, LedgerStats, RejectedTransaction,
+        RejectionReason, StoredTransaction, TxIdSet,
+    },
+    priority::PriorityQueue,
     transaction::{Transaction, TransactionType},
 };
-use anyhow::{Error, Result};
-use std::collections::HashMap;
-use tokio::{sync::mpsc, task::JoinHandle};
+use anyhow::{ensure, Context, Error, Result};
+use futures::{stream, Stream};
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+
+/// Message to the load balancer: a transaction, or a request served at its position among them, after every
+/// transaction sent before it and before any sent after it.
+enum Request {
+    Transaction(Transaction),
+    Snapshot(oneshot::Sender<HashMap<u16, Client>>),
+    Stats(oneshot::Sender<LedgerStats>),
+    Transactions(u16, oneshot::Sender<Vec<DisputableRecord>>),
+    DisputeState(u16, u32, oneshot::Sender<Option<DisputeState>>),
+}
+
+/// Message sent by the load balancer to a worker.
+enum WorkerMessage {
+    Transaction(Transaction),
+    Snapshot(oneshot::Sender<HashMap<u16, Client>>),
+    Stats(oneshot::Sender<LedgerStats>),
+    Transactions(u16, oneshot::Sender<Vec<DisputableRecord>>),
+    DisputeState(u16, u32, oneshot::Sender<Option<DisputeState>>),
+    Seed(ClientSeed),
+
+    /// A deposit or withdrawal took the tx id that the next dispute refers to, see `Ledger::take_tx_id`.
+    TxIdTaken(u32),
+}
+
+/// Clients buffered in a results stream before the load balancer waits for them to be consumed.
+const RESULTS_STREAM_CAPACITY: usize = 1024;
+
+/// Channel to a worker and the handle to its results.
+type Worker = (mpsc::Sender<WorkerMessage>, JoinHandle<Ledger>);
+
+/// Counters of a whole run, merged across the workers.
+pub type ProcessingStats = LedgerStats;
+
+/// Everything the load balancer has once every worker is done.
+struct Outcome {
+    clients_by_worker: Vec<HashMap<u16, Client>>,
+    stats: ProcessingStats,
+    rejected: Vec<RejectedTransaction>,
+
+    /// Stored transactions of every worker, when collected.
+    stored: Vec<StoredTransaction>,
+}
+
+/// Handle to submit transactions to a `TransactionProcessor`, the only one of its transactions channel. It can't
+/// be cloned, and getting the results takes it by value, which closes the channel before the results are awaited.
+/// So the processor can't be left waiting for transactions that will never come.
+#[derive(Debug)]
+pub struct TransactionSubmitter {
+    request_tx: mpsc::Sender<Request>,
+}
+
+impl TransactionSubmitter {
+    /// Submit a transaction, waiting while the channel is full, so a reader faster than the workers is slowed down
+    /// to their pace instead of queueing the whole input in memory.
+    pub async fn submit(&self, transaction: Transaction) -> Result<()> {
+        self.request_tx
+            .send(Request::Transaction(transaction))
+            .await
+            .ok()
+            .context("The transaction processor has already finished.")
+    }
+}
 
 /// Process transactions in parallel by distributing them to workers by their client id.
+///
+/// The transactions of a client are always applied in the order they were submitted: they all go to the same
+/// worker, through channels that preserve their order, so a dispute is always applied before the resolve or
+/// chargeback that follows it in the input. Only transactions of different clients may be applied out of order
+/// relative to each other.
+///
+/// Workers are spawned lazily, the first time a client routes to them, so an input with few clients only costs
+/// as many workers as it needs.
 pub struct TransactionProcessor {
-    join_handle: JoinHandle<Result<HashMap<u16, Client>, Error>>,
+    /// Transactions channel of the submitter, to send the requests in between the transactions and to check that
+    /// the results are asked with it. Weak, so dropping the submitter still closes it.
+    request_tx: mpsc::WeakSender<Request>,
+    join_handle: JoinHandle<Result<Outcome, Error>>,
+    spawned_workers: Arc<AtomicUsize>,
+
+    /// Where to send the stream of the results to, if they are streamed, see `get_results_stream`.
+    stream_tx: Option<oneshot::Sender<mpsc::Sender<Result<Client>>>>,
+}
+
+/// Builder of a `TransactionProcessor`, from the default config, for the options set one at a time.
+#[derive(Default)]
+pub struct TransactionProcessorBuilder {
+    config: Config,
+    seed: Vec<ClientSeed>,
+}
+
+impl TransactionProcessorBuilder {
+    /// Start from a whole config, replacing the options set so far.
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Number of workers to spawn, see `Config::workers`.
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.config.workers = Some(workers);
+        self
+    }
+
+    /// Capacity of the transactions channel and of each worker's, see `Config::channel_capacity`.
+    pub fn channel_capacity(mut self, channel_capacity: usize) -> Self {
+        self.config.channel_capacity = Some(channel_capacity);
+        self
+    }
+
+    pub fn dispute_policy(mut self, dispute_policy: DisputePolicy) -> Self {
+        self.config.dispute_policy = dispute_policy;
+        self
+    }
+
+    /// Clients of a previous run to resume from, see `TransactionProcessor::with_seed`.
+    pub fn seed(mut self, seed: Vec<ClientSeed>) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// The processor, and the submitter of its transactions.
+    pub fn build(self) -> (TransactionSubmitter, TransactionProcessor) {
+        TransactionProcessor::with_seed(self.config, self.seed)
+    }
 }
 
 impl TransactionProcessor {
-    pub fn new(transaction_rx: mpsc::UnboundedReceiver<Transaction>) -> Self {
+    /// Processor with the default config, and the submitter of its transactions.
+    pub fn new() -> (TransactionSubmitter, Self) {
+        Self::builder().build()
+    }
+
+    pub fn builder() -> TransactionProcessorBuilder {
+        Default::default()
+    }
+
+    /// Index of the worker, out of `workers`, that a client is routed to, always the same one for the same
+    /// client. The id is hashed first, with the finalizer of MurmurHash3, so ids that cluster around multiples of
+    /// the number of workers, like sequential blocks, are still spread evenly. Panics if `workers` is 0, as
+    /// `Config::worker_count` never is.
+    pub fn worker_index(client_id: u16, workers: usize) -> usize {
+        assert!(workers >= 1, "A client can't be routed to no worker.");
+
+        let mut hash = u64::from(client_id);
+        hash = (hash ^ (hash >> 33)).wrapping_mul(0xff51_afd7_ed55_8ccd);
+        hash = (hash ^ (hash >> 33)).wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+        hash ^= hash >> 33;
+
+        (hash % workers as u64) as usize
+    }
+
+    /// Process a CSV file of transactions, gzipped or not, with the default config, and return the clients.
+    /// Malformed rows are skipped, as by `TransactionReader`.
+    pub async fn process_file(path: impl AsRef<Path>) -> Result<HashMap<u16, Client>> {
+        let path = path.as_ref();
+        let file = tokio::fs::File::open(path)
+            .await
+            .with_context(|| format!("Fail to open {}.", path.display()))?;
+        let mut reader = TransactionReader::new(input::decompressed(Box::new(file)).await?);
+
+        let (submitter, processor) = Self::new();
+        while let Some(transaction) = reader.next().await? {
+            submitter.submit(transaction).await?;
+        }
+
+        // Getting the results takes the submitter, which closes the channel before waiting for the workers.
+        processor.get_results(submitter).await
+    }
+
+    pub fn with_config(config: Config) -> (TransactionSubmitter, Self) {
+        Self::with_seed(config, Vec::new())
+    }
+
+    /// Resume from the clients of a previous run, with their open disputes, before applying any transaction.
+    pub fn with_seed(config: Config, seed: Vec<ClientSeed>) -> (TransactionSubmitter, Self) {
+        let (request_tx, request_rx) = mpsc::channel(config.channel_capacity());
+
         // Create the load balancer.
-        let join_handle = tokio::spawn(Self::load_balancer(transaction_rx));
+        let (stream_tx, stream_rx) = oneshot::channel();
+        let spawned_workers = Arc::new(AtomicUsize::new(0));
+        let join_handle = tokio::spawn(Self::load_balancer(
+            request_rx,
+            stream_rx,
+            config,
+            seed,
+            spawned_workers.clone(),
+        ));
+
+        let processor = Self {
+            request_tx: request_tx.downgrade(),
+            join_handle,
+            spawned_workers,
+            stream_tx: Some(stream_tx),
+        };
 
-        Self { join_handle }
+        (TransactionSubmitter { request_tx }, processor)
     }
 
-    pub async fn get_results(self) -> Result<HashMap<u16, Client>, Error> {
+    /// Number of workers spawned so far.
+    pub fn spawned_workers(&self) -> usize {
+        self.spawned_workers.load(Ordering::Relaxed)
+    }
+
+    pub async fn get_results(
+        self,
+        submitter: TransactionSubmitter,
+    ) -> Result<HashMap<u16, Client>, Error> {
+        let (clients, _, _) = self.finalize(submitter).await?;
+
+        Ok(clients)
+    }
+
+    /// Close the transactions channel, wait for every transaction to be applied, and return the clients, the
+    /// counters of the whole run, and every rejected transaction with the reason, in the order each worker
+    /// rejected them. The rejected transactions are only kept when `Config::collect_rejections` is set, otherwise
+    /// there are none.
+    pub async fn finalize(
+        self,
+        submitter: TransactionSubmitter,
+    ) -> Result<(
+        HashMap<u16, Client>,
+        ProcessingStats,
+        Vec<RejectedTransaction>,
+    )> {
+        let outcome = self.outcome(submitter).await?;
+        let clients = outcome.clients_by_worker.into_iter().flatten().collect();
+
+        Ok((clients, outcome.stats, outcome.rejected))
+    }
+
+    /// The clients, and the stored transactions of every worker by client id and then transaction id. The
+    /// transactions are only collected when the config asks for them, otherwise there are none.
+    pub async fn get_results_with_store(
+        self,
+        submitter: TransactionSubmitter,
+    ) -> Result<(HashMap<u16, Client>, Vec<StoredTransaction>)> {
+        let outcome = self.outcome(submitter).await?;
+        let clients = outcome.clients_by_worker.into_iter().flatten().collect();
+
+        Ok((clients, outcome.stored))
+    }
+
+    /// The results of each worker, without merging them. The partitioning is the routing of the load balancer:
+    /// the map at index `i` has exactly the clients for which `worker_index` with the number of maps is `i`. A
+    /// worker that was never spawned has an empty map.
+    pub async fn get_results_by_worker(
+        self,
+        submitter: TransactionSubmitter,
+    ) -> Result<Vec<HashMap<u16, Client>>, Error> {
+        Ok(self.outcome(submitter).await?.clients_by_worker)
+    }
+
+    /// The clients as a stream, each worker's as soon as it is done, instead of all of them at once. Only the
+    /// clients waiting to be consumed are kept in memory, so a huge number of them can be written as they come.
+    /// The clients of a worker come in no particular order, and the workers in the order of their index. An error
+    /// of the processor is the last item.
+    pub fn get_results_stream(
+        mut self,
+        submitter: TransactionSubmitter,
+    ) -> impl Stream<Item = Result<Client>> {
+        let (results_tx, results_rx) = mpsc::channel(RESULTS_STREAM_CAPACITY);
+
+        match self.check_submitter(&submitter) {
+            // Sent before the transactions channel is closed, so the load balancer has it once every transaction
+            // is applied.
+            Ok(()) => {
+                if let Some(stream_tx) = self.stream_tx.take() {
+                    stream_tx.send(results_tx.clone()).unwrap_or_default();
+                }
+
+                tokio::spawn(async move {
+                    if let Err(error) = self.outcome(submitter).await {
+                        results_tx.send(Err(error)).await.unwrap_or_default();
+                    }
+                });
+            }
+            Err(error) => results_tx.try_send(Err(error)).unwrap_or_default(),
+        }
+
+        stream::unfold(results_rx, |mut results_rx| async move {
+            let client = results_rx.recv().await?;
+
+            Some((client, results_rx))
+        })
+    }
+
+    /// Close the transactions channel by dropping its submitter, and wait for the load balancer to be done.
+    async fn outcome(self, submitter: TransactionSubmitter) -> Result<Outcome> {
+        self.check_submitter(&submitter)?;
+        drop(submitter);
+
         self.join_handle.await?
     }
 
+    /// Another processor's submitter would leave this one's channel open, and the wait would never end.
+    fn check_submitter(&self, submitter: &TransactionSubmitter) -> Result<()> {
+        ensure!(
+            self.request_tx
+                .upgrade()
+                .is_none_or(|request_tx| request_tx.same_channel(&submitter.request_tx)),
+            "The submitter belongs to another transaction processor."
+        );
+
+        Ok(())
+    }
+
+    /// Take a snapshot of the clients while transactions are still being submitted, such as to monitor a long run.
+    ///
+    /// The request is queued on the transactions channel, behind the transactions already submitted, so it waits
+    /// for room like a transaction does when the channel is full, but never longer than those take to route. The
+    /// snapshot is a consistent point-in-time view: it reflects every transaction whose submission completed
+    /// before this call and none of the ones submitted after the request was queued. The workers keep processing
+    /// afterwards, so later snapshots and the final results include everything. Fails once the submitter is
+    /// dropped.
+    pub async fn flush_snapshot(&self) -> Result<HashMap<u16, Client>> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.request(Request::Snapshot(reply_tx)).await?;
+
+        reply_rx
+            .await
+            .context("The transaction processor has already finished.")
+    }
+
+    /// Counters of the transactions processed so far, with the same point-in-time view as `flush_snapshot`.
+    pub async fn flush_stats(&self) -> Result<LedgerStats> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.request(Request::Stats(reply_tx)).await?;
+
+        reply_rx
+            .await
+            .context("The transaction processor has already finished.")
+    }
+
+    /// The stored transactions of a client that a dispute may refer to, with the same point-in-time view as
+    /// `flush_snapshot`. Empty for an unknown client.
+    pub async fn query_transactions(&self, client_id: u16) -> Result<Vec<DisputableRecord>> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.request(Request::Transactions(client_id, reply_tx))
+            .await?;
+
+        reply_rx
+            .await
+            .context("The transaction processor has already finished.")
+    }
+
+    /// Where a transaction of a client is in the dispute lifecycle, with the same point-in-time view as
+    /// `flush_snapshot`. `None` if the client has no such stored transaction.
+    pub async fn dispute_state(&self, client_id: u16, tx_id: u32) -> Result<Option<DisputeState>> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.request(Request::DisputeState(client_id, tx_id, reply_tx))
+            .await?;
+
+        reply_rx
+            .await
+            .context("The transaction processor has already finished.")
+    }
+
+    async fn request(&self, request: Request) -> Result<()> {
+        self.request_tx
+            .upgrade()
+            .context("The transaction processor has already finished.")?
+            .send(request)
+            .await
+            .ok()
+            .context("The transaction processor has already finished.")
+    }
+
     /// This load balancer uses the client' id to find which worker should process the transaction.
     /// It is a very basic load balancer but has a convenient property: A single worker is responsible for
     /// managing the client state. The clients don't migrate between workers, that way the worker doesn't
     /// need to use any locking mechanism to access the client data, since it's local to the worker in question.
+    ///
+    /// Requests come on the same channel as the transactions and are served at their position: every transaction
+    /// before a request is forwarded before it, and since each worker handles its messages in order, the request
+    /// only sees the transactions before it.
+    ///
+    /// The tx ids of deposits and withdrawals must be unique across every client, which no worker can tell on its
+    /// own, so the load balancer rejects the reused ones before routing them.
     async fn load_balancer(
-        mut rx: mpsc::UnboundedReceiver<Transaction>,
-    ) -> Result<HashMap<u16, Client>> {
-        let worker_join_handlers = {
-            let workers = (0..num_cpus::get())
-                .map(|_| mpsc::unbounded_channel::<Transaction>())
-                .map(|(tx, rx)| {
-                    let join_handle = tokio::spawn(Self::worker(rx));
+        mut rx: mpsc::Receiver<Request>,
+        mut stream_rx: oneshot::Receiver<mpsc::Sender<Result<Client>>>,
+        config: Config,
+        seed: Vec<ClientSeed>,
+        spawned_workers: Arc<AtomicUsize>,
+    ) -> Result<Outcome> {
+        // The workers send their rejections to a task of their own, without waiting on it, which calls the hook
+        // and keeps them if asked to. Without either, the rejections aren't sent at all, nor cloned to be.
+        let collect_rejections = config.collect_rejections;
+        let (rejections, rejections_handle) = if collect_rejections || config.on_reject.is_some() {
+            let (rejections, mut rejections_rx) = mpsc::unbounded_channel::<RejectedTransaction>();
+            let on_reject = config.on_reject.clone();
+            let rejections_handle = tokio::spawn(async move {
+                let mut rejected = Vec::new();
+                while let Some(rejection) = rejections_rx.recv().await {
+                    if let Some(on_reject) = &on_reject {
+                        on_reject.call(&rejection.transaction, rejection.reason);
+                    }
+                    if collect_rejections {
+                        rejected.push(rejection);
+                    }
+                }
 
-                    (tx, join_handle)
-                })
-                .collect::<Vec<_>>();
+                rejected
+            });
 
-            let workers_len = workers.len() as u16;
+            (Some(rejections), Some(rejections_handle))
+        } else {
+            (None, None)
+        };
 
-            while let Some(transaction) = rx.recv().await {
-                // Simple load balance by client id.
-                let worker_index = transaction.get_client_id() % workers_len;
+        // Tx ids of every deposit and withdrawal received, and the counters of the ones rejected here. The tx ids
+        // are kept for the whole run, see `TxIdSet` for their memory cost.
+        let mut tx_ids = TxIdSet::default();
+        let mut own_stats = LedgerStats::default();
 
-                let (tx, _) = &workers[worker_index as usize];
-                tx.send(transaction)?;
-            }
+        let worker_join_handlers = {
+            // Each slot is only filled when the first transaction is routed to it.
+            let mut workers = (0..config.worker_count())
+                .map(|_| None)
+                .collect::<Vec<Option<Worker>>>();
 
-            workers.into_iter().map(|(_, join_handle)| join_handle)
-        };
+            let workers_len = workers.len();
+            let spawn_worker = || {
+                spawned_workers.fetch_add(1, Ordering::Relaxed);
 
-        let mut results = HashMap::new();
-        for join_handle in worker_join_handlers {
-            let result = join_handle.await?;
+                let (tx, rx) = mpsc::channel(config.channel_capacity());
+                (
+                    tx,
+                    tokio::spawn(Self::worker(rx, config.clone(), rejections.clone())),
+                )
+            };
 
-            results.extend(result);
-        }
+            // Seeded clients are sent ahead of every transaction, which spawns their workers even if no
+            // transaction ever routes to them, so the seeded clients are always in the results.
+            for client_seed in seed {
+                tx_ids.extend(
+                    client_seed
+                        .open_disputes
+                        .iter()
+                        .map(|dispute| dispute.tx_id),
+                );
+                let worker_index = Self::worker_index(client_seed.client.get_id(), workers_len);
 
-        Ok(results)
-    }
-
-    async fn worker(mut rx: mpsc::UnboundedReceiver<Transaction>) -> HashMap<u16, Client> {
-        let mut clients = HashMap::new();
-        let mut transactions: HashMap<u32, Transaction> = Default::default();
+                let (tx, _) = workers[worker_index].get_or_insert_with(spawn_worker);
+                tx.send(WorkerMessage::Seed(client_seed)).await?;
+            }
 
-        while let Some(transaction) = rx.recv().await {
-            let client = clients
-                .entry(transaction.get_client_id())
-                .or_insert_with(|| Client::new(transaction.get_client_id()));
+            while let Some(request) = rx.recv().await {
+                match request {
+                    Request::Transaction(transaction) => {
+                        if Self::is_duplicate(&mut tx_ids, &transaction) {
+                            if let Some(source) = transaction.get_source() {
+                                *own_stats
+                                    .processed_by_source
+                                    .entry(source.into())
+                                    .or_default() += 1;
+                            }
+                            own_stats.duplicates += 1;
+                            own_stats.rejected += 1;
 
-            if !client.is_locked() {
-                if let Some(transaction_type) = transaction.get_type() {
-                    match transaction_type {
-                        TransactionType::Deposit => {
-                            if let Some(amount) = transaction.get_amount() {
-                                if client.add_available(*amount).is_ok() {
-                                    transactions.insert(transaction.get_tx_id(), transaction);
-                                }
+                            if let Some(rejections) = &rejections {
+                                // Nobody listening anymore is fine.
+                                rejections
+                                    .send(RejectedTransaction {
+                                        transaction,
+                                        reason: RejectionReason::DuplicateTransaction,
+                                    })
+                                    .unwrap_or_default();
                             }
+                            continue;
                         }
 
-                        TransactionType::Withdrawal => {
-                            if let Some(amount) = transaction.get_amount() {
-                                if client.subtract_available(*amount).is_ok() {
-                                    transactions.insert(transaction.get_tx_id(), transaction);
-                                }
-                            }
+                        // Load balance by the hash of the client id.
+                        let worker_index =
+                            Self::worker_index(transaction.get_client_id(), workers_len);
+
+                        let (tx, _) = workers[worker_index].get_or_insert_with(spawn_worker);
+
+                        // The worker only knows the tx ids of its own clients' deposits.
+                        let ref_tx_id = transaction.get_ref_tx_id();
+                        if transaction.get_type() == TransactionType::Dispute
+                            && tx_ids.contains(ref_tx_id)
+                        {
+                            tx.send(WorkerMessage::TxIdTaken(ref_tx_id)).await?;
                         }
+                        tx.send(WorkerMessage::Transaction(transaction)).await?;
+                    }
 
-                        TransactionType::Dispute => {
-                            if let Some(ref_transaction) =
-                                transactions.get(&transaction.get_tx_id())
-                            {
-                                if ref_transaction.get_client_id() == client.get_id() {
-                                    if let Some(amount) = ref_transaction.get_amount() {
-                                        client
-                                            .transfer_available_to_held(*amount)
-                                            .unwrap_or_default();
-                                    }
-                                }
-                            }
+                    Request::Snapshot(reply_tx) => {
+                        let mut snapshot = HashMap::new();
+                        for reply in Self::ask_workers(&workers, WorkerMessage::Snapshot).await? {
+                            snapshot.extend(reply.await?);
+                        }
+
+                        // The caller may have given up waiting, which is fine.
+                        reply_tx.send(snapshot).unwrap_or_default();
+                    }
+
+                    Request::Stats(reply_tx) => {
+                        let mut stats = own_stats.clone();
+                        for reply in Self::ask_workers(&workers, WorkerMessage::Stats).await? {
+                            stats.merge(reply.await?);
                         }
 
-                        TransactionType::Resolve => {
-                            if let Some(ref_transaction) =
-                                transactions.get(&transaction.get_tx_id())
-                            {
-                                if ref_transaction.get_client_id() == client.get_id() {
-                                    if let Some(amount) = ref_transaction.get_amount() {
-                                        client
-                                            .transfer_held_to_available(*amount)
-                                            .unwrap_or_default();
-                                    }
-                                }
+                        reply_tx.send(stats).unwrap_or_default();
+                    }
+
+                    // Only the worker of the client has its transactions, if it was ever spawned.
+                    Request::Transactions(client_id, reply_tx) => {
+                        match &workers[Self::worker_index(client_id, workers_len)] {
+                            Some((tx, _)) => {
+                                tx.send(WorkerMessage::Transactions(client_id, reply_tx))
+                                    .await?
                             }
+                            None => reply_tx.send(Vec::new()).unwrap_or_default(),
                         }
+                    }
 
-                        TransactionType::Chargeback => {
-                            if let Some(ref_transaction) =
-                                transactions.get(&transaction.get_tx_id())
-                            {
-                                if ref_transaction.get_client_id() == client.get_id() {
-                                    if let Some(amount) = ref_transaction.get_amount() {
-                                        client.subtract_held(*amount).unwrap_or_default();
-                                        client.lock_account();
-                                    }
-                                }
+                    Request::DisputeState(client_id, tx_id, reply_tx) => {
+                        match &workers[Self::worker_index(client_id, workers_len)] {
+                            Some((tx, _)) => {
+                                tx.send(WorkerMessage::DisputeState(client_id, tx_id, reply_tx))
+                                    .await?
                             }
+                            None => reply_tx.send(None).unwrap_or_default(),
                         }
                     }
                 }
             }
+
+            workers
+                .into_iter()
+                .map(|worker| worker.map(|(_, join_handle)| join_handle))
+        };
+
+        // The results are streamed if asked for before the transactions channel was closed.
+        let results_tx = stream_rx.try_recv().ok();
+
+        let mut clients_by_worker = Vec::new();
+        let mut stats = own_stats;
+        let mut stored = Vec::new();
+        for join_handle in worker_join_handlers {
+            let clients = match join_handle {
+                Some(join_handle) => {
+                    let ledger = join_handle.await?;
+                    stats.merge(ledger.stats().clone());
+                    if config.collect_tx_store {
+                        stored.extend(ledger.stored_transactions());
+                    }
+
+                    ledger.into_clients()
+                }
+                None => HashMap::new(),
+            };
+
+            match &results_tx {
+                Some(results_tx) => {
+                    for client in clients.into_values() {
+                        // Nobody consuming the stream anymore is fine.
+                        results_tx.send(Ok(client)).await.unwrap_or_default();
+                    }
+                    clients_by_worker.push(HashMap::new());
+                }
+                None => clients_by_worker.push(clients),
+            }
+        }
+        stored.sort_by_key(|stored| (stored.client, stored.record.tx_id));
+
+        // Every worker is done, so only the queued rejections are left.
+        drop(rejections);
+        let rejected = match rejections_handle {
+            Some(rejections_handle) => rejections_handle.await?,
+            None => Vec::new(),
+        };
+
+        Ok(Outcome {
+            clients_by_worker,
+            stats,
+            rejected,
+            stored,
+        })
+    }
+
+    /// Whether a transaction is a deposit or withdrawal reusing the tx id of an earlier one, remembering its tx id
+    /// otherwise.
+    fn is_duplicate(tx_ids: &mut TxIdSet, transaction: &Transaction) -> bool {
+        matches!(
+            transaction.get_type(),
+            TransactionType::Deposit | TransactionType::Withdrawal
+        ) && !tx_ids.insert(transaction.get_tx_id())
+    }
+
+    /// Send a request to every spawned worker, returning where each of them will reply.
+    async fn ask_workers<T>(
+        workers: &[Option<Worker>],
+        message: fn(oneshot::Sender<T>) -> WorkerMessage,
+    ) -> Result<Vec<oneshot::Receiver<T>>> {
+        let mut replies = Vec::new();
+        for (tx, _) in workers.iter().flatten() {
+            let (reply_tx, reply_rx) = oneshot::channel();
+            tx.send(message(reply_tx)).await?;
+            replies.push(reply_rx);
+        }
+
+        Ok(replies)
+    }
+
+    /// Apply the transactions routed to this worker, in arrival order or, when priorities are configured, most
+    /// urgent first among the ones waiting (see `PriorityQueue`).
+    async fn worker(
+        mut rx: mpsc::Receiver<WorkerMessage>,
+        config: Config,
+        rejections: Option<mpsc::UnboundedSender<RejectedTransaction>>,
+    ) -> Ledger {
+        let mut queue =
+            (!config.priorities.is_empty()).then(|| PriorityQueue::new(config.priorities.clone()));
+        let catch_panics = config.catch_panics;
+        let mut ledger = Ledger::new(config).without_tx_id_check();
+        if let Some(rejections) = rejections {
+            ledger = ledger.with_rejections(rejections);
+        }
+
+        loop {
+            // With pending transactions, everything already waiting on the channel is queued before picking the
+            // next one to apply. Otherwise wait for the next message.
+            let message = match &mut queue {
+                Some(queue) if !queue.is_empty() => match rx.try_recv() {
+                    Ok(message) => message,
+                    Err(_) => {
+                        Self::apply(
+                            &mut ledger,
+                            queue.pop().expect("The queue isn't empty."),
+                            catch_panics,
+                        );
+                        continue;
+                    }
+                },
+                _ => match rx.recv().await {
+                    Some(message) => message,
+                    None => break,
+                },
+            };
+
+            if let WorkerMessage::Transaction(transaction) = message {
+                match &mut queue {
+                    Some(queue) => queue.push(transaction),
+                    None => Self::apply(&mut ledger, transaction, catch_panics),
+                }
+
+                continue;
+            }
+
+            // A tx id taken stays taken, so it doesn't have to wait for the queued transactions.
+            if let WorkerMessage::TxIdTaken(tx_id) = message {
+                ledger.take_tx_id(tx_id);
+                continue;
+            }
+
+            // Requests must see every transaction received before them.
+            if let Some(queue) = &mut queue {
+                while let Some(transaction) = queue.pop() {
+                    Self::apply(&mut ledger, transaction, catch_panics);
+                }
+            }
+
+            match message {
+                WorkerMessage::Transaction(_) | WorkerMessage::TxIdTaken(_) => unreachable!(),
+                WorkerMessage::Seed(client_seed) => ledger.seed(client_seed),
+                WorkerMessage::Snapshot(reply_tx) => {
+                    reply_tx.send(ledger.clients().clone()).unwrap_or_default()
+                }
+                WorkerMessage::Stats(reply_tx) => {
+                    ledger.record_divergences();
+                    reply_tx.send(ledger.stats().clone()).unwrap_or_default()
+                }
+                WorkerMessage::Transactions(client_id, reply_tx) => reply_tx
+                    .send(ledger.query_transactions(client_id))
+                    .unwrap_or_default(),
+                WorkerMessage::DisputeState(client_id, tx_id, reply_tx) => reply_tx
+                    .send(ledger.dispute_state(client_id, tx_id))
+                    .unwrap_or_default(),
+            }
+        }
+
+        ledger.finish();
+
+        ledger
+    }
+
+    /// Apply a transaction, skipping it if it panics when panics are caught. It is then rejected and counted in
+    /// the stats, see `Ledger::try_apply`.
+    fn apply(ledger: &mut Ledger, transaction: Transaction, catch_panics: bool) {
+        if !catch_panics {
+            ledger.apply(transaction);
+            return;
         }
 
-        clients
+        // Already rejected, with the transaction, and counted.
+        ledger.try_apply(transaction).unwrap_or_default();
     }
 }
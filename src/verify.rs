@@ -0,0 +1,172 @@
+use crate::client::Client;
+use anyhow::{bail, Result};
+use std::collections::{BTreeMap, HashMap};
+
+/// How a client differs between two sets of results.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    /// Only in the new results.
+    Added,
+
+    /// In both, with different balances, lock state or flag.
+    Changed,
+
+    /// Only in the old results.
+    Removed,
+}
+
+impl Change {
+    pub fn name(self) -> &'static str {
+        match self {
+            Change::Added => "added",
+            Change::Changed => "changed",
+            Change::Removed => "removed",
+        }
+    }
+}
+
+/// The clients that differ between the old and the new results, and how, by client id.
+pub fn diff(old: &HashMap<u16, Client>, new: &HashMap<u16, Client>) -> BTreeMap<u16, Change> {
+    old.keys()
+        .chain(new.keys())
+        .filter_map(|id| {
+            let change = match (old.get(id), new.get(id)) {
+                (Some(old), Some(new)) if old == new => return None,
+                (Some(_), Some(_)) => Change::Changed,
+                (None, _) => Change::Added,
+                (_, None) => Change::Removed,
+            };
+
+            Some((*id, change))
+        })
+        .collect()
+}
+
+/// Check that the parallel results match the single-threaded reference for every client.
+///
+/// Fails listing the ids of the clients that are missing from either side or whose balances, lock state or flag
+/// differ.
+pub fn compare(reference: &HashMap<u16, Client>, results: &HashMap<u16, Client>) -> Result<()> {
+    let divergent = diff(reference, results).into_keys().collect::<Vec<_>>();
+    if !divergent.is_empty() {
+        bail!("Parallel results diverge from the reference for clients {divergent:?}.");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ledger::Ledger,
+        transaction::{Transaction, TransactionType},
+        transaction_processor::TransactionProcessor,
+    };
+    use rust_decimal::Decimal;
+
+    /// Every deposit is disputed and then resolved or charged back, followed by a withdrawal.
+    fn dispute_heavy_transactions() -> Vec<Transaction> {
+        let mut transactions = Vec::new();
+        let mut tx = 0;
+
+        for round in 0..10 {
+            for client in 0..50 {
+                tx += 1;
+                let deposit_tx = tx;
+                transactions.push(Transaction::new(
+                    TransactionType::Deposit,
+                    client,
+                    deposit_tx,
+                    Some(Decimal::new(100 + round, 1)),
+                ));
+
+                transactions.push(Transaction::new(
+                    TransactionType::Dispute,
+                    client,
+                    deposit_tx,
+                    None,
+                ));
+
+                let settlement = match (client as u32 + deposit_tx) % 3 {
+                    0 => TransactionType::Chargeback,
+                    _ => TransactionType::Resolve,
+                };
+                transactions.push(Transaction::new(settlement, client, deposit_tx, None));
+
+                tx += 1;
+                transactions.push(Transaction::new(
+                    TransactionType::Withdrawal,
+                    client,
+                    tx,
+                    Some(Decimal::new(35, 1)),
+                ));
+            }
+        }
+
+        transactions
+    }
+
+    /// Test that the parallel processor matches the reference over a dispute-heavy input.
+    #[tokio::test]
+    async fn test_parallel_matches_reference() {
+        let transactions = dispute_heavy_transactions();
+        let reference = Ledger::process(Default::default(), transactions.iter().cloned());
+
+        let (submitter, tp) = TransactionProcessor::new();
+        for transaction in transactions {
+            submitter.submit(transaction).await.unwrap();
+        }
+
+        let results = tp.get_results(submitter).await.unwrap();
+
+        assert!(reference.values().any(|client| client.is_locked()));
+        assert!(compare(&reference, &results).is_ok());
+    }
+
+    /// Test that a divergence is detected.
+    #[test]
+    fn test_divergence() {
+        let transactions = dispute_heavy_transactions();
+        let reference = Ledger::process(Default::default(), transactions.iter().cloned());
+
+        // Drop the first deposit.
+        let results = Ledger::process(Default::default(), transactions.iter().skip(1).cloned());
+
+        assert!(compare(&reference, &results).is_err());
+        assert!(compare(&reference, &HashMap::new()).is_err());
+    }
+
+    /// Test that the diff tells apart the added, changed and removed clients, including a client that was only
+    /// flagged, and leaves out the same ones.
+    #[test]
+    fn test_diff() {
+        let old = HashMap::from([
+            (1, Client::with_balances(1, Decimal::ONE, Decimal::ZERO)),
+            (2, Client::with_balances(2, Decimal::ONE, Decimal::ZERO)),
+            (3, Client::new(3)),
+            (5, Client::new(5)),
+        ]);
+        let mut flagged = Client::new(5);
+        flagged.flag();
+        let new = HashMap::from([
+            (
+                1,
+                Client::with_balances(1, Decimal::new(10, 1), Decimal::ZERO),
+            ),
+            (2, Client::with_balances(2, Decimal::TWO, Decimal::ZERO)),
+            (4, Client::new(4)),
+            (5, flagged),
+        ]);
+
+        assert_eq!(
+            diff(&old, &new).into_iter().collect::<Vec<_>>(),
+            [
+                (2, Change::Changed),
+                (3, Change::Removed),
+                (4, Change::Added),
+                (5, Change::Changed)
+            ]
+        );
+    }
+}
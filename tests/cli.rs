@@ -0,0 +1,58 @@
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+};
+
+/// Test that the transactions piped to the binary's standard input are processed, for `-` and without a path.
+#[test]
+fn test_stdin_input() {
+    for args in [&["-"][..], &[]] {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_rct"))
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(b"type, client, tx, amount\ndeposit, 1, 1, 2.5\nwithdrawal, 1, 2, 1.0\n")
+            .unwrap();
+
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            "client,available,held,total,locked\n1,1.5000,0.0000,1.5000,false\n"
+        );
+    }
+}
+
+/// Test that a transaction skipped with `--skip-tx` doesn't fail `--strict` on its invalid amount.
+#[test]
+fn test_strict_skip_tx() {
+    let mut transactions_file = tempfile::NamedTempFile::new().unwrap();
+    write!(
+        transactions_file,
+        "type, client, tx, amount\ndeposit, 1, 1, 2.5\ndeposit, 1, 2, 1.00001\n"
+    )
+    .unwrap();
+    let path = transactions_file.path().to_str().unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_rct"))
+        .args(["--strict", path])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+
+    let output = Command::new(env!("CARGO_BIN_EXE_rct"))
+        .args(["--strict", "--skip-tx", "2", path])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,2.5000,0.0000,2.5000,false\n"
+    );
+}
@@ -0,0 +1,142 @@
+use rct::{config::Config, ledger::Ledger, Transaction, TransactionProcessor, TransactionType};
+use rust_decimal::Decimal;
+use std::collections::HashMap;
+
+/// Test that the processor is usable from outside the crate, through its public API only.
+#[tokio::test]
+async fn test_public_api() {
+    let (submitter, processor) = TransactionProcessor::new();
+    for transaction in [
+        Transaction::new(TransactionType::Deposit, 1, 1, Some(Decimal::TEN)),
+        Transaction::new(TransactionType::Withdrawal, 1, 2, Some(Decimal::ONE)),
+        Transaction::new(TransactionType::Deposit, 2, 3, Some(Decimal::ONE)),
+        Transaction::new(TransactionType::Dispute, 2, 3, None),
+    ] {
+        submitter.submit(transaction).await.unwrap();
+    }
+
+    let clients = processor.get_results(submitter).await.unwrap();
+
+    assert_eq!(clients[&1].get_available(), Decimal::new(9, 0));
+    assert_eq!(clients[&2].get_held(), Decimal::ONE);
+}
+
+/// Test that a processor built with a non-default number of workers routes the clients over that many of them.
+#[tokio::test]
+async fn test_builder() {
+    let (submitter, processor) = TransactionProcessor::builder()
+        .workers(3)
+        .channel_capacity(1)
+        .build();
+    for client in 1..=6 {
+        let transaction = Transaction::new(
+            TransactionType::Deposit,
+            client,
+            client.into(),
+            Some(Decimal::ONE),
+        );
+        submitter.submit(transaction).await.unwrap();
+    }
+
+    let clients_by_worker = processor.get_results_by_worker(submitter).await.unwrap();
+
+    assert_eq!(clients_by_worker.len(), 3);
+    assert_eq!(clients_by_worker.iter().map(HashMap::len).sum::<usize>(), 6);
+    for (index, clients) in clients_by_worker.iter().enumerate() {
+        assert!(clients
+            .keys()
+            .all(|client| TransactionProcessor::worker_index(*client, 3) == index));
+    }
+}
+
+/// Test that a client is always routed to the same worker, for any number of workers.
+#[test]
+fn test_worker_index_consistent() {
+    for workers in 1..=16 {
+        for client in [0, 1, 2, 1000, u16::MAX] {
+            let index = TransactionProcessor::worker_index(client, workers);
+
+            assert!(index < workers);
+            assert_eq!(TransactionProcessor::worker_index(client, workers), index);
+        }
+    }
+}
+
+/// Test that sequential client ids are spread evenly over the workers, even those in steps of the number of
+/// workers, which all went to the same worker with a plain modulo.
+#[test]
+fn test_worker_index_distribution() {
+    let workers = 8;
+    for step in [1, workers as u16] {
+        let mut counts = vec![0; workers];
+        for client in (0..8000).map(|n| n * step) {
+            counts[TransactionProcessor::worker_index(client, workers)] += 1;
+        }
+
+        // 1000 per worker, give or take 10%.
+        assert!(
+            counts.iter().all(|count| (900..=1100).contains(count)),
+            "{counts:?}"
+        );
+    }
+}
+
+/// Test that a fixture file is processed from its path alone.
+#[tokio::test]
+async fn test_process_file() {
+    let path =
+        std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/transactions.csv");
+
+    let clients = TransactionProcessor::process_file(path).await.unwrap();
+
+    assert_eq!(clients.len(), 2);
+    assert_eq!(clients[&1].get_available(), Decimal::new(85, 1));
+    assert_eq!(clients[&1].get_held(), Decimal::ONE);
+    assert_eq!(clients[&2].get_total(), Decimal::ZERO);
+    assert!(clients[&2].is_locked());
+    assert!(TransactionProcessor::process_file("missing.csv")
+        .await
+        .is_err());
+}
+
+/// Test that a dispute of a tx id already taken by a withdrawal, on the same worker or another one, is rejected
+/// right away, as by a single ledger, instead of waiting for a deposit that can't come.
+#[tokio::test]
+async fn test_dispute_of_taken_tx_id() {
+    let transactions = [
+        Transaction::new(TransactionType::Deposit, 1, 1, Some(Decimal::TEN)),
+        Transaction::new(TransactionType::Withdrawal, 1, 2, Some(Decimal::ONE)),
+        Transaction::new(TransactionType::Dispute, 1, 2, None),
+        Transaction::new(TransactionType::Dispute, 2, 2, None),
+        // Only a deposit still to come may be waited for.
+        Transaction::new(TransactionType::Dispute, 2, 3, None),
+    ];
+    let mut ledger = Ledger::new(Config::default());
+    for transaction in transactions.clone() {
+        ledger.apply(transaction);
+    }
+
+    // Clients 1 and 2 route to different workers.
+    let config = Config {
+        workers: Some(2),
+        ..Default::default()
+    };
+    let (submitter, processor) = TransactionProcessor::with_config(config);
+    for transaction in transactions {
+        submitter.submit(transaction).await.unwrap();
+    }
+    let stats = processor.flush_stats().await.unwrap();
+
+    assert_eq!(stats.rejected, 2);
+    assert_eq!(stats.pending_disputes, 1);
+    assert_eq!(stats.rejected, ledger.stats().rejected);
+    assert_eq!(stats.pending_disputes, ledger.stats().pending_disputes);
+    processor.get_results(submitter).await.unwrap();
+}
+
+/// Test that routing to no worker panics instead of dividing by zero.
+#[test]
+#[should_panic(expected = "A client can't be routed to no worker.")]
+fn test_worker_index_no_workers() {
+    TransactionProcessor::worker_index(1, 0);
+}